@@ -0,0 +1,522 @@
+//! HTTP control API to read and change the state of rooms and lights, answering JSON
+//!
+//! | Request                      | Answer                                                      |
+//! |------------------------------|-------------------------------------------------------------|
+//! | `GET /rooms`                 | [`RoomStatus`] of every room                                |
+//! | `GET /rooms/<room>`          | [`RoomStatus`] of the room                                  |
+//! | `POST /rooms/<room>/pause`   | Pause motion control, the lights are left alone             |
+//! | `POST /rooms/<room>/resume`  | Resume motion control, counting down to the timeout from now |
+//! | `POST /rooms/<room>/freeze`  | Freeze the timer, motion is still handled                   |
+//! | `POST /rooms/<room>/unfreeze` | Continue the frozen timer where it was                     |
+//! | `POST /rooms/<room>/extend`  | Push the timeout back with an [`Extension`] body            |
+//! | `POST /rooms/<room>/timeout` | Fade the lights now                                         |
+//! | `POST /rooms/<room>/restore` | Restore the faded lights now                                |
+//! | `GET /lights`                | [`LightStatus`] of every light                              |
+//! | `GET /lights/<light>`        | [`LightStatus`] of the light                                |
+//! | `PUT /lights/<light>`        | Change the light with a [`LightChange`] body                |
+//! | `GET /metrics`               | [Prometheus metrics](crate::metrics), if enabled            |
+//!
+//! Rooms and lights are addressed by their names in the config file. Errors are answered with
+//! `{"error": "<message>"}`.
+//!
+//! ```text
+//! $ curl -X POST localhost:8080/rooms/bedroom/pause
+//! $ curl -X POST localhost:8080/rooms/bedroom/extend -d '{"by": 600}'
+//! $ curl -X PUT localhost:8080/lights/taklampa -d '{"brightness": 50, "duration": 2}'
+//! ```
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use std::{fmt, io};
+
+use lifx_core::{Message, HSBK};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Server};
+
+use crate::config::{option_secs, secs};
+use crate::light::{self, LightError, WrongMessageError};
+use crate::metrics::{self, Metrics};
+use crate::room::{all_lights, with_light, Rooms};
+use crate::{Light, Room};
+
+/// State of a room
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RoomStatus {
+    pub name: String,
+    /// State of the room's state machine, like `vacant` or `fading`
+    pub state: String,
+    /// If motion control is paused through the API
+    pub paused: bool,
+    /// If the lights are faded after the timeout, false while paused or disabled by a schedule
+    pub enabled: bool,
+    /// Timeout in use in seconds
+    pub timeout: f64,
+    /// Seconds until the timer runs out, `null` if it is not running
+    pub remaining: Option<f64>,
+    /// If the timer is frozen through the API, `remaining` stands still
+    pub frozen: bool,
+    /// If any light has been faded and not restored yet
+    pub fading: bool,
+    /// Seconds since the latest motion, or since the start if there was none
+    pub since_motion: f64,
+    /// Names of the active schedules
+    pub schedules: Vec<String>,
+}
+
+impl RoomStatus {
+    pub fn new(room: &Room) -> Self {
+        let settings = room.settings();
+        Self {
+            name: room.name().to_string(),
+            state: room.state().to_string(),
+            paused: room.is_paused(),
+            enabled: settings.enabled,
+            timeout: settings.timeout.as_secs_f64(),
+            remaining: room.remaining().map(|remaining| remaining.as_secs_f64()),
+            frozen: room.is_frozen(),
+            fading: room.is_fading(),
+            since_motion: room
+                .clock()
+                .now()
+                .saturating_duration_since(room.last_motion())
+                .as_secs_f64(),
+            schedules: room.overrides().active,
+        }
+    }
+}
+
+/// State of a light, in the units of the command line interface
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LightStatus {
+    pub name: String,
+    pub label: String,
+    pub power: bool,
+    /// Hue in degrees
+    pub hue: f32,
+    /// Saturation in percent
+    pub saturation: f32,
+    /// Brightness in percent
+    pub brightness: f32,
+    pub kelvin: u16,
+}
+
+impl LightStatus {
+    /// Read the state of `light` with name `name`
+    pub fn read(name: &str, light: &Light<SocketAddr>) -> Result<Self, LightError> {
+        match light.request(Message::LightGet)? {
+            Message::LightState {
+                color,
+                power,
+                label,
+                ..
+            } => Ok(Self {
+                name: name.to_string(),
+                label: label.to_string(),
+                power: power != 0,
+                hue: light::to_degrees(color.hue),
+                saturation: light::to_percent(color.saturation),
+                brightness: light::to_percent(color.brightness),
+                kelvin: color.kelvin,
+            }),
+            msg => Err(WrongMessageError(msg).into()),
+        }
+    }
+}
+
+/// Body of `POST /rooms/<room>/extend`
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Extension {
+    /// Seconds to push the timeout back
+    #[serde(with = "secs")]
+    pub by: Duration,
+}
+
+/// Body of `PUT /lights/<light>`, fields that are left out are not changed
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LightChange {
+    /// Hue in degrees
+    pub hue: Option<f32>,
+    /// Saturation in percent
+    pub saturation: Option<f32>,
+    /// Brightness in percent
+    pub brightness: Option<f32>,
+    pub kelvin: Option<u16>,
+    pub power: Option<bool>,
+    /// Seconds to apply the change over
+    #[serde(default, with = "option_secs")]
+    pub duration: Option<Duration>,
+}
+
+impl LightChange {
+    /// Apply the change to `light`
+    pub fn apply(&self, light: &Light<SocketAddr>) -> Result<(), LightError> {
+        let duration = self.duration.unwrap_or_default();
+        if self.hue.is_some()
+            || self.saturation.is_some()
+            || self.brightness.is_some()
+            || self.kelvin.is_some()
+        {
+            light.change_color(
+                |color| HSBK {
+                    hue: self.hue.map_or(color.hue, light::from_degrees),
+                    saturation: self
+                        .saturation
+                        .map_or(color.saturation, light::from_percent),
+                    brightness: self
+                        .brightness
+                        .map_or(color.brightness, light::from_percent),
+                    kelvin: self.kelvin.unwrap_or(color.kelvin),
+                },
+                duration,
+            )?;
+        }
+        if let Some(power) = self.power {
+            light.send_acked(Message::LightSetPower {
+                level: if power { light::MAX } else { 0 },
+                duration: duration.as_millis() as u32,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Answer to a request, with its HTTP status code
+#[derive(Clone, Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub body: Value,
+}
+
+impl Response {
+    fn ok<T: Serialize>(body: T) -> Self {
+        Self {
+            status: 200,
+            body: serde_json::to_value(body).expect("status serializes to JSON"),
+        }
+    }
+
+    fn error(status: u16, message: impl ToString) -> Self {
+        Self {
+            status,
+            body: json!({ "error": message.to_string() }),
+        }
+    }
+}
+
+/// Answer a request with `method` on `path` with `body`
+pub fn handle(rooms: &[Arc<Room>], method: &str, path: &str, body: &str) -> Response {
+    let path = path.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    match (method, &segments[..]) {
+        ("GET", ["rooms"]) => Response::ok(
+            rooms
+                .iter()
+                .map(|room| RoomStatus::new(room))
+                .collect::<Vec<_>>(),
+        ),
+        (method, ["rooms", name, action @ ..]) => {
+            let Some(room) = rooms.iter().find(|room| room.name() == *name) else {
+                return Response::error(404, format!("unknown room {:?}", name));
+            };
+            match (method, action) {
+                ("GET", []) => {}
+                ("POST", ["pause"]) => room.pause(),
+                ("POST", ["resume"]) => room.resume(),
+                ("POST", ["freeze"]) => room.freeze(),
+                ("POST", ["unfreeze"]) => room.unfreeze(),
+                ("POST", ["extend"]) => match serde_json::from_str::<Extension>(body) {
+                    Ok(extension) => room.extend(extension.by),
+                    Err(err) => return Response::error(400, err),
+                },
+                ("POST", ["timeout"]) => room.force_timeout(),
+                ("POST", ["restore"]) => room.restore(),
+                (_, [])
+                | (
+                    _,
+                    ["pause" | "resume" | "freeze" | "unfreeze" | "extend" | "timeout" | "restore"],
+                ) => return Response::error(405, format!("{} not allowed on {}", method, path)),
+                _ => return Response::error(404, format!("not found: {}", path)),
+            }
+            Response::ok(RoomStatus::new(room))
+        }
+        ("GET", ["lights"]) => {
+            let mut statuses = Vec::new();
+            for (name, _) in all_lights(rooms) {
+                match with_light(rooms, &name, |light| LightStatus::read(&name, light)) {
+                    Some(Ok(status)) => statuses.push(status),
+                    Some(Err(err)) => {
+                        return Response::error(502, format!("light {:?}: {}", name, err))
+                    }
+                    // removed from its room since
+                    None => {}
+                }
+            }
+            Response::ok(statuses)
+        }
+        (method, ["lights", name]) => {
+            if !all_lights(rooms).iter().any(|(light, _)| light == name) {
+                return Response::error(404, format!("unknown light {:?}", name));
+            }
+            let change = match method {
+                "GET" => None,
+                "PUT" => match serde_json::from_str::<LightChange>(body) {
+                    Ok(change) => Some(change),
+                    Err(err) => return Response::error(400, err),
+                },
+                _ => return Response::error(405, format!("{} not allowed on {}", method, path)),
+            };
+            let result = with_light(rooms, name, |light| {
+                if let Some(change) = &change {
+                    change.apply(light)?;
+                }
+                LightStatus::read(name, light)
+            });
+            match result {
+                Some(Ok(status)) => Response::ok(status),
+                Some(Err(err)) => Response::error(502, format!("light {:?}: {}", name, err)),
+                None => Response::error(404, format!("unknown light {:?}", name)),
+            }
+        }
+        _ => Response::error(404, format!("not found: {}", path)),
+    }
+}
+
+/// Ask the API listening on `addr` for the [`RoomStatus`] of every room, giving up after `timeout`
+pub fn fetch_rooms(addr: SocketAddr, timeout: Duration) -> io::Result<Vec<RoomStatus>> {
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    write!(
+        stream,
+        "GET /rooms HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        addr
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| invalid("incomplete answer from the API".to_string()))?;
+    let status = head.lines().next().unwrap_or_default();
+    if !status.starts_with("HTTP/1.1 200") {
+        return Err(invalid(format!("API answered {}: {}", status, body)));
+    }
+    serde_json::from_str(body).map_err(|err| invalid(err.to_string()))
+}
+
+/// HTTP server answering API requests on its own thread, stopped when dropped
+pub struct Api {
+    server: Arc<Server>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Api {
+    /// Listen on `bind` and answer requests about `rooms`, and serve `metrics` if given
+    pub fn spawn(bind: SocketAddr, rooms: Rooms, metrics: Option<Metrics>) -> io::Result<Self> {
+        let server = Arc::new(Server::http(bind).map_err(io::Error::other)?);
+        let server_thread = server.clone();
+        let thread = thread::Builder::new()
+            .name("api".to_string())
+            .spawn(move || {
+                for mut request in server_thread.incoming_requests() {
+                    let scrape = request.method() == &Method::Get && request.url() == "/metrics";
+                    if let Some(metrics) = metrics.as_ref().filter(|_| scrape) {
+                        let rooms = rooms.lock().unwrap().clone();
+                        let content_type =
+                            Header::from_bytes("Content-Type", metrics::CONTENT_TYPE).unwrap();
+                        let result = request.respond(
+                            tiny_http::Response::from_string(metrics.render(&rooms))
+                                .with_header(content_type),
+                        );
+                        if let Err(err) = result {
+                            tracing::warn!("could not answer metrics request: {}", err);
+                        }
+                        continue;
+                    }
+                    let mut body = String::new();
+                    let response = match request.as_reader().read_to_string(&mut body) {
+                        Ok(_) => {
+                            let rooms = rooms.lock().unwrap().clone();
+                            handle(&rooms, request.method().as_str(), request.url(), &body)
+                        }
+                        Err(err) => Response::error(400, err),
+                    };
+                    tracing::debug!(
+                        method = %request.method(),
+                        url = request.url(),
+                        status = response.status,
+                        "API request"
+                    );
+                    let content_type =
+                        Header::from_bytes("Content-Type", "application/json").unwrap();
+                    let result = request.respond(
+                        tiny_http::Response::from_string(response.body.to_string())
+                            .with_status_code(response.status)
+                            .with_header(content_type),
+                    );
+                    if let Err(err) = result {
+                        tracing::warn!("could not answer API request: {}", err);
+                    }
+                }
+            })?;
+        Ok(Self {
+            server,
+            thread: Some(thread),
+        })
+    }
+
+    /// Address the API is listening on
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+}
+
+impl fmt::Debug for Api {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("Api")
+            .field("addr", &self.addr())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for Api {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{RoomConfig, Timings};
+    use crate::fake::FakeBulb;
+    use std::sync::Mutex;
+
+    fn rooms(bulb: &FakeBulb) -> Vec<Arc<Room>> {
+        let config = RoomConfig::test("bedroom", Duration::from_secs(10));
+        let lights = vec![("lamp".to_string(), bulb.light().unwrap())];
+        vec![Arc::new(Room::new(config, Timings::default(), lights))]
+    }
+
+    #[test]
+    fn test_rooms() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let rooms = rooms(&bulb);
+        let response = handle(&rooms, "GET", "/rooms", "");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body[0]["name"], "bedroom");
+        assert_eq!(response.body[0]["state"], "vacant");
+        assert_eq!(response.body[0]["enabled"], true);
+
+        let response = handle(&rooms, "POST", "/rooms/bedroom/pause", "");
+        assert_eq!(response.body["paused"], true);
+        assert_eq!(response.body["enabled"], false);
+        let response = handle(&rooms, "POST", "/rooms/bedroom/timeout", "");
+        assert_eq!(response.body["state"], "fading");
+        assert!(bulb.is_fading());
+        let response = handle(&rooms, "POST", "/rooms/bedroom/restore", "");
+        assert_eq!(response.body["fading"], false);
+        let response = handle(&rooms, "POST", "/rooms/bedroom/resume/", "");
+        assert_eq!(response.body["paused"], false);
+
+        let response = handle(&rooms, "POST", "/rooms/bedroom/freeze", "");
+        assert_eq!(response.body["frozen"], true);
+        let response = handle(&rooms, "POST", "/rooms/bedroom/extend", r#"{"by": 60}"#);
+        assert_eq!(response.status, 200);
+        thread::sleep(Duration::from_millis(20));
+        let response = handle(&rooms, "GET", "/rooms/bedroom", "");
+        assert!(response.body["remaining"].as_f64().unwrap() > 60.0);
+        let response = handle(&rooms, "POST", "/rooms/bedroom/unfreeze", "");
+        assert_eq!(response.body["frozen"], false);
+        let response = handle(&rooms, "POST", "/rooms/bedroom/extend", r#"{"for": 60}"#);
+        assert_eq!(response.status, 400);
+
+        assert_eq!(handle(&rooms, "GET", "/rooms/kitchen", "").status, 404);
+        assert_eq!(
+            handle(&rooms, "GET", "/rooms/bedroom/pause", "").status,
+            405
+        );
+        assert_eq!(
+            handle(&rooms, "POST", "/rooms/bedroom/dance", "").status,
+            404
+        );
+        assert_eq!(handle(&rooms, "GET", "/", "").status, 404);
+    }
+
+    #[test]
+    fn test_lights() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let rooms = rooms(&bulb);
+        let response = handle(&rooms, "GET", "/lights", "");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body[0]["label"], "Taklampa");
+
+        let response = handle(
+            &rooms,
+            "PUT",
+            "/lights/lamp",
+            r#"{"hue": 180, "brightness": 100, "power": false}"#,
+        );
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body["power"], false);
+        assert_eq!(bulb.color().hue, 0x8000);
+        assert_eq!(bulb.color().brightness, light::MAX);
+        assert_eq!(bulb.power(), 0);
+
+        let response = handle(&rooms, "PUT", "/lights/lamp", r#"{"colour": 1}"#);
+        assert_eq!(response.status, 400);
+        assert!(response.body["error"]
+            .as_str()
+            .unwrap()
+            .contains("unknown field"));
+        assert_eq!(handle(&rooms, "GET", "/lights/strip", "").status, 404);
+        assert_eq!(handle(&rooms, "DELETE", "/lights/lamp", "").status, 405);
+    }
+
+    #[test]
+    fn test_http() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let rooms = Arc::new(Mutex::new(rooms(&bulb)));
+        let metrics = Metrics::new(None);
+        let api = Api::spawn("127.0.0.1:0".parse().unwrap(), rooms, Some(metrics)).unwrap();
+
+        let mut stream = TcpStream::connect(api.addr().unwrap()).unwrap();
+        write!(
+            stream,
+            "POST /rooms/bedroom/pause HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("application/json"));
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        let status: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(status["paused"], true);
+
+        let statuses = fetch_rooms(api.addr().unwrap(), Duration::from_secs(1)).unwrap();
+        assert_eq!(statuses[0].name, "bedroom");
+        assert!(statuses[0].paused);
+
+        let mut stream = TcpStream::connect(api.addr().unwrap()).unwrap();
+        write!(
+            stream,
+            "GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("text/plain; version=0.0.4"));
+        assert!(response.contains("# TYPE motion_sensor_lifx_light_brightness_percent gauge"));
+        drop(api);
+    }
+}
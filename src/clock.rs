@@ -0,0 +1,259 @@
+//! Source of time for timers, fades and polling, real or manually advanced in tests
+//!
+//! With a [`MockClock`] a ten minute timeout is reached by calling [`MockClock::advance`] instead
+//! of waiting, every [`Instant`] handed out is the instant the clock was created plus the time it
+//! has been advanced. [`MockClock::wait_for_sleepers`] waits until the threads using the clock have
+//! caught up with it, instead of sleeping for some real time in the hope that they have.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::mpsc::{Receiver, RecvError, RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+/// Real time blocked at once by a [`MockClock`] while waiting on a channel, before checking the clock again
+const MOCK_SLICE: Duration = Duration::from_millis(1);
+/// Real time [`MockClock::wait_for_sleepers`] waits before giving up
+const SLEEPERS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A source of time
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Current instant on this clock
+    fn now(&self) -> Instant;
+
+    /// Block until `duration` has passed on this clock
+    fn sleep(&self, duration: Duration);
+
+    /// Real time to block at once while waiting for `remaining` time on this clock, asked before every block
+    fn slice(&self, remaining: Duration) -> Duration;
+}
+
+/// The system clock
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RealClock;
+
+impl Clock for RealClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+
+    fn slice(&self, remaining: Duration) -> Duration {
+        remaining
+    }
+}
+
+/// Shared system clock, the default for timers and rooms
+pub fn real() -> Arc<dyn Clock> {
+    Arc::new(RealClock)
+}
+
+/// A clock that only moves when advanced
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<Instant>,
+    advanced: Condvar,
+    sleepers: Mutex<Sleepers>,
+    parked: Condvar,
+}
+
+/// Threads blocking on a [`MockClock`], counted to know when they wait for it to move
+#[derive(Debug, Default)]
+struct Sleepers {
+    /// Number of times any thread blocked on the clock
+    parks: u64,
+    /// The two latest parks of every thread that blocked on the clock
+    latest: HashMap<ThreadId, [u64; 2]>,
+}
+
+impl MockClock {
+    /// Clock standing still at the current instant
+    pub fn new() -> Self {
+        Self {
+            now: Mutex::new(Instant::now()),
+            advanced: Condvar::new(),
+            sleepers: Mutex::default(),
+            parked: Condvar::new(),
+        }
+    }
+
+    /// Move the clock forward by `duration`, waking up everything sleeping on it
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+        self.advanced.notify_all();
+    }
+
+    /// Block until `count` threads wait for the clock to move
+    ///
+    /// Each of them has to block on the clock twice after the call, so whatever was sent to them
+    /// or became due before it has been handled.
+    ///
+    /// # Panics
+    /// If fewer than `count` threads are waiting after ten seconds of real time
+    pub fn wait_for_sleepers(&self, count: usize) {
+        let sleepers = self.sleepers.lock().unwrap();
+        let since = sleepers.parks;
+        let (_sleepers, result) = self
+            .parked
+            .wait_timeout_while(sleepers, SLEEPERS_TIMEOUT, |sleepers| {
+                let waiting = sleepers.latest.values();
+                waiting.filter(|[previous, _]| *previous > since).count() < count
+            })
+            .unwrap();
+        assert!(
+            !result.timed_out(),
+            "fewer than {} threads wait for the clock",
+            count
+        );
+    }
+
+    /// Count the current thread blocking on the clock
+    fn park(&self) {
+        let mut sleepers = self.sleepers.lock().unwrap();
+        sleepers.parks += 1;
+        let park = sleepers.parks;
+        let latest = sleepers.latest.entry(thread::current().id()).or_default();
+        *latest = [latest[1], park];
+        self.parked.notify_all();
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let mut now = self.now.lock().unwrap();
+        let deadline = *now + duration;
+        while *now < deadline {
+            self.park();
+            now = self.advanced.wait_timeout(now, MOCK_SLICE).unwrap().0;
+        }
+    }
+
+    fn slice(&self, remaining: Duration) -> Duration {
+        self.park();
+        remaining.min(MOCK_SLICE)
+    }
+}
+
+/// Wait for a message on `receiver` until `timeout` has passed on `clock`, like [`Receiver::recv_timeout`]
+pub fn recv_timeout<T>(
+    clock: &dyn Clock,
+    receiver: &Receiver<T>,
+    timeout: Duration,
+) -> Result<T, RecvTimeoutError> {
+    let deadline = clock.now() + timeout;
+    loop {
+        // only block, which a mock clock counts, with nothing left to receive
+        match receiver.try_recv() {
+            Ok(message) => return Ok(message),
+            Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+            Err(TryRecvError::Empty) => {}
+        }
+        let remaining = deadline.saturating_duration_since(clock.now());
+        if remaining.is_zero() {
+            return Err(RecvTimeoutError::Timeout);
+        }
+        match receiver.recv_timeout(clock.slice(remaining)) {
+            Err(RecvTimeoutError::Timeout) => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Wait for a message on `receiver` for as long as it takes, like [`Receiver::recv`], blocking as `clock` does
+pub fn recv<T>(clock: &dyn Clock, receiver: &Receiver<T>) -> Result<T, RecvError> {
+    loop {
+        match receiver.try_recv() {
+            Ok(message) => return Ok(message),
+            Err(TryRecvError::Disconnected) => return Err(RecvError),
+            Err(TryRecvError::Empty) => {}
+        }
+        match receiver.recv_timeout(clock.slice(Duration::MAX)) {
+            Ok(message) => return Ok(message),
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return Err(RecvError),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_mock_sleep() {
+        let clock = Arc::new(MockClock::new());
+        let start = clock.now();
+        let sleeping = clock.clone();
+        let sleeper = thread::spawn(move || sleeping.sleep(Duration::from_secs(600)));
+        // let the sleeper start sleeping before moving the clock
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_secs(300));
+        clock.wait_for_sleepers(1);
+        assert!(!sleeper.is_finished());
+        clock.advance(Duration::from_secs(300));
+        sleeper.join().unwrap();
+        assert_eq!(clock.now() - start, Duration::from_secs(600));
+    }
+
+    #[test]
+    fn test_recv_timeout() {
+        let clock = Arc::new(MockClock::new());
+        let (sender, receiver) = mpsc::channel::<()>();
+        let waiting = clock.clone();
+        let waiter = thread::spawn(move || {
+            recv_timeout(waiting.as_ref(), &receiver, Duration::from_secs(600))
+        });
+        clock.wait_for_sleepers(1);
+        assert!(!waiter.is_finished(), "real time does not time out");
+        clock.advance(Duration::from_secs(600));
+        assert_eq!(waiter.join().unwrap(), Err(RecvTimeoutError::Timeout));
+        drop(sender);
+    }
+
+    /// Waiting for sleepers waits until what was sent to them has been handled
+    #[test]
+    fn test_wait_for_sleepers() {
+        let clock = Arc::new(MockClock::new());
+        let (sender, receiver) = mpsc::channel::<u32>();
+        let (handled, handled_receiver) = mpsc::channel();
+        let waiting = clock.clone();
+        let waiter = thread::spawn(move || {
+            while let Ok(message) = recv(waiting.as_ref(), &receiver) {
+                thread::sleep(Duration::from_millis(20));
+                handled.send(message).unwrap();
+            }
+        });
+        clock.wait_for_sleepers(1);
+        for message in 0..3 {
+            sender.send(message).unwrap();
+        }
+        clock.wait_for_sleepers(1);
+        assert_eq!(handled_receiver.try_iter().collect::<Vec<_>>(), [0, 1, 2]);
+        drop(sender);
+        waiter.join().unwrap();
+    }
+
+    #[test]
+    fn test_real() {
+        let (_sender, receiver) = mpsc::channel::<()>();
+        let started = Instant::now();
+        let result = recv_timeout(&RealClock, &receiver, Duration::from_millis(20));
+        assert_eq!(result, Err(RecvTimeoutError::Timeout));
+        assert!(started.elapsed() >= Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,1216 @@
+//! Configuration file describing sensors, lights, rooms and timings
+//!
+//! # Example configuration
+//!
+//! ```toml
+//! [timings]
+//! socket_timeout = 30
+//!
+//! [[sensors]]
+//! name = "hallway"
+//! pin = 17
+//!
+//! [[lights]]
+//! name = "taklampa"
+//! label = "Taklampa"
+//!
+//! [[rooms]]
+//! name = "bedroom"
+//! sensors = ["hallway"]
+//! lights = ["taklampa"]
+//! timeout = 600
+//! fade_duration = 180
+//!
+//! [[schedules]]
+//! name = "night"
+//! days = ["weekdays"]
+//! start = "23:00"
+//! end = "07:00"
+//! timeout = 120
+//! ```
+//!
+//! All durations are given in seconds.
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use std::{fmt, fs, io};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cue::CueConfig;
+use crate::discovery::Registry;
+use crate::schedule::ScheduleConfig;
+use crate::state::DEFAULT_MAX_AGE;
+use crate::temperature::Thermal;
+use crate::{light, FADE_DURATION, MATCHING_THRESHOLD, SOCKET_TIMEOUT, TIMEOUT};
+
+/// Path the daemon reads its configuration from if no other path is given
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/motion_sensor_lifx.toml";
+/// GPIO chip used for sensors if no other chip is given
+pub const DEFAULT_CHIP: &str = "/dev/gpiochip0";
+/// Fade state file used if a `[state]` section is given without a file
+pub const DEFAULT_STATE_PATH: &str = "/var/lib/motion_sensor_lifx/state.json";
+/// Log levels if no other filter is given
+pub const DEFAULT_LOG_FILTER: &str = "info";
+/// Address the HTTP API listens on if no other address is given, only reachable from the same machine
+pub const DEFAULT_API_BIND: &str = "127.0.0.1:8080";
+/// Interval the lights are polled to find lights left on without motion
+pub const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The whole configuration file
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub timings: Timings,
+    #[serde(default)]
+    pub sensors: Vec<SensorConfig>,
+    #[serde(default)]
+    pub lights: Vec<LightConfig>,
+    #[serde(default)]
+    pub rooms: Vec<RoomConfig>,
+    /// Time-of-day rules changing room settings, see [`crate::schedule`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schedules: Vec<ScheduleConfig>,
+    /// HTTP control API, not started if missing, see [`crate::api`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api: Option<ApiConfig>,
+    /// File the fade state is kept in across restarts, not kept if missing, see [`crate::state`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<StateConfig>,
+    /// Prometheus metrics served by the API on `/metrics`, see [`crate::metrics`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<MetricsConfig>,
+    /// MQTT broker to bridge to, needs the `mqtt` feature
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mqtt: Option<MqttConfig>,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+}
+
+/// Timings shared by all rooms
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timings {
+    /// Timeout for UDP socket read and write, see [`SOCKET_TIMEOUT`]
+    #[serde(with = "secs")]
+    pub socket_timeout: Duration,
+    /// Time to wait for lights to answer discovery, for lights configured by label
+    #[serde(with = "secs")]
+    pub discovery_timeout: Duration,
+    /// Interval the lights are polled to find lights left on without motion
+    #[serde(with = "secs")]
+    pub poll_interval: Duration,
+    /// Float percentage factor a fading color should match within, see [`MATCHING_THRESHOLD`]
+    pub matching_threshold: f32,
+}
+
+impl Default for Timings {
+    fn default() -> Self {
+        Self {
+            socket_timeout: SOCKET_TIMEOUT,
+            discovery_timeout: crate::discovery::DISCOVERY_TIMEOUT,
+            poll_interval: POLL_INTERVAL,
+            matching_threshold: MATCHING_THRESHOLD,
+        }
+    }
+}
+
+/// Where and how much the daemon logs, see [`crate::logging`]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Levels per module like `info,motion_sensor_lifx::light=debug`, `RUST_LOG` takes precedence
+    pub filter: String,
+    /// If logs are written to standard error
+    pub console: bool,
+    /// Directory of rotating log files, no log files if missing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub directory: Option<PathBuf>,
+    /// Start of the log file names, followed by the date
+    pub file_prefix: String,
+    /// How often a new log file is started
+    pub rotation: LogRotation,
+    /// Number of log files to keep, all if missing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_files: Option<usize>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            filter: DEFAULT_LOG_FILTER.to_string(),
+            console: true,
+            directory: None,
+            file_prefix: "motion_sensor_lifx.log".to_string(),
+            rotation: LogRotation::Daily,
+            max_files: None,
+        }
+    }
+}
+
+/// How often a new log file is started
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    #[default]
+    Daily,
+    Weekly,
+    Never,
+}
+
+/// HTTP control API of the daemon
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiConfig {
+    /// Address to listen on, `0.0.0.0:8080` to allow the whole LAN, see [`DEFAULT_API_BIND`]
+    #[serde(default = "default_api_bind")]
+    pub bind: SocketAddr,
+}
+
+fn default_api_bind() -> SocketAddr {
+    DEFAULT_API_BIND.parse().unwrap()
+}
+
+/// File the fade state of the rooms is kept in across restarts
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StateConfig {
+    /// See [`DEFAULT_STATE_PATH`]
+    pub file: PathBuf,
+    /// Saved state older than this is ignored at startup, see [`DEFAULT_MAX_AGE`]
+    #[serde(with = "secs")]
+    pub max_age: Duration,
+}
+
+impl Default for StateConfig {
+    fn default() -> Self {
+        Self {
+            file: PathBuf::from(DEFAULT_STATE_PATH),
+            max_age: DEFAULT_MAX_AGE,
+        }
+    }
+}
+
+/// Prometheus metrics of the daemon
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Sysfs file with the CPU temperature in millidegrees, no temperature metric if missing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature_file: Option<PathBuf>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            temperature_file: Some(Thermal::default().temperature_file),
+        }
+    }
+}
+
+/// MQTT broker to publish motion, timers and lights to, and to take commands from
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    /// Host name or ip address of the broker
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    /// Prefix of every topic published or subscribed to, see [`DEFAULT_TOPIC_PREFIX`]
+    #[serde(default = "default_topic_prefix")]
+    pub topic_prefix: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// Interval the state of every room and light is published, besides when it changes
+    #[serde(default = "default_state_interval", with = "secs")]
+    pub state_interval: Duration,
+    /// Prefix for Home Assistant discovery, usually `homeassistant`, no discovery if missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub discovery_prefix: Option<String>,
+}
+
+/// Prefix of MQTT topics if no other prefix is given
+pub const DEFAULT_TOPIC_PREFIX: &str = "motion_sensor_lifx";
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    DEFAULT_TOPIC_PREFIX.to_string()
+}
+
+fn default_topic_prefix() -> String {
+    DEFAULT_TOPIC_PREFIX.to_string()
+}
+
+fn default_state_interval() -> Duration {
+    POLL_INTERVAL
+}
+
+/// A PIR motion sensor connected to a GPIO line
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SensorConfig {
+    pub name: String,
+    /// Path to the GPIO chip character device
+    #[serde(default = "default_chip")]
+    pub chip: PathBuf,
+    /// Line offset on the GPIO chip
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pin: Option<u32>,
+    /// Replay file played instead of reading the GPIO line, see [`crate::motion`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replay: Option<PathBuf>,
+}
+
+fn default_chip() -> PathBuf {
+    PathBuf::from(DEFAULT_CHIP)
+}
+
+/// A lifx light, addressed by ip address or found on the LAN by its label
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LightConfig {
+    pub name: String,
+    /// Address of the light, like `192.168.1.11:56700`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<SocketAddr>,
+    /// Label of the light to find with [`crate::discover`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+impl LightConfig {
+    /// Address of the light, looking up the label in `registry` if no address is configured
+    pub fn resolve(&self, registry: Option<&Registry>) -> Option<SocketAddr> {
+        self.address.or_else(|| {
+            let label = self.label.as_deref()?;
+            Some(registry?.by_label(label)?.addr)
+        })
+    }
+}
+
+/// Sensors and lights controlled together by one timer
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoomConfig {
+    pub name: String,
+    /// Names of sensors detecting motion in the room
+    pub sensors: Vec<String>,
+    /// Names of lights that are faded when there is no motion
+    pub lights: Vec<String>,
+    /// Time without motion before the lights start fading, see [`TIMEOUT`]
+    #[serde(default = "default_timeout", with = "secs")]
+    pub timeout: Duration,
+    /// Duration of the fade, see [`FADE_DURATION`]
+    #[serde(default = "default_fade_duration", with = "secs")]
+    pub fade_duration: Duration,
+    /// Brightness in percent the lights fade to, see [`light::MIN`]
+    #[serde(default = "default_fade_brightness")]
+    pub fade_brightness: f32,
+    /// Power the lights off once faded, and on again when restored
+    #[serde(default)]
+    pub power_off: bool,
+    /// Waveform played on the lights when motion restores them, see [`crate::cue`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restore_cue: Option<CueConfig>,
+    /// Warning given on the lights before they fade, so someone sitting still can move to keep them on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warning: Option<WarningConfig>,
+    /// Named points of the countdown, published on MQTT and counted in the metrics when reached
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub milestones: Vec<MilestoneConfig>,
+}
+
+impl RoomConfig {
+    /// Brightness the lights fade to, in the range used by [`lifx_core::HSBK`]
+    pub fn fade_brightness(&self) -> u16 {
+        light::from_percent(self.fade_brightness)
+    }
+
+    /// Room `name` with the sensor `hallway` and the light `lamp`, fading after `timeout`, for tests
+    #[cfg(any(test, feature = "testing"))]
+    pub fn test(name: &str, timeout: Duration) -> Self {
+        Self {
+            name: name.to_string(),
+            sensors: vec!["hallway".to_string()],
+            lights: vec!["lamp".to_string()],
+            timeout,
+            fade_duration: Duration::from_secs(2),
+            fade_brightness: light::to_percent(light::MIN),
+            power_off: false,
+            restore_cue: None,
+            warning: None,
+            milestones: Vec::new(),
+        }
+    }
+}
+
+fn default_timeout() -> Duration {
+    TIMEOUT
+}
+
+fn default_fade_duration() -> Duration {
+    FADE_DURATION
+}
+
+fn default_fade_brightness() -> f32 {
+    light::to_percent(light::MIN)
+}
+
+/// Warning before the lights of a room fade, see [`crate::presence::State::Warning`]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WarningConfig {
+    /// Time before the timeout the lights are dimmed
+    #[serde(with = "secs")]
+    pub before: Duration,
+    /// Percent of their brightness the lights are dimmed by, over [`crate::WARNING_DURATION`]
+    #[serde(default = "default_warning_dim")]
+    pub dim: f32,
+    /// Waveform played on the lights when they are dimmed, like a flash
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cue: Option<CueConfig>,
+}
+
+fn default_warning_dim() -> f32 {
+    20.0
+}
+
+/// Named point of the countdown of a room's timer, see [`crate::Milestone`]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MilestoneConfig {
+    pub name: String,
+    /// Time since the last motion the milestone is reached at, also past the timeout
+    #[serde(with = "secs")]
+    pub after: Duration,
+}
+
+/// Name of the milestone of the [`WarningConfig`] on the room timer
+pub const WARNING_MILESTONE: &str = "warning";
+
+/// Names of timer actions, that milestones can not be named after
+pub const RESERVED_MILESTONES: [&str; 4] = ["start", "restart", "timeout", WARNING_MILESTONE];
+
+/// Errors when loading a configuration file
+#[derive(Debug)]
+pub enum ConfigError {
+    /// File could not be read
+    Io(PathBuf, io::Error),
+    /// File is not valid TOML or does not match the configuration format
+    Parse(toml::de::Error),
+    /// Configuration is well formed but contradicts itself, with every problem found
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => {
+                write!(
+                    fmt,
+                    "could not read config file {}: {}",
+                    path.display(),
+                    err
+                )
+            }
+            ConfigError::Parse(err) => write!(fmt, "could not parse config file: {}", err),
+            ConfigError::Invalid(problems) => {
+                write!(fmt, "invalid config file:")?;
+                for problem in problems {
+                    write!(fmt, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Io(_, err) => Some(err),
+            ConfigError::Parse(err) => Some(err),
+            ConfigError::Invalid(_) => None,
+        }
+    }
+}
+
+impl Config {
+    /// Read, parse and validate the configuration file at `path`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let contents =
+            fs::read_to_string(path).map_err(|err| ConfigError::Io(path.to_path_buf(), err))?;
+        contents.parse()
+    }
+
+    pub fn sensor(&self, name: &str) -> Option<&SensorConfig> {
+        self.sensors.iter().find(|sensor| sensor.name == name)
+    }
+
+    pub fn light(&self, name: &str) -> Option<&LightConfig> {
+        self.lights.iter().find(|light| light.name == name)
+    }
+
+    pub fn room(&self, name: &str) -> Option<&RoomConfig> {
+        self.rooms.iter().find(|room| room.name == name)
+    }
+
+    /// If any light has to be found with discovery
+    pub fn needs_discovery(&self) -> bool {
+        self.lights.iter().any(|light| light.address.is_none())
+    }
+
+    /// Check that names are unique, references exist and values are in range
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        let timings = &self.timings;
+        if timings.socket_timeout.is_zero() {
+            problems.push("timings.socket_timeout must be more than zero".to_string());
+        }
+        if timings.poll_interval.is_zero() {
+            problems.push("timings.poll_interval must be more than zero".to_string());
+        }
+        if !(timings.matching_threshold > 0.0 && timings.matching_threshold <= 1.0) {
+            problems.push(format!(
+                "timings.matching_threshold must be in the range (0, 1], got {}",
+                timings.matching_threshold
+            ));
+        }
+
+        duplicates(
+            "sensor",
+            self.sensors.iter().map(|s| &s.name),
+            &mut problems,
+        );
+        duplicates("light", self.lights.iter().map(|l| &l.name), &mut problems);
+        duplicates("room", self.rooms.iter().map(|r| &r.name), &mut problems);
+        duplicates(
+            "schedule",
+            self.schedules.iter().map(|s| &s.name),
+            &mut problems,
+        );
+
+        for sensor in &self.sensors {
+            if sensor.pin.is_none() && sensor.replay.is_none() {
+                problems.push(format!(
+                    "sensor {:?} needs either a pin or a replay file",
+                    sensor.name
+                ));
+            }
+        }
+
+        for light in &self.lights {
+            match (&light.address, &light.label) {
+                (Some(_), Some(_)) => problems.push(format!(
+                    "light {:?} has both address and label, only one is allowed",
+                    light.name
+                )),
+                (None, None) => problems.push(format!(
+                    "light {:?} needs either an address or a label",
+                    light.name
+                )),
+                _ => {}
+            }
+        }
+
+        if self.rooms.is_empty() {
+            problems.push("at least one room is needed".to_string());
+        }
+        for room in &self.rooms {
+            if room.sensors.is_empty() {
+                problems.push(format!("room {:?} has no sensors", room.name));
+            }
+            if room.lights.is_empty() {
+                problems.push(format!("room {:?} has no lights", room.name));
+            }
+            for sensor in room.sensors.iter().filter(|s| self.sensor(s).is_none()) {
+                problems.push(format!(
+                    "room {:?} references unknown sensor {:?}",
+                    room.name, sensor
+                ));
+            }
+            for light in room.lights.iter().filter(|l| self.light(l).is_none()) {
+                problems.push(format!(
+                    "room {:?} references unknown light {:?}",
+                    room.name, light
+                ));
+            }
+            if room.timeout.is_zero() {
+                problems.push(format!(
+                    "room {:?} timeout must be more than zero",
+                    room.name
+                ));
+            }
+            if !(0.0..=100.0).contains(&room.fade_brightness) {
+                problems.push(format!(
+                    "room {:?} fade_brightness must be a percentage in the range [0, 100], got {}",
+                    room.name, room.fade_brightness
+                ));
+            }
+            if let Some(cue) = &room.restore_cue {
+                problems.extend(cue.problems(&format!("room {:?} restore_cue", room.name)));
+            }
+            if let Some(warning) = &room.warning {
+                if warning.before.is_zero() || warning.before >= room.timeout {
+                    problems.push(format!(
+                        "room {:?} warning before must be more than zero and less than the timeout",
+                        room.name
+                    ));
+                }
+                if !(0.0..=100.0).contains(&warning.dim) {
+                    problems.push(format!(
+                        "room {:?} warning dim must be a percentage in the range [0, 100], got {}",
+                        room.name, warning.dim
+                    ));
+                }
+                if let Some(cue) = &warning.cue {
+                    problems.extend(cue.problems(&format!("room {:?} warning cue", room.name)));
+                }
+            }
+            for (i, milestone) in room.milestones.iter().enumerate() {
+                if milestone.name.is_empty() || RESERVED_MILESTONES.contains(&&*milestone.name) {
+                    problems.push(format!(
+                        "room {:?} milestone name must not be empty or one of {:?}, got {:?}",
+                        room.name, RESERVED_MILESTONES, milestone.name
+                    ));
+                } else if room.milestones[..i]
+                    .iter()
+                    .any(|other| other.name == milestone.name)
+                {
+                    problems.push(format!(
+                        "room {:?} milestone {:?} is defined more than once",
+                        room.name, milestone.name
+                    ));
+                }
+                if milestone.after.is_zero() {
+                    problems.push(format!(
+                        "room {:?} milestone {:?} after must be more than zero",
+                        room.name, milestone.name
+                    ));
+                }
+            }
+        }
+
+        for schedule in &self.schedules {
+            for room in schedule.rooms.iter().filter(|r| self.room(r).is_none()) {
+                problems.push(format!(
+                    "schedule {:?} references unknown room {:?}",
+                    schedule.name, room
+                ));
+            }
+            if schedule.timeout.is_some_and(|timeout| timeout.is_zero()) {
+                problems.push(format!(
+                    "schedule {:?} timeout must be more than zero",
+                    schedule.name
+                ));
+            }
+            if let Some(brightness) = schedule.fade_brightness {
+                if !(0.0..=100.0).contains(&brightness) {
+                    problems.push(format!(
+                        "schedule {:?} fade_brightness must be a percentage in the range [0, 100], got {}",
+                        schedule.name, brightness
+                    ));
+                }
+            }
+        }
+
+        if self
+            .state
+            .as_ref()
+            .is_some_and(|state| state.max_age.is_zero())
+        {
+            problems.push("state.max_age must be more than zero".to_string());
+        }
+        if self.metrics.is_some() && self.api.is_none() {
+            problems
+                .push("metrics are served by the API, which needs an [api] section".to_string());
+        }
+
+        if let Some(mqtt) = &self.mqtt {
+            let prefix = &mqtt.topic_prefix;
+            if prefix.is_empty() || prefix.contains(['+', '#']) || prefix.ends_with('/') {
+                problems.push(format!(
+                    "mqtt.topic_prefix must be a topic without wildcards or trailing '/', got {:?}",
+                    prefix
+                ));
+            }
+            if let Some(prefix) = &mqtt.discovery_prefix {
+                if prefix.is_empty() || prefix.contains(['+', '#']) || prefix.ends_with('/') {
+                    problems.push(format!(
+                        "mqtt.discovery_prefix must be a topic without wildcards or trailing '/', got {:?}",
+                        prefix
+                    ));
+                }
+            }
+            if mqtt.state_interval.is_zero() {
+                problems.push("mqtt.state_interval must be more than zero".to_string());
+            }
+        }
+
+        if let Err(err) = tracing_subscriber::EnvFilter::try_new(&self.logging.filter) {
+            problems.push(format!(
+                "logging.filter {:?} is invalid: {}",
+                self.logging.filter, err
+            ));
+        }
+        if self.logging.max_files == Some(0) {
+            problems.push("logging.max_files must be more than zero".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+}
+
+/// A difference between a running and a reloaded configuration, see [`Config::diff`]
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigChange {
+    /// Shared timings have changed
+    Timings,
+    /// Timeout of `room` has changed
+    RoomTimeout {
+        room: String,
+        timeout: Duration,
+    },
+    /// Fade duration, brightness, power off, restore cue, warning or milestones of `room` have changed
+    RoomFade {
+        room: String,
+    },
+    /// Lights of `room` have been added or removed
+    RoomLights {
+        room: String,
+    },
+    /// Address or label of `light` has changed, so it has to be reconnected
+    Light {
+        light: String,
+    },
+    /// Sensors of `room` have changed
+    RoomSensors {
+        room: String,
+    },
+    /// GPIO chip, pin or replay file of `sensor` has changed, or it was added
+    Sensor {
+        sensor: String,
+    },
+    RoomAdded {
+        room: String,
+    },
+    RoomRemoved {
+        room: String,
+    },
+    /// Schedules have been added, removed or changed
+    Schedules,
+    /// HTTP API has been enabled, disabled or moved to another address
+    Api,
+    /// State file has been enabled, disabled or changed
+    State,
+    /// Metrics have been enabled, disabled or changed
+    Metrics,
+    /// MQTT broker or topics have changed
+    Mqtt,
+    /// Log levels have changed
+    LogFilter,
+    /// Log files or console logging have changed
+    LogOutput,
+}
+
+impl ConfigChange {
+    /// If the change can not be applied to a running daemon, since GPIO lines, the API, metrics, the state file, the MQTT connection and log files are only set up at startup
+    pub fn needs_restart(&self) -> bool {
+        matches!(
+            self,
+            ConfigChange::Sensor { .. }
+                | ConfigChange::Api
+                | ConfigChange::State
+                | ConfigChange::Metrics
+                | ConfigChange::Mqtt
+                | ConfigChange::LogOutput
+        )
+    }
+}
+
+impl fmt::Display for ConfigChange {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigChange::Timings => write!(fmt, "timings changed"),
+            ConfigChange::RoomTimeout { room, timeout } => {
+                write!(fmt, "room {:?} timeout changed to {:?}", room, timeout)
+            }
+            ConfigChange::RoomFade { room } => write!(fmt, "room {:?} fade changed", room),
+            ConfigChange::RoomLights { room } => write!(fmt, "room {:?} lights changed", room),
+            ConfigChange::Light { light } => write!(fmt, "light {:?} address changed", light),
+            ConfigChange::RoomSensors { room } => {
+                write!(fmt, "room {:?} sensors changed", room)
+            }
+            ConfigChange::Sensor { sensor } => write!(fmt, "sensor {:?} changed", sensor),
+            ConfigChange::RoomAdded { room } => write!(fmt, "room {:?} added", room),
+            ConfigChange::RoomRemoved { room } => write!(fmt, "room {:?} removed", room),
+            ConfigChange::Schedules => write!(fmt, "schedules changed"),
+            ConfigChange::Api => write!(fmt, "api changed"),
+            ConfigChange::State => write!(fmt, "state file changed"),
+            ConfigChange::Metrics => write!(fmt, "metrics changed"),
+            ConfigChange::Mqtt => write!(fmt, "mqtt changed"),
+            ConfigChange::LogFilter => write!(fmt, "log filter changed"),
+            ConfigChange::LogOutput => write!(fmt, "log output changed"),
+        }
+    }
+}
+
+impl Config {
+    /// Changes needed to go from this (running) configuration to the `new` configuration
+    pub fn diff(&self, new: &Config) -> Vec<ConfigChange> {
+        let mut changes = Vec::new();
+        if self.timings != new.timings {
+            changes.push(ConfigChange::Timings);
+        }
+        for sensor in &new.sensors {
+            if self.sensor(&sensor.name) != Some(sensor) {
+                changes.push(ConfigChange::Sensor {
+                    sensor: sensor.name.clone(),
+                });
+            }
+        }
+        for light in &new.lights {
+            if matches!(self.light(&light.name), Some(old) if old != light) {
+                changes.push(ConfigChange::Light {
+                    light: light.name.clone(),
+                });
+            }
+        }
+        for room in &self.rooms {
+            if new.room(&room.name).is_none() {
+                changes.push(ConfigChange::RoomRemoved {
+                    room: room.name.clone(),
+                });
+            }
+        }
+        for room in &new.rooms {
+            let name = room.name.clone();
+            let Some(old) = self.room(&room.name) else {
+                changes.push(ConfigChange::RoomAdded { room: name });
+                continue;
+            };
+            if old.timeout != room.timeout {
+                changes.push(ConfigChange::RoomTimeout {
+                    room: name.clone(),
+                    timeout: room.timeout,
+                });
+            }
+            if old.fade_duration != room.fade_duration
+                || old.fade_brightness != room.fade_brightness
+                || old.power_off != room.power_off
+                || old.restore_cue != room.restore_cue
+                || old.warning != room.warning
+                || old.milestones != room.milestones
+            {
+                changes.push(ConfigChange::RoomFade { room: name.clone() });
+            }
+            if old.lights != room.lights {
+                changes.push(ConfigChange::RoomLights { room: name.clone() });
+            }
+            if old.sensors != room.sensors {
+                changes.push(ConfigChange::RoomSensors { room: name });
+            }
+        }
+        if self.schedules != new.schedules {
+            changes.push(ConfigChange::Schedules);
+        }
+        if self.api != new.api {
+            changes.push(ConfigChange::Api);
+        }
+        if self.state != new.state {
+            changes.push(ConfigChange::State);
+        }
+        if self.metrics != new.metrics {
+            changes.push(ConfigChange::Metrics);
+        }
+        if self.mqtt != new.mqtt {
+            changes.push(ConfigChange::Mqtt);
+        }
+        if self.logging.filter != new.logging.filter {
+            changes.push(ConfigChange::LogFilter);
+        }
+        let output = |logging: &LoggingConfig| LoggingConfig {
+            filter: String::new(),
+            ..logging.clone()
+        };
+        if output(&self.logging) != output(&new.logging) {
+            changes.push(ConfigChange::LogOutput);
+        }
+        changes
+    }
+}
+
+/// Add a problem for every name that appears more than once
+fn duplicates<'a, I>(kind: &str, names: I, problems: &mut Vec<String>)
+where
+    I: Iterator<Item = &'a String>,
+{
+    let mut seen = HashSet::new();
+    for name in names {
+        if name.is_empty() {
+            problems.push(format!("{} name must not be empty", kind));
+        } else if !seen.insert(name) {
+            problems.push(format!("{} {:?} is defined more than once", kind, name));
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    /// Parse and validate a configuration
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let config: Config = toml::from_str(s).map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// Serialize [`Duration`] as (fractional) seconds
+pub(crate) mod secs {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        duration.as_secs_f64().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let secs = f64::deserialize(deserializer)?;
+        Duration::try_from_secs_f64(secs).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Serialize an optional [`Duration`] as (fractional) seconds
+pub(crate) mod option_secs {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => super::secs::serialize(duration, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Option::<f64>::deserialize(deserializer)?
+            .map(|secs| Duration::try_from_secs_f64(secs).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = include_str!("../motion_sensor_lifx.toml");
+
+    #[test]
+    fn test_example() {
+        let config: Config = EXAMPLE.parse().unwrap();
+        let room = config.room("bedroom").unwrap();
+        assert_eq!(room.timeout, TIMEOUT);
+        assert_eq!(room.fade_duration, FADE_DURATION);
+        assert_eq!(room.fade_brightness(), light::MIN);
+        assert_eq!(config.timings, Timings::default());
+        let sensor = config.sensor("hallway").unwrap();
+        assert_eq!(sensor.pin, Some(17));
+        assert_eq!(sensor.chip, PathBuf::from(DEFAULT_CHIP));
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config: Config = r#"
+            [[sensors]]
+            name = "pir"
+            pin = 4
+
+            [[lights]]
+            name = "lamp"
+            address = "127.0.0.1:56700"
+
+            [[rooms]]
+            name = "room"
+            sensors = ["pir"]
+            lights = ["lamp"]
+            timeout = 1.5
+            milestones = [{ name = "halfway", after = 0.75 }]
+        "#
+        .parse()
+        .unwrap();
+        let room = &config.rooms[0];
+        assert_eq!(room.timeout, Duration::from_millis(1500));
+        assert_eq!(room.fade_duration, FADE_DURATION);
+        assert_eq!(
+            room.milestones,
+            vec![MilestoneConfig {
+                name: "halfway".to_string(),
+                after: Duration::from_millis(750),
+            }]
+        );
+        assert_eq!(
+            config.light("lamp").unwrap().resolve(None),
+            Some("127.0.0.1:56700".parse().unwrap())
+        );
+        assert!(!config.needs_discovery());
+    }
+
+    /// Milestones past the timeout are reached while the lights are faded
+    #[test]
+    fn test_milestone_past_timeout() {
+        let config: Config = r#"
+            [[sensors]]
+            name = "pir"
+            pin = 4
+
+            [[lights]]
+            name = "lamp"
+            address = "127.0.0.1:56700"
+
+            [[rooms]]
+            name = "room"
+            sensors = ["pir"]
+            lights = ["lamp"]
+            timeout = 600
+            milestones = [{ name = "power off", after = 1200 }]
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(
+            config.rooms[0].milestones[0].after,
+            Duration::from_secs(1200)
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        let err = r#"
+            [[sensors]]
+            name = "door"
+
+            [[lights]]
+            name = "lamp"
+
+            [[lights]]
+            name = "lamp"
+            address = "127.0.0.1:56700"
+
+            [[rooms]]
+            name = "room"
+            sensors = ["pir"]
+            lights = ["lamp", "strip"]
+            fade_brightness = 150
+            restore_cue = { waveform = "pulse", skew = 2 }
+            warning = { before = 600, dim = 20 }
+            milestones = [
+                { name = "timeout", after = 60 },
+                { name = "half", after = 0 },
+                { name = "half", after = 60 },
+            ]
+
+            [[schedules]]
+            name = "night"
+            rooms = ["attic"]
+            start = "23:00"
+            end = "07:00"
+            timeout = 0
+
+            [mqtt]
+            host = "localhost"
+            topic_prefix = "home/#"
+
+            [logging]
+            filter = "info,light=loud"
+        "#
+        .parse::<Config>()
+        .unwrap_err();
+        let ConfigError::Invalid(problems) = err else {
+            panic!("expected validation error, got {}", err);
+        };
+        assert_eq!(
+            problems,
+            vec![
+                r#"light "lamp" is defined more than once"#,
+                r#"sensor "door" needs either a pin or a replay file"#,
+                r#"light "lamp" needs either an address or a label"#,
+                r#"room "room" references unknown sensor "pir""#,
+                r#"room "room" references unknown light "strip""#,
+                r#"room "room" fade_brightness must be a percentage in the range [0, 100], got 150"#,
+                r#"room "room" restore_cue skew must be in the range [0, 1], got 2"#,
+                r#"room "room" warning before must be more than zero and less than the timeout"#,
+                r#"room "room" milestone name must not be empty or one of ["start", "restart", "timeout", "warning"], got "timeout""#,
+                r#"room "room" milestone "half" after must be more than zero"#,
+                r#"room "room" milestone "half" is defined more than once"#,
+                r#"schedule "night" references unknown room "attic""#,
+                r#"schedule "night" timeout must be more than zero"#,
+                r#"mqtt.topic_prefix must be a topic without wildcards or trailing '/', got "home/#""#,
+                concat!(
+                    r#"logging.filter "info,light=loud" is invalid: error parsing level filter: "#,
+                    r#"expected one of "off", "error", "warn", "info", "debug", "trace", or a number 0-5"#
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let old: Config = EXAMPLE.parse().unwrap();
+        assert!(old.diff(&old).is_empty());
+
+        let mut new = old.clone();
+        new.rooms[0].timeout = Duration::from_secs(60);
+        new.rooms[0].fade_brightness = 10.0;
+        new.lights.push(LightConfig {
+            name: "lifxz".to_string(),
+            address: Some("127.0.0.1:56700".parse().unwrap()),
+            label: None,
+        });
+        new.rooms[0].lights.push("lifxz".to_string());
+        new.sensors[0].pin = Some(4);
+        let changes = old.diff(&new);
+        assert_eq!(
+            changes,
+            vec![
+                ConfigChange::Sensor {
+                    sensor: "hallway".to_string()
+                },
+                ConfigChange::RoomTimeout {
+                    room: "bedroom".to_string(),
+                    timeout: Duration::from_secs(60)
+                },
+                ConfigChange::RoomFade {
+                    room: "bedroom".to_string()
+                },
+                ConfigChange::RoomLights {
+                    room: "bedroom".to_string()
+                },
+            ]
+        );
+        assert!(changes[0].needs_restart());
+        assert!(!changes[1].needs_restart());
+
+        let mut new = old.clone();
+        new.sensors.push(SensorConfig {
+            name: "door".to_string(),
+            chip: PathBuf::from(DEFAULT_CHIP),
+            pin: Some(4),
+            replay: None,
+        });
+        new.rooms[0].sensors.push("door".to_string());
+        new.rooms.remove(0);
+        let changes = old.diff(&new);
+        assert_eq!(
+            changes,
+            vec![
+                ConfigChange::Sensor {
+                    sensor: "door".to_string()
+                },
+                ConfigChange::RoomRemoved {
+                    room: "bedroom".to_string()
+                },
+            ]
+        );
+        assert!(!changes[1].needs_restart());
+
+        let new: Config = format!(
+            "{}\n[[schedules]]\nname = \"night\"\nstart = \"23:00\"\nend = \"07:00\"\n",
+            EXAMPLE
+        )
+        .parse()
+        .unwrap();
+        assert_eq!(old.diff(&new), vec![ConfigChange::Schedules]);
+        assert!(!ConfigChange::Schedules.needs_restart());
+
+        let new: Config = format!("{}\n[api]\n", EXAMPLE).parse().unwrap();
+        assert_eq!(
+            new.api.as_ref().unwrap().bind,
+            DEFAULT_API_BIND.parse().unwrap()
+        );
+        assert_eq!(old.diff(&new), vec![ConfigChange::Api]);
+        assert!(ConfigChange::Api.needs_restart());
+
+        let new: Config = format!("{}\n[api]\n[metrics]\n", EXAMPLE).parse().unwrap();
+        assert_eq!(
+            new.metrics.as_ref().unwrap().temperature_file,
+            Some(PathBuf::from("/sys/class/thermal/thermal_zone0/temp"))
+        );
+        assert_eq!(
+            old.diff(&new),
+            vec![ConfigChange::Api, ConfigChange::Metrics]
+        );
+        assert!(ConfigChange::Metrics.needs_restart());
+
+        let new: Config = format!("{}\n[state]\nmax_age = 3600\n", EXAMPLE)
+            .parse()
+            .unwrap();
+        let state = new.state.as_ref().unwrap();
+        assert_eq!(state.file, PathBuf::from(DEFAULT_STATE_PATH));
+        assert_eq!(state.max_age, Duration::from_secs(3600));
+        assert_eq!(old.diff(&new), vec![ConfigChange::State]);
+        assert!(ConfigChange::State.needs_restart());
+        assert_eq!(
+            format!("{}\n[metrics]\n", EXAMPLE)
+                .parse::<Config>()
+                .unwrap_err()
+                .to_string(),
+            "invalid config file:\n  - metrics are served by the API, which needs an [api] section"
+        );
+
+        let new: Config = format!("{}\n[mqtt]\nhost = \"localhost\"\n", EXAMPLE)
+            .parse()
+            .unwrap();
+        let mqtt = new.mqtt.as_ref().unwrap();
+        assert_eq!(mqtt.port, 1883);
+        assert_eq!(mqtt.topic_prefix, DEFAULT_TOPIC_PREFIX);
+        assert_eq!(old.diff(&new), vec![ConfigChange::Mqtt]);
+
+        let new: Config = format!(
+            "{}\n[logging]\nfilter = \"info,motion_sensor_lifx::light=debug\"\nrotation = \"hourly\"\n",
+            EXAMPLE
+        )
+        .parse()
+        .unwrap();
+        assert_eq!(new.logging.rotation, LogRotation::Hourly);
+        let changes = old.diff(&new);
+        assert_eq!(
+            changes,
+            vec![ConfigChange::LogFilter, ConfigChange::LogOutput]
+        );
+        assert!(!changes[0].needs_restart());
+        assert!(changes[1].needs_restart());
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let err = "[[rooms]]\nname = 5".parse::<Config>().unwrap_err();
+        assert!(matches!(err, ConfigError::Parse(_)));
+        let err = "unknown = true".parse::<Config>().unwrap_err();
+        assert!(err.to_string().contains("unknown field"));
+    }
+}
@@ -0,0 +1,184 @@
+//! Waveform effects played on the lights of a room as cues
+//!
+//! A cue is a short change of color the light returns from by itself, like a soft pulse to show
+//! that motion brought the lights back. Color values left out are taken from the light, so a cue
+//! with only a brightness keeps the color of every light.
+//!
+//! ```toml
+//! [[rooms]]
+//! name = "bedroom"
+//! sensors = ["hallway"]
+//! lights = ["taklampa"]
+//!
+//! [rooms.restore_cue]
+//! waveform = "sine"
+//! brightness = 30
+//! period = 0.8
+//! cycles = 1
+//! ```
+
+use std::time::Duration;
+
+use lifx_core::Waveform;
+use serde::{Deserialize, Serialize};
+
+use crate::config::secs;
+use crate::light::{self, Wave};
+
+/// Shape of a cue, see [`Waveform`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CueWaveform {
+    /// Goes to the cue color and jumps back
+    Saw,
+    /// Goes to the cue color and back smoothly
+    #[default]
+    Sine,
+    /// Goes to the cue color smoothly and jumps back
+    HalfSine,
+    /// Goes to the cue color and back linearly
+    Triangle,
+    /// Jumps to the cue color for `skew` of the period and back
+    Pulse,
+}
+
+impl From<CueWaveform> for Waveform {
+    fn from(waveform: CueWaveform) -> Self {
+        match waveform {
+            CueWaveform::Saw => Waveform::Saw,
+            CueWaveform::Sine => Waveform::Sine,
+            CueWaveform::HalfSine => Waveform::HalfSign,
+            CueWaveform::Triangle => Waveform::Triangle,
+            CueWaveform::Pulse => Waveform::Pulse,
+        }
+    }
+}
+
+/// A waveform effect as given in the config file
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CueConfig {
+    #[serde(default)]
+    pub waveform: CueWaveform,
+    /// Hue in degrees of the cue color
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hue: Option<f32>,
+    /// Saturation in percent of the cue color
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub saturation: Option<f32>,
+    /// Brightness in percent of the cue color
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub brightness: Option<f32>,
+    /// Color temperature in kelvin of the cue color
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kelvin: Option<u16>,
+    /// Duration of one cycle
+    #[serde(default = "default_period", with = "secs")]
+    pub period: Duration,
+    #[serde(default = "default_cycles")]
+    pub cycles: f32,
+    /// Part of a cycle at the cue color for pulses, or spent reaching it for the other waveforms
+    #[serde(default = "default_skew")]
+    pub skew: f32,
+    /// Return to the color from before the cue, `false` keeps the cue color
+    #[serde(default = "default_transient")]
+    pub transient: bool,
+}
+
+fn default_period() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_cycles() -> f32 {
+    1.0
+}
+
+fn default_skew() -> f32 {
+    0.5
+}
+
+fn default_transient() -> bool {
+    true
+}
+
+impl CueConfig {
+    /// The cue as sent to a light
+    pub fn wave(&self) -> Wave {
+        Wave {
+            waveform: self.waveform.into(),
+            hue: self.hue.map(light::from_degrees),
+            saturation: self.saturation.map(light::from_percent),
+            brightness: self.brightness.map(light::from_percent),
+            kelvin: self.kelvin,
+            period: self.period,
+            cycles: self.cycles,
+            skew: self.skew,
+            transient: self.transient,
+        }
+    }
+
+    /// Problems with the values of the cue named `what` in error messages
+    pub fn problems(&self, what: &str) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (field, value) in [
+            ("saturation", self.saturation),
+            ("brightness", self.brightness),
+        ] {
+            if value.is_some_and(|value| !(0.0..=100.0).contains(&value)) {
+                problems.push(format!(
+                    "{} {} must be a percentage in the range [0, 100], got {}",
+                    what,
+                    field,
+                    value.unwrap()
+                ));
+            }
+        }
+        if self.period.is_zero() {
+            problems.push(format!("{} period must be more than zero", what));
+        }
+        if self.cycles <= 0.0 || self.cycles.is_nan() {
+            problems.push(format!(
+                "{} cycles must be more than zero, got {}",
+                what, self.cycles
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.skew) {
+            problems.push(format!(
+                "{} skew must be in the range [0, 1], got {}",
+                what, self.skew
+            ));
+        }
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wave() {
+        let cue: CueConfig =
+            toml::from_str("waveform = \"pulse\"\nbrightness = 50\nskew = 0.2").unwrap();
+        let wave = cue.wave();
+        assert_eq!(wave.waveform, Waveform::Pulse);
+        assert_eq!(wave.brightness, Some(light::from_percent(50.0)));
+        assert_eq!(wave.hue, None);
+        assert_eq!(wave.period, Duration::from_secs(1));
+        assert!(wave.transient);
+        assert!(cue.problems("cue").is_empty());
+
+        let cue = CueConfig {
+            brightness: Some(120.0),
+            cycles: 0.0,
+            ..cue
+        };
+        assert_eq!(
+            cue.problems("cue"),
+            vec![
+                "cue brightness must be a percentage in the range [0, 100], got 120",
+                "cue cycles must be more than zero, got 0",
+            ]
+        );
+    }
+}
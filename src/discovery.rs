@@ -0,0 +1,301 @@
+//! LAN discovery of lifx devices by broadcasting [`Message::GetService`]
+//!
+//! Every device that answers with a [`Message::StateService`] is asked for its label and group
+//! directly, the replies are collected into a [`Registry`] until the discovery timeout is reached.
+
+use std::error::Error;
+use std::fmt;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use lifx_core::{BuildOptions, Message, RawMessage, Service};
+
+use crate::Light;
+
+/// Broadcast address used to reach every lifx device on the LAN
+pub const BROADCAST: &str = "255.255.255.255:56700";
+/// Time to wait for devices to answer a discovery broadcast
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(1);
+/// Source identifier of discovery messages, non-zero so devices answer with unicast
+const DISCOVERY_SOURCE: u32 = 0x4d53_4c58;
+/// Size of frame, frame address and protocol header of a lifx message
+const HEADER_SIZE: usize = 36;
+
+/// A lifx device found on the LAN
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Device {
+    /// Device id (MAC address) used as `target` in the frame address
+    pub target: u64,
+    /// Address the device accepts UDP messages on
+    pub addr: SocketAddr,
+    /// Label of the device, if it answered [`Message::GetLabel`] in time
+    pub label: Option<String>,
+    /// Label of the group the device belongs to, if it answered [`Message::GetGroup`] in time
+    pub group: Option<String>,
+}
+
+impl Device {
+    /// MAC address of the device in the form `d0:73:d5:01:02:03`
+    pub fn mac(&self) -> String {
+        format_mac(self.target)
+    }
+
+    /// Create a new [`Light`] addressing this device
+    pub fn light(&self) -> Result<Light<SocketAddr>, std::io::Error> {
+        let mut light = Light::new(self.addr)?;
+        light.options.target = Some(self.target);
+        Ok(light)
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            fmt,
+            "{} {} {:?} in group {:?}",
+            self.mac(),
+            self.addr,
+            self.label.as_deref().unwrap_or(""),
+            self.group.as_deref().unwrap_or("")
+        )
+    }
+}
+
+/// Format a device `target` as a MAC address, the MAC is stored in the lower 6 bytes (little endian)
+pub fn format_mac(target: u64) -> String {
+    target.to_le_bytes()[..6]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Parse a MAC address like `d0:73:d5:01:02:03` (or with `-` separators) into a device `target`
+pub fn parse_mac(mac: &str) -> Option<u64> {
+    let mut bytes = [0; 8];
+    let mut parts = mac.split([':', '-']);
+    for byte in bytes.iter_mut().take(6) {
+        *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// Devices found by [`discover`], addressable by label, group or MAC
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Registry {
+    devices: Vec<Device>,
+}
+
+impl Registry {
+    /// All devices in the order they answered
+    pub fn devices(&self) -> &[Device] {
+        &self.devices
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// Device with `label`, case sensitive
+    pub fn by_label(&self, label: &str) -> Option<&Device> {
+        self.devices
+            .iter()
+            .find(|device| device.label.as_deref() == Some(label))
+    }
+
+    /// All devices in group with `group` label
+    pub fn by_group<'a>(&'a self, group: &'a str) -> impl Iterator<Item = &'a Device> + 'a {
+        self.devices
+            .iter()
+            .filter(move |device| device.group.as_deref() == Some(group))
+    }
+
+    /// Device with MAC address `mac`, see [`parse_mac`]
+    pub fn by_mac(&self, mac: &str) -> Option<&Device> {
+        let target = parse_mac(mac)?;
+        self.devices.iter().find(|device| device.target == target)
+    }
+
+    fn get_mut(&mut self, target: u64) -> Option<&mut Device> {
+        self.devices
+            .iter_mut()
+            .find(|device| device.target == target)
+    }
+}
+
+impl<'a> IntoIterator for &'a Registry {
+    type Item = &'a Device;
+    type IntoIter = std::slice::Iter<'a, Device>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.devices.iter()
+    }
+}
+
+/// Broadcast [`Message::GetService`] to `broadcast` and collect all devices answering within `timeout`.
+///
+/// Always blocks for the whole `timeout` since there is no way to know when every device has answered.
+pub fn discover<A: ToSocketAddrs>(
+    broadcast: A,
+    timeout: Duration,
+) -> Result<Registry, Box<dyn Error>> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_broadcast(true)?;
+    let options = BuildOptions {
+        source: DISCOVERY_SOURCE,
+        res_required: true,
+        ..Default::default()
+    };
+    let get_service = RawMessage::build(&options, Message::GetService)?.pack()?;
+    socket.send_to(&get_service, broadcast)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut registry = Registry::default();
+    let mut buf = [0; 1024];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                break
+            }
+            Err(err) => return Err(Box::new(err)),
+        };
+        // skip anything that is not a complete lifx message
+        let size = u16::from_le_bytes([buf[0], buf[1]]) as usize;
+        if len < HEADER_SIZE || size > len {
+            continue;
+        }
+        let raw = match RawMessage::unpack(&buf[..len]) {
+            Ok(raw) => raw,
+            Err(err) => {
+                tracing::debug!(%from, "skipping malformed packet: {:?}", err);
+                continue;
+            }
+        };
+        let target = raw.frame_addr.target;
+        match Message::from_raw(&raw) {
+            Ok(Message::StateService {
+                service: Service::UDP,
+                port,
+            }) if registry.get_mut(target).is_none() => {
+                let addr = SocketAddr::new(from.ip(), port as u16);
+                registry.devices.push(Device {
+                    target,
+                    addr,
+                    label: None,
+                    group: None,
+                });
+                // ask the new device directly for the rest of the information
+                let unicast = BuildOptions {
+                    target: Some(target),
+                    ..options
+                };
+                for message in [Message::GetLabel, Message::GetGroup] {
+                    socket.send_to(&RawMessage::build(&unicast, message)?.pack()?, addr)?;
+                }
+            }
+            Ok(Message::StateLabel { label }) => {
+                if let Some(device) = registry.get_mut(target) {
+                    device.label = Some(label.to_string());
+                }
+            }
+            Ok(Message::StateGroup { label, .. }) => {
+                if let Some(device) = registry.get_mut(target) {
+                    device.group = Some(label.to_string());
+                }
+            }
+            // duplicate services and unknown messages are ignored
+            _ => {}
+        }
+    }
+    Ok(registry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::FakeBulb;
+    use std::thread;
+
+    const TARGET: u64 = 0x0000_0302_01d5_73d0;
+
+    #[test]
+    fn test_mac() {
+        assert_eq!(format_mac(TARGET), "d0:73:d5:01:02:03");
+        assert_eq!(parse_mac("d0:73:d5:01:02:03"), Some(TARGET));
+        assert_eq!(parse_mac("D0-73-D5-01-02-03"), Some(TARGET));
+        assert_eq!(parse_mac("d0:73:d5:01:02"), None);
+        assert_eq!(parse_mac("d0:73:d5:01:02:03:04"), None);
+    }
+
+    #[test]
+    fn test_discover() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        bulb.set_group("Sovrum");
+        let registry = discover(bulb.addr(), Duration::from_millis(300)).unwrap();
+        assert_eq!(registry.len(), 1);
+        let device = registry
+            .by_label("Taklampa")
+            .expect("device found by label");
+        assert_eq!(device.addr, bulb.addr());
+        assert_eq!(device.target, bulb.target());
+        assert_eq!(device.group.as_deref(), Some("Sovrum"));
+        assert_eq!(registry.by_mac(&device.mac()), Some(device));
+        assert_eq!(registry.by_group("Sovrum").count(), 1);
+        assert!(registry.by_label("Fönster").is_none());
+
+        let light = device.light().unwrap();
+        assert_eq!(light.options.target, Some(bulb.target()));
+    }
+
+    /// A foreign packet on the lifx port does not end the discovery
+    #[test]
+    fn test_discover_foreign_packet() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let (target, port) = (bulb.target(), bulb.addr().port());
+        let broadcast = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = broadcast.local_addr().unwrap();
+        let answering = thread::spawn(move || {
+            let mut buf = [0; 1024];
+            let (_, from) = broadcast.recv_from(&mut buf).unwrap();
+            // lifx sized, but of another protocol version
+            let mut foreign = [0; HEADER_SIZE];
+            foreign[0] = HEADER_SIZE as u8;
+            broadcast.send_to(&foreign, from).unwrap();
+            let options = BuildOptions {
+                target: Some(target),
+                ..Default::default()
+            };
+            let service = Message::StateService {
+                service: Service::UDP,
+                port: port.into(),
+            };
+            let bytes = RawMessage::build(&options, service)
+                .unwrap()
+                .pack()
+                .unwrap();
+            broadcast.send_to(&bytes, from).unwrap();
+        });
+        let registry = discover(addr, Duration::from_millis(300)).unwrap();
+        answering.join().unwrap();
+        assert_eq!(registry.len(), 1);
+        assert!(registry.by_label("Taklampa").is_some());
+    }
+}
@@ -0,0 +1,119 @@
+//! Bus of things happening in the daemon, for bridges like MQTT to follow
+//!
+//! Every subscriber gets its own channel with every event published after it subscribed.
+//! Publishing never blocks, subscribers that hung up are dropped from the bus.
+
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lifx_core::HSBK;
+
+use crate::motion::Motion;
+use crate::ACTION;
+
+/// Something that happened in the daemon
+#[derive(Clone, Debug, PartialEq)]
+pub enum DaemonEvent {
+    /// Sensor with name `sensor` reported motion
+    Motion { sensor: String, motion: Motion },
+    /// Timer of `room` started, restarted or ran out
+    Timer { room: String, action: ACTION },
+    /// State or settings of `room` changed
+    Room { room: String },
+    /// Color or power of `light` was changed by a room, `color` is the color it was set to or left at if known
+    Light { light: String, color: Option<HSBK> },
+    /// Color and power of `light` were read by a room, like when polling
+    Observed {
+        light: String,
+        color: HSBK,
+        powered: bool,
+    },
+    /// Fade of `light` in `room` started, or ended by restoring or leaving the light
+    Fade {
+        room: String,
+        light: String,
+        outcome: FadeOutcome,
+    },
+    /// Request to `light` was answered or failed after `latency`
+    Request {
+        light: String,
+        latency: Duration,
+        failed: bool,
+    },
+}
+
+/// What happened to the fade of a light
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FadeOutcome {
+    /// Light started fading after the timeout
+    Started,
+    /// Light was restored to its color from before the fade
+    Restored,
+    /// Light was changed during the fade or while off, so it was left alone
+    Aborted,
+}
+
+impl fmt::Display for FadeOutcome {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FadeOutcome::Started => write!(fmt, "started"),
+            FadeOutcome::Restored => write!(fmt, "restored"),
+            FadeOutcome::Aborted => write!(fmt, "aborted"),
+        }
+    }
+}
+
+/// Handle to a bus of [`DaemonEvent`]s, clones publish to the same subscribers
+#[derive(Clone, Debug, Default)]
+pub struct Events {
+    subscribers: Arc<Mutex<Vec<Sender<DaemonEvent>>>>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Receive every event published from now on
+    pub fn subscribe(&self) -> Receiver<DaemonEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Send `event` to every subscriber
+    pub fn publish(&self, event: DaemonEvent) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_publish() {
+        let events = Events::new();
+        let motion = DaemonEvent::Motion {
+            sensor: "hallway".to_string(),
+            motion: Motion::On,
+        };
+        events.publish(motion.clone());
+
+        let first = events.subscribe();
+        let second = events.clone().subscribe();
+        events.publish(motion.clone());
+        assert_eq!(first.try_recv(), Ok(motion.clone()));
+        assert_eq!(second.try_recv(), Ok(motion.clone()));
+        assert!(first.try_recv().is_err(), "published before subscribing");
+
+        drop(second);
+        events.publish(motion);
+        assert_eq!(events.subscribers.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,771 @@
+//! In-process fake lifx bulb answering on a local UDP socket, to test without devices on the LAN
+//!
+//! # Example usage
+//!
+//! ```
+//! # use std::{thread, time::Duration};
+//! # use motion_sensor_lifx::{fade_target, fake::FakeBulb, Light};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let bulb = FakeBulb::new("Taklampa")?;
+//! let before = bulb.color();
+//! let light = Light::new(bulb.addr())?;
+//! light.change_color(fade_target, Duration::from_millis(100))?;
+//! thread::sleep(Duration::from_millis(200));
+//! assert_eq!(bulb.color(), fade_target(before));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::ffi::CString;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use lifx_core::{
+    ApplicationRequest, BuildOptions, LifxIdent, LifxString, Message, PowerLevel, RawMessage,
+    Service, HSBK,
+};
+
+use crate::clock::{self, Clock};
+use crate::light::{
+    build_raw, decode_colors, encode_colors, Tile, EXTENDED_ZONES, GET_64, GET_DEVICE_CHAIN,
+    MAX_TILES, SET_64, SET_EXTENDED_COLOR_ZONES, STATE_64, STATE_DEVICE_CHAIN, TILE_INFO_SIZE,
+    TILE_PIXELS, TILE_WIDTH_OFFSET,
+};
+use crate::Light;
+
+/// Interval the bulb thread checks if it should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Product id of the LIFX Z strip, reported by multizone bulbs
+const LIFX_Z: u32 = 32;
+/// Product id of the LIFX Tile, reported by matrix bulbs
+const LIFX_TILE: u32 = 55;
+/// Product id of the LIFX A19, reported by other bulbs
+const LIFX_A19: u32 = 27;
+
+/// Device ids handed out to fake bulbs, so every bulb has a unique MAC address
+static NEXT_TARGET: AtomicU64 = AtomicU64::new(0x0000_0000_01d5_73d0);
+
+/// Color transition started by [`Message::LightSetColor`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Fade {
+    from: HSBK,
+    to: HSBK,
+    started: Instant,
+    duration: Duration,
+}
+
+impl Fade {
+    /// Linear interpolation of the color at `now`
+    fn color(&self, now: Instant) -> HSBK {
+        let perc = if self.duration.is_zero() {
+            1.0
+        } else {
+            (now.duration_since(self.started).as_secs_f64() / self.duration.as_secs_f64())
+                .clamp(0.0, 1.0)
+        };
+        let lerp = |from: u16, to: u16| (from as f64 + (to as f64 - from as f64) * perc) as u16;
+        HSBK {
+            hue: lerp(self.from.hue, self.to.hue),
+            saturation: lerp(self.from.saturation, self.to.saturation),
+            brightness: lerp(self.from.brightness, self.to.brightness),
+            kelvin: lerp(self.from.kelvin, self.to.kelvin),
+        }
+    }
+
+    fn is_done(&self, now: Instant) -> bool {
+        now.duration_since(self.started) >= self.duration
+    }
+}
+
+/// State of the fake bulb, shared between the bulb thread and the test
+#[derive(Debug)]
+struct BulbState {
+    label: String,
+    group: String,
+    target: u64,
+    power: u16,
+    color: HSBK,
+    fade: Option<Fade>,
+    /// Colors of the zones of a multizone bulb, changed at once without fading
+    zones: Option<Vec<HSBK>>,
+    /// Zone changes not applied yet, see [`ApplicationRequest`]
+    pending: Vec<HSBK>,
+    /// Size and pixels row by row of every tile of a matrix bulb, changed at once without fading
+    tiles: Option<Vec<(Tile, Vec<HSBK>)>>,
+    /// Major and minor firmware version
+    firmware: (u16, u16),
+    started: Instant,
+    received: Vec<Message>,
+    /// Number of upcoming messages to ignore, to simulate packet loss
+    drop: usize,
+    /// Clock the fades run on
+    clock: Arc<dyn Clock>,
+}
+
+impl BulbState {
+    fn color(&self) -> HSBK {
+        match self.fade {
+            Some(fade) => fade.color(self.clock.now()),
+            None => self.color,
+        }
+    }
+
+    /// Apply `message` and get the replies a real bulb would send back
+    fn handle(&mut self, message: Message, port: u16, res_required: bool) -> Vec<Message> {
+        let state = match message {
+            Message::GetService => Message::StateService {
+                service: Service::UDP,
+                port: port as u32,
+            },
+            Message::GetHostInfo => Message::StateHostInfo {
+                signal: 1e-5,
+                tx: 0,
+                rx: 0,
+                reserved: 0,
+            },
+            Message::GetHostFirmware => Message::StateHostFirmware {
+                build: 0,
+                reserved: 0,
+                version_minor: self.firmware.1,
+                version_major: self.firmware.0,
+            },
+            Message::GetWifiInfo => Message::StateWifiInfo {
+                signal: 1e-5,
+                reserved6: 0,
+                reserved7: 0,
+                reserved: 0,
+            },
+            Message::GetWifiFirmware => Message::StateWifiFirmware {
+                build: 0,
+                reserved: 0,
+                version_minor: 0,
+                version_major: 0,
+            },
+            Message::GetVersion => Message::StateVersion {
+                vendor: 1,
+                product: if self.zones.is_some() {
+                    LIFX_Z
+                } else if self.tiles.is_some() {
+                    LIFX_TILE
+                } else {
+                    LIFX_A19
+                },
+                reserved: 0,
+            },
+            Message::GetInfo => Message::StateInfo {
+                time: 0,
+                uptime: (self.clock.now() - self.started).as_nanos() as u64,
+                downtime: 0,
+            },
+            Message::GetLocation => Message::StateLocation {
+                location: LifxIdent([0; 16]),
+                label: LifxString::new(&CString::new("Home").unwrap()),
+                updated_at: 0,
+            },
+            Message::GetGroup => Message::StateGroup {
+                group: LifxIdent([0; 16]),
+                label: LifxString::new(&CString::new(self.group.clone()).unwrap_or_default()),
+                updated_at: 0,
+            },
+            Message::GetLabel => Message::StateLabel {
+                label: self.label(),
+            },
+            Message::EchoRequest { payload } => Message::EchoResponse { payload },
+            Message::GetPower => Message::StatePower { level: self.power },
+            Message::LightGetPower => Message::LightStatePower { level: self.power },
+            Message::LightGet => self.light_state(),
+            // set messages only answer if a response is required
+            Message::SetLabel { label } => {
+                self.label = label.to_string();
+                let state = Message::StateLabel {
+                    label: self.label(),
+                };
+                return self.reply_if(res_required, state);
+            }
+            Message::SetPower { level } => {
+                self.power = match level {
+                    PowerLevel::Standby => 0,
+                    PowerLevel::Enabled => u16::MAX,
+                };
+                return self.reply_if(res_required, Message::StatePower { level: self.power });
+            }
+            Message::LightSetPower { level, .. } => {
+                self.power = level;
+                let state = Message::LightStatePower { level: self.power };
+                return self.reply_if(res_required, state);
+            }
+            Message::LightSetColor {
+                color, duration, ..
+            } => {
+                // reply with the state before the change, like a real bulb
+                let state = self.light_state();
+                self.fade = Some(Fade {
+                    from: self.color(),
+                    to: color,
+                    started: self.clock.now(),
+                    duration: Duration::from_millis(duration as u64),
+                });
+                self.color = color;
+                if let Some(zones) = &mut self.zones {
+                    zones.fill(color);
+                    self.pending.clone_from(zones);
+                }
+                for (_, pixels) in self.tiles.iter_mut().flatten() {
+                    pixels.fill(color);
+                }
+                return self.reply_if(res_required, state);
+            }
+            // a transient wave ends at the color from before, only a lasting one changes it
+            Message::SetWaveform {
+                transient, color, ..
+            } => {
+                if !transient {
+                    self.set_color(color);
+                }
+                return Vec::new();
+            }
+            Message::SetWaveformOptional {
+                transient,
+                color,
+                set_hue,
+                set_saturation,
+                set_brightness,
+                set_kelvin,
+                ..
+            } => {
+                if !transient {
+                    let current = self.color();
+                    self.set_color(HSBK {
+                        hue: if set_hue { color.hue } else { current.hue },
+                        saturation: if set_saturation {
+                            color.saturation
+                        } else {
+                            current.saturation
+                        },
+                        brightness: if set_brightness {
+                            color.brightness
+                        } else {
+                            current.brightness
+                        },
+                        kelvin: if set_kelvin {
+                            color.kelvin
+                        } else {
+                            current.kelvin
+                        },
+                    });
+                }
+                return Vec::new();
+            }
+            Message::GetColorZones {
+                start_index,
+                end_index,
+            } => return self.state_multi_zones(start_index as usize, end_index as usize),
+            Message::GetExtendedColorZone => return self.state_extended_zones(),
+            Message::SetColorZones {
+                start_index,
+                end_index,
+                color,
+                apply,
+                ..
+            } if self.zones.is_some() => {
+                if apply != ApplicationRequest::ApplyOnly {
+                    let end = (end_index as usize).min(self.pending.len().saturating_sub(1));
+                    for zone in self
+                        .pending
+                        .iter_mut()
+                        .take(end + 1)
+                        .skip(start_index as usize)
+                    {
+                        *zone = color;
+                    }
+                }
+                if apply != ApplicationRequest::NoApply {
+                    self.apply_zones();
+                }
+                return Vec::new();
+            }
+            _ => return Vec::new(),
+        };
+        vec![state]
+    }
+
+    /// Change the color at once, ending a running fade
+    fn set_color(&mut self, color: HSBK) {
+        self.color = color;
+        self.fade = None;
+    }
+
+    /// Apply a message of type `typ` that [`Message`] does not have from its raw `payload`, and get the replies
+    fn handle_raw(&mut self, typ: u16, payload: &[u8]) -> Vec<(u16, Vec<u8>)> {
+        match typ {
+            SET_EXTENDED_COLOR_ZONES => self.set_extended_zones(payload),
+            GET_DEVICE_CHAIN => return self.state_device_chain().into_iter().collect(),
+            GET_64 if payload.len() >= 6 => return self.state_64(payload),
+            SET_64 if payload.len() >= 10 + TILE_PIXELS * 8 => self.set_64(payload),
+            _ => {}
+        }
+        Vec::new()
+    }
+
+    /// Apply a `SetExtendedColorZones` message from its raw `payload`
+    fn set_extended_zones(&mut self, payload: &[u8]) {
+        if self.zones.is_none() || payload.len() < 8 + EXTENDED_ZONES * 8 {
+            return;
+        }
+        let apply = payload[4];
+        let index = u16::from_le_bytes([payload[5], payload[6]]) as usize;
+        let colors = decode_colors(&payload[8..], payload[7] as usize);
+        if apply != ApplicationRequest::ApplyOnly as u8 {
+            for (zone, color) in self.pending.iter_mut().skip(index).zip(colors) {
+                *zone = color;
+            }
+        }
+        if apply != ApplicationRequest::NoApply as u8 {
+            self.apply_zones();
+        }
+    }
+
+    /// `StateDeviceChain` reply with the size of every tile of a matrix bulb
+    fn state_device_chain(&self) -> Option<(u16, Vec<u8>)> {
+        let tiles = self.tiles.as_ref()?;
+        let mut payload = vec![0; 2 + MAX_TILES * TILE_INFO_SIZE];
+        for (index, (tile, _)) in tiles.iter().take(MAX_TILES).enumerate() {
+            let at = 1 + index * TILE_INFO_SIZE + TILE_WIDTH_OFFSET;
+            payload[at] = tile.width;
+            payload[at + 1] = tile.height;
+        }
+        payload[1 + MAX_TILES * TILE_INFO_SIZE] = tiles.len().min(MAX_TILES) as u8;
+        Some((STATE_DEVICE_CHAIN, payload))
+    }
+
+    /// Positions in the pixels of `tile` of the pixels in a rectangle `width` wide from `x`, `y`
+    fn rectangle(tile: Tile, x: u8, y: u8, width: u8) -> impl Iterator<Item = Option<usize>> {
+        let width = width.max(1) as usize;
+        (0..TILE_PIXELS).map(move |i| {
+            let (px, py) = (x as usize + i % width, y as usize + i / width);
+            (px < tile.width as usize && py < tile.height as usize)
+                .then_some(py * tile.width as usize + px)
+        })
+    }
+
+    /// `State64` replies with the pixels of the tiles asked for by a `Get64` message
+    fn state_64(&self, payload: &[u8]) -> Vec<(u16, Vec<u8>)> {
+        let Some(tiles) = &self.tiles else {
+            return Vec::new();
+        };
+        let (first, length, x, y, width) =
+            (payload[0], payload[1], payload[3], payload[4], payload[5]);
+        tiles
+            .iter()
+            .enumerate()
+            .skip(first as usize)
+            .take(length as usize)
+            .map(|(index, (tile, pixels))| {
+                let colors: Vec<HSBK> = Self::rectangle(*tile, x, y, width)
+                    .map(|at| at.map_or(self.color, |at| pixels[at]))
+                    .collect();
+                let mut reply = vec![index as u8, 0, x, y, width];
+                encode_colors(&mut reply, &colors, TILE_PIXELS);
+                (STATE_64, reply)
+            })
+            .collect()
+    }
+
+    /// Apply a `Set64` message, the bulb reports the first pixel as its color
+    fn set_64(&mut self, payload: &[u8]) {
+        let Some(tiles) = &mut self.tiles else {
+            return;
+        };
+        let (first, length, x, y, width) =
+            (payload[0], payload[1], payload[3], payload[4], payload[5]);
+        let colors = decode_colors(&payload[10..], TILE_PIXELS);
+        for (tile, pixels) in tiles.iter_mut().skip(first as usize).take(length as usize) {
+            for (at, &color) in Self::rectangle(*tile, x, y, width).zip(&colors) {
+                if let Some(at) = at {
+                    pixels[at] = color;
+                }
+            }
+        }
+        if let Some(&first) = tiles.first().and_then(|(_, pixels)| pixels.first()) {
+            self.color = first;
+            self.fade = None;
+        }
+    }
+
+    /// Apply the pending zone changes, the bulb reports the first zone as its color
+    fn apply_zones(&mut self) {
+        self.zones = Some(self.pending.clone());
+        if let Some(&first) = self.pending.first() {
+            self.color = first;
+            self.fade = None;
+        }
+    }
+
+    /// `StateMultiZone` replies of eight zones each covering zones `start..=end`
+    fn state_multi_zones(&self, start: usize, end: usize) -> Vec<Message> {
+        let Some(zones) = &self.zones else {
+            return Vec::new();
+        };
+        let end = end.min(zones.len().saturating_sub(1));
+        let zone = |index: usize| zones.get(index).copied().unwrap_or(self.color);
+        (start..=end)
+            .step_by(8)
+            .map(|index| Message::StateMultiZone {
+                count: zones.len() as u8,
+                index: index as u8,
+                color0: zone(index),
+                color1: zone(index + 1),
+                color2: zone(index + 2),
+                color3: zone(index + 3),
+                color4: zone(index + 4),
+                color5: zone(index + 5),
+                color6: zone(index + 6),
+                color7: zone(index + 7),
+            })
+            .collect()
+    }
+
+    /// `StateExtendedColorZones` replies of 82 zones each covering all zones
+    fn state_extended_zones(&self) -> Vec<Message> {
+        let Some(zones) = &self.zones else {
+            return Vec::new();
+        };
+        zones
+            .chunks(EXTENDED_ZONES)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut colors = Box::new([self.color; EXTENDED_ZONES]);
+                colors[..chunk.len()].copy_from_slice(chunk);
+                Message::StateExtendedColorZones {
+                    zones_count: zones.len() as u16,
+                    zone_index: (i * EXTENDED_ZONES) as u16,
+                    colors_count: chunk.len() as u8,
+                    colors,
+                }
+            })
+            .collect()
+    }
+
+    fn reply_if(&self, res_required: bool, message: Message) -> Vec<Message> {
+        if res_required {
+            vec![message]
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn label(&self) -> LifxString {
+        LifxString::new(&CString::new(self.label.clone()).unwrap_or_default())
+    }
+
+    fn light_state(&self) -> Message {
+        Message::LightState {
+            color: self.color(),
+            reserved: 0,
+            power: self.power,
+            label: self.label(),
+            reserved2: 0,
+        }
+    }
+}
+
+/// A fake bulb listening on a local UDP socket until dropped
+#[derive(Debug)]
+pub struct FakeBulb {
+    addr: SocketAddr,
+    state: Arc<Mutex<BulbState>>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FakeBulb {
+    /// Start a new bulb with `label` that is powered on with full white brightness
+    pub fn new(label: &str) -> Result<Self, io::Error> {
+        Self::with_clock(label, clock::real())
+    }
+
+    /// Start a new bulb with `label` that runs its fades on `clock`
+    pub fn with_clock(label: &str, clock: Arc<dyn Clock>) -> Result<Self, io::Error> {
+        let socket = UdpSocket::bind("127.0.0.1:0")?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let addr = socket.local_addr()?;
+
+        let state = Arc::new(Mutex::new(BulbState {
+            label: label.to_string(),
+            group: String::new(),
+            target: NEXT_TARGET.fetch_add(1 << 40, Ordering::Relaxed),
+            power: u16::MAX,
+            color: HSBK {
+                hue: 0,
+                saturation: 0,
+                brightness: u16::MAX,
+                kelvin: 3500,
+            },
+            fade: None,
+            zones: None,
+            pending: Vec::new(),
+            tiles: None,
+            firmware: (3, 70),
+            started: clock.now(),
+            received: Vec::new(),
+            drop: 0,
+            clock,
+        }));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let state_inner = state.clone();
+        let running_inner = running.clone();
+        let thread = thread::Builder::new()
+            .name(format!("fake_bulb_{}", label))
+            .spawn(move || {
+                let mut buf = [0; 1024];
+                while running_inner.load(Ordering::Relaxed) {
+                    let (len, from) = match socket.recv_from(&mut buf) {
+                        Ok(received) => received,
+                        Err(_) => continue,
+                    };
+                    let raw = match RawMessage::unpack(&buf[..len]) {
+                        Ok(raw) => raw,
+                        Err(_) => continue,
+                    };
+                    // messages lifx-core does not have are handled from their raw payload
+                    let message = Message::from_raw(&raw).ok();
+
+                    let mut state = state_inner.lock().unwrap();
+                    if state.drop > 0 {
+                        state.drop -= 1;
+                        continue;
+                    }
+                    let mut replies = Vec::new();
+                    let mut raw_replies = Vec::new();
+                    if raw.frame_addr.ack_required {
+                        replies.push(Message::Acknowledgement {
+                            seq: raw.frame_addr.sequence,
+                        });
+                    }
+                    match message {
+                        Some(message) => {
+                            state.received.push(message.clone());
+                            let res_required = raw.frame_addr.res_required;
+                            replies.extend(state.handle(message, addr.port(), res_required));
+                        }
+                        None => {
+                            raw_replies = state.handle_raw(raw.protocol_header.typ, &raw.payload)
+                        }
+                    }
+
+                    let options = BuildOptions {
+                        target: Some(state.target),
+                        source: raw.frame.source,
+                        sequence: raw.frame_addr.sequence,
+                        ..Default::default()
+                    };
+                    drop(state);
+                    let replies = replies
+                        .into_iter()
+                        .map(|reply| RawMessage::build(&options, reply))
+                        .chain(
+                            raw_replies
+                                .into_iter()
+                                .map(|(typ, payload)| build_raw(&options, typ, payload)),
+                        );
+                    for reply in replies {
+                        if let Ok(bytes) = reply.and_then(|raw| raw.pack()) {
+                            let _ = socket.send_to(&bytes, from);
+                        }
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            addr,
+            state,
+            running,
+            thread: Some(thread),
+        })
+    }
+
+    /// Start a new multizone bulb with `label` like the LIFX Z strip, with `zones` zones of full white brightness
+    pub fn multizone(label: &str, zones: usize) -> Result<Self, io::Error> {
+        let bulb = Self::new(label)?;
+        {
+            let mut state = bulb.state.lock().unwrap();
+            state.zones = Some(vec![state.color; zones]);
+            state.pending = vec![state.color; zones];
+        }
+        Ok(bulb)
+    }
+
+    /// Start a new matrix bulb with `label` like a chain of LIFX Tiles or a Candle, with `tiles` of full white brightness
+    pub fn matrix(label: &str, tiles: &[Tile]) -> Result<Self, io::Error> {
+        let bulb = Self::new(label)?;
+        {
+            let mut state = bulb.state.lock().unwrap();
+            let color = state.color;
+            state.tiles = Some(
+                tiles
+                    .iter()
+                    .map(|&tile| {
+                        (
+                            tile,
+                            vec![color; tile.width as usize * tile.height as usize],
+                        )
+                    })
+                    .collect(),
+            );
+        }
+        Ok(bulb)
+    }
+
+    /// Address the bulb is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Device id (MAC address) of the bulb
+    pub fn target(&self) -> u64 {
+        self.state.lock().unwrap().target
+    }
+
+    /// Create a new [`Light`] connected to this bulb
+    pub fn light(&self) -> Result<Light<SocketAddr>, io::Error> {
+        Light::new(self.addr)
+    }
+
+    /// Current color, in between colors if a fade is running
+    pub fn color(&self) -> HSBK {
+        self.state.lock().unwrap().color()
+    }
+
+    /// Change the color instantly, like a person using the app would
+    pub fn set_color(&self, color: HSBK) {
+        let mut state = self.state.lock().unwrap();
+        state.color = color;
+        state.fade = None;
+        let count = state.pending.len();
+        if state.zones.is_some() {
+            state.pending = vec![color; count];
+            state.apply_zones();
+        }
+    }
+
+    /// Colors of the zones of a multizone bulb, `None` for other bulbs
+    pub fn zones(&self) -> Option<Vec<HSBK>> {
+        self.state.lock().unwrap().zones.clone()
+    }
+
+    /// Change the zone colors of a multizone bulb instantly, like a person using the app would
+    pub fn set_zones(&self, zones: &[HSBK]) {
+        let mut state = self.state.lock().unwrap();
+        state.pending = zones.to_vec();
+        state.apply_zones();
+    }
+
+    /// Pixels row by row of every tile of a matrix bulb, `None` for other bulbs
+    pub fn pixels(&self) -> Option<Vec<Vec<HSBK>>> {
+        let state = self.state.lock().unwrap();
+        let tiles = state.tiles.as_ref()?;
+        Some(tiles.iter().map(|(_, pixels)| pixels.clone()).collect())
+    }
+
+    /// Change the pixels of the tiles of a matrix bulb instantly, like a person using the app would
+    pub fn set_pixels(&self, pixels: &[Vec<HSBK>]) {
+        let mut state = self.state.lock().unwrap();
+        for ((_, current), pixels) in state.tiles.iter_mut().flatten().zip(pixels) {
+            current.clone_from(pixels);
+        }
+        if let Some(&first) = pixels.first().and_then(|pixels| pixels.first()) {
+            state.color = first;
+            state.fade = None;
+        }
+    }
+
+    /// Report firmware version `major.minor`, multizone bulbs before 2.77 only understand the legacy zone messages
+    pub fn set_firmware(&self, major: u16, minor: u16) {
+        self.state.lock().unwrap().firmware = (major, minor);
+    }
+
+    /// If a color transition is still running
+    pub fn is_fading(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        matches!(state.fade, Some(fade) if !fade.is_done(state.clock.now()))
+    }
+
+    /// Current power level, `0` is off and `65535` is on
+    pub fn power(&self) -> u16 {
+        self.state.lock().unwrap().power
+    }
+
+    pub fn set_power(&self, level: u16) {
+        self.state.lock().unwrap().power = level;
+    }
+
+    pub fn set_group(&self, group: &str) {
+        self.state.lock().unwrap().group = group.to_string();
+    }
+
+    /// All messages received by the bulb, in order
+    pub fn received(&self) -> Vec<Message> {
+        self.state.lock().unwrap().received.clone()
+    }
+
+    /// Ignore the next `count` messages, as if they were lost on the network
+    pub fn drop_next(&self, count: usize) {
+        self.state.lock().unwrap().drop = count;
+    }
+}
+
+impl Drop for FakeBulb {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fade_target, light};
+
+    #[test]
+    fn test_fade() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let light = bulb.light().unwrap();
+        let before = bulb.color();
+        light
+            .change_color(fade_target, Duration::from_millis(400))
+            .unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert!(bulb.is_fading());
+        let brightness = bulb.color().brightness;
+        assert!(
+            light::MIN < brightness && brightness < before.brightness,
+            "brightness is in between before and target while fading"
+        );
+        thread::sleep(Duration::from_millis(300));
+        assert!(!bulb.is_fading());
+        assert_eq!(bulb.color(), fade_target(before));
+    }
+
+    #[test]
+    fn test_unchanged_color_is_not_sent() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let light = bulb.light().unwrap();
+        light.change_color(|color| color, Duration::ZERO).unwrap();
+        assert_eq!(bulb.received(), vec![Message::LightGet]);
+    }
+
+    #[test]
+    fn test_unique_targets() {
+        let first = FakeBulb::new("First").unwrap();
+        let second = FakeBulb::new("Second").unwrap();
+        assert_ne!(first.target(), second.target());
+    }
+}
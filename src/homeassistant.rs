@@ -0,0 +1,208 @@
+//! Home Assistant MQTT discovery, so sensors, rooms and lights show up as entities, with the `mqtt` feature
+//!
+//! With `discovery_prefix = "homeassistant"` in the `[mqtt]` section the [bridge](crate::mqtt)
+//! publishes a retained config message for every entity whenever it connects:
+//!
+//! - a `binary_sensor` with device class `motion` for every sensor
+//! - a `switch` turning motion control of every room on or off, off is the same as pausing the room
+//! - a `number` with the timeout of every room in seconds
+//! - a `light` for every light, with brightness, color and color temperature
+//!
+//! Entities read the state topics of the bridge and send to its command topics, so everything
+//! can be used without Home Assistant too.
+
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+
+use crate::mqtt::{Publication, Topics};
+use crate::room::all_lights;
+use crate::Room;
+
+/// Prefix Home Assistant subscribes to for discovery by default
+pub const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+/// Color temperatures of lifx lights in mireds, for 9000 to 1500 kelvin
+const MIREDS: (u32, u32) = (111, 667);
+
+/// Payload of the light command topic, a [`crate::api::LightChange`] made from the Home Assistant request
+const LIGHT_ON: &str = concat!(
+    r#"{"power": true"#,
+    r#"{% if brightness is defined %}, "brightness": {{ brightness / 2.55 }}{% endif %}"#,
+    r#"{% if hue is defined %}, "hue": {{ hue }}, "saturation": {{ sat }}{% endif %}"#,
+    r#"{% if color_temp is defined %}, "kelvin": {{ (1000000 / color_temp) | int }}{% endif %}"#,
+    r#"{% if transition is defined %}, "duration": {{ transition }}{% endif %}}"#,
+);
+const LIGHT_OFF: &str = concat!(
+    r#"{"power": false"#,
+    r#"{% if transition is defined %}, "duration": {{ transition }}{% endif %}}"#,
+);
+
+/// Name usable in a topic and unique id, other characters are replaced by `_`
+fn object_id(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Config messages of every entity of `rooms`, below `prefix` and identified by `node_id`
+pub fn discovery(
+    topics: &Topics,
+    prefix: &str,
+    node_id: &str,
+    rooms: &[Arc<Room>],
+) -> Vec<Publication> {
+    let node = object_id(node_id);
+    let device = json!({
+        "identifiers": [node],
+        "name": "Motion sensor LIFX",
+        "model": "motion_sensor_lifx",
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    });
+    let config = |component: &str, name: &str, kind: &str, mut config: Value| {
+        let object = format!("{}_{}", object_id(name), kind);
+        config["unique_id"] = json!(format!("{}_{}", node, object));
+        config["availability_topic"] = json!(topics.status());
+        config["device"] = device.clone();
+        Publication {
+            topic: format!("{}/{}/{}/{}/config", prefix, component, node, object),
+            payload: config.to_string(),
+            retain: true,
+        }
+    };
+
+    let mut sensors: Vec<String> = Vec::new();
+    for sensor in rooms.iter().flat_map(|room| room.config().sensors) {
+        if !sensors.contains(&sensor) {
+            sensors.push(sensor);
+        }
+    }
+    let mut publications: Vec<_> = sensors
+        .iter()
+        .map(|sensor| {
+            config(
+                "binary_sensor",
+                sensor,
+                "motion",
+                json!({
+                    "name": format!("{} motion", sensor),
+                    "device_class": "motion",
+                    "state_topic": topics.motion(sensor),
+                    "payload_on": "on",
+                    "payload_off": "off",
+                }),
+            )
+        })
+        .collect();
+
+    for room in rooms {
+        let name = room.name();
+        publications.push(config(
+            "switch",
+            name,
+            "motion_control",
+            json!({
+                "name": format!("{} motion control", name),
+                "icon": "mdi:motion-sensor",
+                "state_topic": topics.room_state(name),
+                "value_template": "{{ value_json.paused | lower }}",
+                // on resumes and off pauses the room
+                "command_topic": topics.pause(name),
+                "payload_on": "false",
+                "payload_off": "true",
+            }),
+        ));
+        publications.push(config(
+            "number",
+            name,
+            "timeout",
+            json!({
+                "name": format!("{} timeout", name),
+                "icon": "mdi:timer-outline",
+                "state_topic": topics.room_state(name),
+                "value_template": "{{ value_json.timeout }}",
+                "command_topic": topics.timeout(name),
+                "min": 1,
+                "max": 86400,
+                "step": 1,
+                "mode": "box",
+                "unit_of_measurement": "s",
+            }),
+        ));
+    }
+
+    for (name, _) in all_lights(rooms) {
+        publications.push(config(
+            "light",
+            &name,
+            "light",
+            json!({
+                "name": name,
+                "schema": "template",
+                "state_topic": topics.light_state(&name),
+                "command_topic": topics.light_set(&name),
+                "command_on_template": LIGHT_ON,
+                "command_off_template": LIGHT_OFF,
+                "state_template": "{{ 'on' if value_json.power else 'off' }}",
+                "brightness_template": "{{ (value_json.brightness * 2.55) | round(0) | int }}",
+                "color_temp_template": "{{ (1000000 / value_json.kelvin) | round(0) | int }}",
+                "min_mireds": MIREDS.0,
+                "max_mireds": MIREDS.1,
+            }),
+        ));
+    }
+    publications
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{RoomConfig, Timings};
+    use crate::fake::FakeBulb;
+    use std::time::Duration;
+
+    #[test]
+    fn test_object_id() {
+        assert_eq!(object_id("living room/2"), "living_room_2");
+        assert_eq!(object_id("fönster-1"), "f_nster-1");
+    }
+
+    #[test]
+    fn test_discovery() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let config = RoomConfig::test("bedroom", Duration::from_secs(10));
+        let lights = vec![("lamp".to_string(), bulb.light().unwrap())];
+        let rooms = vec![Arc::new(Room::new(config, Timings::default(), lights))];
+        let topics = Topics::new("pir");
+
+        let publications = discovery(&topics, "homeassistant", "pir", &rooms);
+        let topics: Vec<_> = publications.iter().map(|p| p.topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            [
+                "homeassistant/binary_sensor/pir/hallway_motion/config",
+                "homeassistant/switch/pir/bedroom_motion_control/config",
+                "homeassistant/number/pir/bedroom_timeout/config",
+                "homeassistant/light/pir/lamp_light/config",
+            ]
+        );
+        assert!(publications.iter().all(|p| p.retain));
+
+        let configs: Vec<Value> = publications
+            .iter()
+            .map(|p| serde_json::from_str(&p.payload).unwrap())
+            .collect();
+        assert_eq!(configs[0]["state_topic"], "pir/sensors/hallway/motion");
+        assert_eq!(configs[0]["unique_id"], "pir_hallway_motion");
+        assert_eq!(configs[1]["command_topic"], "pir/rooms/bedroom/pause/set");
+        assert_eq!(configs[2]["command_topic"], "pir/rooms/bedroom/timeout/set");
+        assert_eq!(configs[3]["command_topic"], "pir/lights/lamp/set");
+        assert_eq!(configs[3]["availability_topic"], "pir/status");
+        assert_eq!(configs[3]["device"]["identifiers"][0], "pir");
+    }
+}
@@ -1,3 +1,4 @@
+use std::sync::Arc;
 use std::time::Duration;
 
 /// Signals that can be sent to a [`Timer`]
@@ -7,15 +8,23 @@ pub enum SIGNAL<T> {
     START,
     /// Terminate whole timer thread
     TERMINATE,
+    /// Freeze the countdown, see [`Timer::pause`]
+    PAUSE,
+    /// Continue a frozen countdown, see [`Timer::resume`]
+    RESUME,
+    /// Push the timeout back, see [`Timer::extend`]
+    EXTEND(Duration),
     /// Send arbitrary message to socket
     OTHER(T),
 }
 
 /// Actions that can be received in the callback of a [`Timer`]
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum ACTION {
     /// If restarted while already running
     START { restarted: bool },
+    /// If a milestone set with [`Timer::set_milestones`] is reached, with its name
+    MILESTONE { name: Arc<str> },
     /// If a timeout is reached in [`Timer`]
     TIMEOUT,
 }
@@ -27,17 +36,25 @@ pub const SOCKET_TIMEOUT: Duration = Duration::from_secs(30);
 
 /// Duration the light takes to completely turn off after no motion for [`TIMEOUT`] time
 pub const FADE_DURATION: Duration = Duration::from_secs(60 * 3); // 3 minutes
+/// Duration the light takes to dim by a small step as a warning before [`TIMEOUT`]
+pub const WARNING_DURATION: Duration = Duration::from_secs(1);
 /// HSBK color for when light is off/dark after fading, by modifying input color
 pub const fn fade_target(color: HSBK) -> HSBK {
+    fade_to(color, light::MIN)
+}
+/// HSBK color after fading to `brightness`, by modifying input color
+pub const fn fade_to(color: HSBK, brightness: u16) -> HSBK {
     HSBK {
-        brightness: light::MIN,
+        brightness,
         ..color
     }
 }
 /// Float percentage factor that fading color should match within for it to appear as not-changed
 pub const MATCHING_THRESHOLD: f32 = 0.05; // 5%
 
-/// IP address of ceiling light
+/// Label of ceiling light, used to find it with [`discover`]
+pub const TAKLAMPA_LABEL: &str = "Taklampa";
+/// IP address of ceiling light, fallback if it is not found with [`discover`]
 pub const TAKLAMPA: &str = "192.168.1.11:56700";
 /// IP address of light strip
 pub const LIFXZ: &str = "192.168.1.12:56700";
@@ -47,13 +64,61 @@ pub const MINI: &str = "192.168.1.44:56700";
 pub use lifx_core::Message;
 use lifx_core::HSBK;
 
+pub mod clock;
+pub use clock::Clock;
+
 pub mod timer;
-pub use timer::Timer;
+pub use timer::{Milestone, Timer};
 
 pub mod light;
 pub use light::Light;
 
+pub mod discovery;
+pub use discovery::{discover, Registry};
+
+#[cfg(any(test, feature = "testing"))]
+pub mod fake;
+
+pub mod config;
+pub use config::Config;
+
+pub mod logging;
+pub mod reload;
+
+pub mod events;
+
+pub mod motion;
+pub use motion::{Motion, MotionEvent, MotionSource};
+
+pub mod presence;
+
+pub mod room;
+pub use room::Room;
+
+pub mod schedule;
+
+pub mod cue;
+
+pub mod state;
+
+pub mod api;
+
+pub mod metrics;
+
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+
+#[cfg(feature = "mqtt")]
+pub mod homeassistant;
+
+#[allow(
+    clippy::unused_unit,
+    clippy::redundant_closure,
+    clippy::filter_map_identity,
+    clippy::explicit_counter_loop
+)]
 pub mod temperature;
 
+#[allow(clippy::needless_borrow)]
 mod buffer;
 pub use buffer::FixedBuffer;
@@ -2,12 +2,17 @@
 
 use std::error::Error;
 use std::fmt;
+use std::io;
 use std::net::ToSocketAddrs;
 use std::net::UdpSocket;
-use std::time::Duration;
+use std::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use lifx_core::HSBK;
-use lifx_core::{BuildOptions, Message, RawMessage};
+use lifx_core::{
+    get_product_info, ApplicationRequest, BuildOptions, Message, ProductInfo, RawMessage, Waveform,
+};
 
 use crate::MATCHING_THRESHOLD;
 use crate::SOCKET_TIMEOUT;
@@ -21,8 +26,135 @@ pub const MIN: u16 = (u16::MAX as f64 / 200.0 + 0.5) as u16;
 /// `65535 = 0xFFFF = 100% of 0xFFFF`
 pub const MAX: u16 = u16::MAX;
 
+/// Brightness or saturation level from a percentage, clamped to `0..=100`
+pub fn from_percent(percent: f32) -> u16 {
+    (percent.clamp(0.0, 100.0) / 100.0 * MAX as f32).round() as u16
+}
+
+/// Percentage of a brightness or saturation level
+pub fn to_percent(level: u16) -> f32 {
+    level as f32 / MAX as f32 * 100.0
+}
+
+/// Hue from an angle in degrees, wrapping around at 360
+pub fn from_degrees(degrees: f32) -> u16 {
+    (degrees.rem_euclid(360.0) / 360.0 * 65536.0).round() as u32 as u16
+}
+
+/// Angle in degrees of a hue
+pub fn to_degrees(hue: u16) -> f32 {
+    hue as f32 / 65536.0 * 360.0
+}
+
+/// Number of zones in one extended zone message
+pub const EXTENDED_ZONES: usize = 82;
+/// Firmware version from which multizone lights understand the extended zone messages
+const EXTENDED_FIRMWARE: (u16, u16) = (2, 77);
+/// Message type of `SetExtendedColorZones`, which [`Message`] does not have
+pub const SET_EXTENDED_COLOR_ZONES: u16 = 510;
+/// Number of pixels in one `Get64`, `State64` or `Set64` message
+pub const TILE_PIXELS: usize = 64;
+/// Most tiles in one chain of a matrix light
+pub const MAX_TILES: usize = 16;
+/// Message types of the matrix messages, which [`Message`] does not have
+pub const GET_DEVICE_CHAIN: u16 = 701;
+pub const STATE_DEVICE_CHAIN: u16 = 702;
+pub const GET_64: u16 = 707;
+pub const STATE_64: u16 = 711;
+pub const SET_64: u16 = 715;
+/// Size of one tile in `StateDeviceChain`, with its width and height at [`TILE_WIDTH_OFFSET`]
+pub(crate) const TILE_INFO_SIZE: usize = 55;
+pub(crate) const TILE_WIDTH_OFFSET: usize = 16;
+
+/// How the zones of a multizone light like the LIFX Z strip are read and changed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Zones {
+    /// `GetColorZones` and `SetColorZones`, eight zones per reply and a range of one color per change
+    Legacy,
+    /// `GetExtendedColorZone` and `SetExtendedColorZones`, 82 zones per message, from firmware 2.77
+    Extended,
+}
+
+/// Size in pixels of one tile of a matrix light, a Tile panel or a Candle
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tile {
+    pub width: u8,
+    pub height: u8,
+}
+
+impl Tile {
+    /// Rows of pixels in one `Get64` or `Set64` message
+    fn rows(&self) -> u8 {
+        (TILE_PIXELS / self.width.max(1) as usize).min(u8::MAX as usize) as u8
+    }
+
+    /// First row and number of pixels of every message covering the tile
+    fn chunks(&self) -> impl Iterator<Item = (u8, usize)> + '_ {
+        (0..self.height)
+            .step_by(self.rows().max(1) as usize)
+            .map(|y| {
+                let rows = self.rows().min(self.height - y);
+                (y, self.width as usize * rows as usize)
+            })
+    }
+}
+
+/// Colors of the zones or pixels of a light, to put back exactly what it showed
+#[derive(Clone, Debug, PartialEq)]
+pub enum Pattern {
+    /// Zones of a multizone light, see [`Light::zones`]
+    Zones(Vec<HSBK>),
+    /// Pixels of every tile of a matrix light row by row, see [`Light::pixels`]
+    Tiles(Vec<Vec<HSBK>>),
+}
+
+impl Pattern {
+    /// The pattern with no color brighter than `brightness`, keeping hue, saturation and kelvin
+    pub fn dimmed(&self, brightness: u16) -> Self {
+        let dim = |colors: &Vec<HSBK>| -> Vec<HSBK> {
+            colors
+                .iter()
+                .map(|&color| HSBK {
+                    brightness: color.brightness.min(brightness),
+                    ..color
+                })
+                .collect()
+        };
+        match self {
+            Pattern::Zones(zones) => Pattern::Zones(dim(zones)),
+            Pattern::Tiles(tiles) => Pattern::Tiles(tiles.iter().map(dim).collect()),
+        }
+    }
+}
+
+/// Waveform effect changing the color of a light back and forth, see [`Light::set_waveform`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Wave {
+    pub waveform: Waveform,
+    /// Color the wave goes to, fields that are `None` keep the color of the light
+    pub hue: Option<u16>,
+    pub saturation: Option<u16>,
+    pub brightness: Option<u16>,
+    pub kelvin: Option<u16>,
+    /// Duration of one cycle
+    pub period: Duration,
+    /// Number of cycles, may be fractional
+    pub cycles: f32,
+    /// Part of a cycle spent at the wave color for [`Waveform::Pulse`], or to reach it for the others, in `0..=1`
+    pub skew: f32,
+    /// If the light returns to its color from before the wave once done, or stays at the wave color
+    pub transient: bool,
+}
+
+impl Wave {
+    /// Skew ratio as sent to the light, `0..=1` scaled to `-32768..=32767`
+    fn skew_ratio(&self) -> i16 {
+        (self.skew.clamp(0.0, 1.0) * u16::MAX as f32 - 32768.0).round() as i16
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
-pub struct WrongMessageError(Message);
+pub struct WrongMessageError(pub Message);
 impl fmt::Display for WrongMessageError {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -34,11 +166,126 @@ impl fmt::Display for WrongMessageError {
 }
 impl Error for WrongMessageError {}
 
+/// Errors when talking to a [`Light`]
+#[derive(Debug)]
+pub enum LightError {
+    /// No reply from the light, after `attempts` sent messages
+    Timeout { attempts: u32 },
+    /// Socket could not send or receive
+    Io(io::Error),
+    /// Message could not be encoded or the reply could not be decoded
+    Protocol(lifx_core::Error),
+    /// Light replied with another message than expected
+    WrongMessage(WrongMessageError),
+    /// Reply was meant for another client
+    UnexpectedSource { expected: u32, received: u32 },
+}
+
+impl LightError {
+    /// If the error is likely to go away by trying again, like a lost packet on Wi-Fi
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            LightError::Timeout { .. } | LightError::Io(_) | LightError::UnexpectedSource { .. }
+        )
+    }
+}
+
+impl fmt::Display for LightError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LightError::Timeout { attempts } => {
+                write!(fmt, "no reply from light after {} attempts", attempts)
+            }
+            LightError::Io(err) => write!(fmt, "socket error: {}", err),
+            LightError::Protocol(err) => write!(fmt, "lifx protocol error: {}", err),
+            LightError::WrongMessage(err) => err.fmt(fmt),
+            LightError::UnexpectedSource { expected, received } => write!(
+                fmt,
+                "reply from light has source {:#x}, expected {:#x}",
+                received, expected
+            ),
+        }
+    }
+}
+
+impl Error for LightError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LightError::Io(err) => Some(err),
+            LightError::Protocol(err) => Some(err),
+            LightError::WrongMessage(err) => Some(err),
+            LightError::Timeout { .. } | LightError::UnexpectedSource { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for LightError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+                LightError::Timeout { attempts: 1 }
+            }
+            _ => LightError::Io(err),
+        }
+    }
+}
+
+impl From<lifx_core::Error> for LightError {
+    fn from(err: lifx_core::Error) -> Self {
+        match err {
+            lifx_core::Error::Io(err) => LightError::Io(err),
+            err => LightError::Protocol(err),
+        }
+    }
+}
+
+impl From<WrongMessageError> for LightError {
+    fn from(err: WrongMessageError) -> Self {
+        LightError::WrongMessage(err)
+    }
+}
+
+/// How messages expecting a reply from the light are retried
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Number of times a message is sent before giving up
+    pub attempts: u32,
+    /// Time to wait for a reply to the first attempt
+    pub timeout: Duration,
+    /// Factor the reply timeout is multiplied with after every failed attempt
+    pub backoff: f32,
+}
+
+impl Default for RetryPolicy {
+    /// Four attempts waiting 0.25, 0.5, 1 and 2 seconds, so a light is given up after 3.75 seconds
+    fn default() -> Self {
+        Self {
+            attempts: 4,
+            timeout: Duration::from_millis(250),
+            backoff: 2.0,
+        }
+    }
+}
+
+/// Source identifiers handed out to lights, so replies can be told apart from other clients
+static NEXT_SOURCE: AtomicU32 = AtomicU32::new(1);
+
 #[derive(Debug)]
 pub struct Light<A: ToSocketAddrs> {
     pub device: A,
     pub socket: UdpSocket,
     pub options: BuildOptions,
+    /// Retry policy for [`Light::request`] and [`Light::send_acked`]
+    pub retry: RetryPolicy,
+    /// Socket read and write timeout, see [`Light::set_timeout`]
+    timeout: Duration,
+    /// Source identifier of sent messages, replies with another source are ignored
+    source: u32,
+    /// Wrap around sequence number of the last sent message, shared between clones
+    sequence: Arc<AtomicU8>,
+    /// Held while waiting for replies, so clones sharing the socket do not take each other's replies
+    exchange: Arc<Mutex<()>>,
 }
 
 impl<A: ToSocketAddrs + Clone> Clone for Light<A> {
@@ -46,7 +293,12 @@ impl<A: ToSocketAddrs + Clone> Clone for Light<A> {
         Self {
             device: self.device.clone(),
             socket: self.socket.try_clone().expect("Cannot clone socket"),
-            options: self.options.clone(),
+            options: self.options,
+            retry: self.retry,
+            timeout: self.timeout,
+            source: self.source,
+            sequence: self.sequence.clone(),
+            exchange: self.exchange.clone(),
         }
     }
 }
@@ -63,57 +315,577 @@ where
         socket.set_read_timeout(Some(SOCKET_TIMEOUT))?;
         socket.set_write_timeout(Some(SOCKET_TIMEOUT))?;
         let options = BuildOptions::default();
+        // unique per process and light, zero would make the light broadcast its replies
+        let source =
+            (std::process::id() << 12) | (NEXT_SOURCE.fetch_add(1, Ordering::Relaxed) & 0xFFF);
 
         Ok(Self {
             device,
             socket,
             options,
+            retry: RetryPolicy::default(),
+            timeout: SOCKET_TIMEOUT,
+            source: source.max(1),
+            sequence: Arc::new(AtomicU8::new(0)),
+            exchange: Arc::new(Mutex::new(())),
         })
     }
 
+    /// Set the socket read and write `timeout`, used by [`Light::receive`] (default is [`SOCKET_TIMEOUT`])
+    pub fn set_timeout(&mut self, timeout: Duration) -> Result<(), io::Error> {
+        self.socket.set_read_timeout(Some(timeout))?;
+        self.socket.set_write_timeout(Some(timeout))?;
+        self.timeout = timeout;
+        Ok(())
+    }
+
     /// Get binary [`RawMessage`] from [`Message`] using standard BuildOptions.
-    pub fn raw_message(&self, message: Message) -> Result<RawMessage, Box<dyn Error>> {
-        Ok(RawMessage::build(&self.options, message.clone())?)
+    pub fn raw_message(&self, message: Message) -> Result<RawMessage, LightError> {
+        Ok(RawMessage::build(&self.options, message)?)
     }
 
-    /// Send `message` to the light.
-    pub fn send(&self, message: Message) -> Result<(), Box<dyn Error>> {
-        let bytes = self.raw_message(message)?.pack()?;
+    /// Build a message with `encode` and the next sequence number, returning the bytes and the sequence number used
+    fn build<E>(
+        &self,
+        encode: &E,
+        ack_required: bool,
+        res_required: bool,
+    ) -> Result<(Vec<u8>, u8), LightError>
+    where
+        E: Fn(&BuildOptions) -> Result<RawMessage, lifx_core::Error>,
+    {
+        let sequence = self
+            .sequence
+            .fetch_add(1, Ordering::Relaxed)
+            .wrapping_add(1);
+        let options = BuildOptions {
+            ack_required,
+            res_required,
+            sequence,
+            source: self.source,
+            ..self.options
+        };
+        Ok((encode(&options)?.pack()?, sequence))
+    }
+
+    /// Send `message` to the light without waiting for it to arrive.
+    pub fn send(&self, message: Message) -> Result<(), LightError> {
+        let encode = |options: &BuildOptions| RawMessage::build(options, message.clone());
+        let (bytes, _) = self.build(&encode, false, false)?;
         self.socket.send(&bytes)?;
         Ok(())
     }
 
+    /// Send `message` and wait for its reply, retrying according to [`Light::retry`].
+    ///
+    /// Replies to earlier messages (with another sequence number) are skipped.
+    pub fn request(&self, message: Message) -> Result<Message, LightError> {
+        self.transact(message, false, true, |reply| match reply {
+            Message::Acknowledgement { .. } => None,
+            reply => Some(reply),
+        })
+    }
+
+    /// Send `message` and wait for the light to acknowledge it, retrying according to [`Light::retry`].
+    pub fn send_acked(&self, message: Message) -> Result<(), LightError> {
+        self.transact(message, true, false, |reply| match reply {
+            Message::Acknowledgement { .. } => Some(()),
+            _ => None,
+        })
+    }
+
+    /// Send `message` until `accept` returns a value for a reply with the same sequence number
+    fn transact<T, F>(
+        &self,
+        message: Message,
+        ack_required: bool,
+        res_required: bool,
+        mut accept: F,
+    ) -> Result<T, LightError>
+    where
+        F: FnMut(Message) -> Option<T>,
+    {
+        let encode = |options: &BuildOptions| RawMessage::build(options, message.clone());
+        self.transact_with(encode, ack_required, res_required, |raw| {
+            Ok(accept(Message::from_raw(raw)?))
+        })
+    }
+
+    /// Same as [`Light::transact`] with the message built by `encode` and raw replies, for messages [`Message`] does not have
+    fn transact_with<T, E, F>(
+        &self,
+        encode: E,
+        ack_required: bool,
+        res_required: bool,
+        mut accept: F,
+    ) -> Result<T, LightError>
+    where
+        E: Fn(&BuildOptions) -> Result<RawMessage, lifx_core::Error>,
+        F: FnMut(&RawMessage) -> Result<Option<T>, LightError>,
+    {
+        let _span =
+            tracing::debug_span!("transact", peer = ?self.socket.peer_addr().ok()).entered();
+        let _exchange = self.exchange.lock().unwrap();
+        let mut timeout = self.retry.timeout;
+        for attempt in 1..=self.retry.attempts.max(1) {
+            let (bytes, sequence) = self.build(&encode, ack_required, res_required)?;
+            tracing::trace!(attempt, sequence, len = bytes.len(), "sending");
+            self.socket.send(&bytes)?;
+            let deadline = Instant::now() + timeout;
+            while let Some(raw) = self.receive_until(deadline)? {
+                if raw.frame_addr.sequence != sequence || raw.frame.source != self.source {
+                    continue;
+                }
+                if let Some(value) = accept(&raw)? {
+                    self.socket.set_read_timeout(Some(self.timeout))?;
+                    return Ok(value);
+                }
+            }
+            tracing::debug!(attempt, ?timeout, "no reply");
+            timeout = timeout.mul_f32(self.retry.backoff);
+        }
+        tracing::debug!("giving up");
+        self.socket.set_read_timeout(Some(self.timeout))?;
+        Err(LightError::Timeout {
+            attempts: self.retry.attempts.max(1),
+        })
+    }
+
+    /// Receive a raw message, or `None` if nothing arrived before `deadline`
+    fn receive_until(&self, deadline: Instant) -> Result<Option<RawMessage>, LightError> {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(None);
+        }
+        self.socket.set_read_timeout(Some(remaining))?;
+        let mut buf = [0; 1024];
+        match self.socket.recv(&mut buf) {
+            Ok(_) => Ok(Some(RawMessage::unpack(&buf)?)),
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(err) => Err(LightError::Io(err)),
+        }
+    }
+
     /// Receive a message from the device.
-    pub fn receive(&self) -> Result<Message, Box<dyn Error>> {
+    pub fn receive(&self) -> Result<Message, LightError> {
         let mut buf = [0; 1024];
+        let _exchange = self.exchange.lock().unwrap();
         self.socket.recv(&mut buf)?;
         let raw = RawMessage::unpack(&buf)?;
+        if raw.frame.source != self.source {
+            return Err(LightError::UnexpectedSource {
+                expected: self.source,
+                received: raw.frame.source,
+            });
+        }
         Ok(Message::from_raw(&raw)?)
     }
 
     /// Change the color using function `change` which has the current color as argument, and apply it for `duration`.
     ///
     /// If change returns its original argument no update to the light is sent.
-    pub fn change_color<F>(&self, change: F, duration: Duration) -> Result<(), Box<dyn Error>>
+    pub fn change_color<F>(&self, change: F, duration: Duration) -> Result<(), LightError>
     where
         F: FnOnce(HSBK) -> HSBK,
     {
-        self.send(Message::LightGet)?;
-        match self.receive()? {
-            Message::LightState { color, .. } => {
-                let new_color = change(color);
-                if new_color != color {
-                    self.send(Message::LightSetColor {
-                        color: new_color,
-                        duration: duration.as_millis() as u32,
-                        reserved: 0,
+        let color = self.color()?;
+        let new_color = change(color);
+        if new_color != color {
+            self.set_color(new_color, duration)?;
+        }
+        Ok(())
+    }
+
+    /// Current color of the light
+    pub fn color(&self) -> Result<HSBK, LightError> {
+        match self.request(Message::LightGet)? {
+            Message::LightState { color, .. } => Ok(color),
+            msg => Err(WrongMessageError(msg).into()),
+        }
+    }
+
+    /// Current color of the light and if it is powered on
+    pub fn state(&self) -> Result<(HSBK, bool), LightError> {
+        match self.request(Message::LightGet)? {
+            Message::LightState { color, power, .. } => Ok((color, power != 0)),
+            msg => Err(WrongMessageError(msg).into()),
+        }
+    }
+
+    /// Power the light on or off over `duration`, waiting for the light to acknowledge it
+    pub fn set_power(&self, on: bool, duration: Duration) -> Result<(), LightError> {
+        self.send_acked(Message::LightSetPower {
+            level: if on { MAX } else { 0 },
+            duration: duration.as_millis() as u32,
+        })
+    }
+
+    /// Change the color to `color` over `duration`, waiting for the light to acknowledge it
+    pub fn set_color(&self, color: HSBK, duration: Duration) -> Result<(), LightError> {
+        self.send_acked(Message::LightSetColor {
+            color,
+            duration: duration.as_millis() as u32,
+            reserved: 0,
+        })
+    }
+
+    /// Play `wave` on the light, waiting for the light to acknowledge it
+    ///
+    /// Waves leaving some of hue, saturation, brightness or kelvin unchanged are sent as `SetWaveformOptional`.
+    pub fn set_waveform(&self, wave: &Wave) -> Result<(), LightError> {
+        let color = HSBK {
+            hue: wave.hue.unwrap_or(0),
+            saturation: wave.saturation.unwrap_or(0),
+            brightness: wave.brightness.unwrap_or(0),
+            kelvin: wave.kelvin.unwrap_or(0),
+        };
+        let (period, skew_ratio) = (wave.period.as_millis() as u32, wave.skew_ratio());
+        let set = [wave.hue, wave.saturation, wave.brightness, wave.kelvin].map(|v| v.is_some());
+        self.send_acked(if set.iter().all(|&set| set) {
+            Message::SetWaveform {
+                reserved: 0,
+                transient: wave.transient,
+                color,
+                period,
+                cycles: wave.cycles,
+                skew_ratio,
+                waveform: wave.waveform,
+            }
+        } else {
+            Message::SetWaveformOptional {
+                reserved: 0,
+                transient: wave.transient,
+                color,
+                period,
+                cycles: wave.cycles,
+                skew_ratio,
+                waveform: wave.waveform,
+                set_hue: set[0],
+                set_saturation: set[1],
+                set_brightness: set[2],
+                set_kelvin: set[3],
+            }
+        })
+    }
+
+    /// How the zones of the light are read and changed, `None` if it is not a multizone light
+    pub fn zone_support(&self) -> Result<Option<Zones>, LightError> {
+        if !self.product()?.is_some_and(|product| product.multizone) {
+            return Ok(None);
+        }
+        self.firmware_zones().map(Some)
+    }
+
+    /// How the zones of a multizone light are read and changed with its firmware
+    fn firmware_zones(&self) -> Result<Zones, LightError> {
+        match self.request(Message::GetHostFirmware)? {
+            Message::StateHostFirmware {
+                version_major,
+                version_minor,
+                ..
+            } if (version_major, version_minor) >= EXTENDED_FIRMWARE => Ok(Zones::Extended),
+            Message::StateHostFirmware { .. } => Ok(Zones::Legacy),
+            msg => Err(WrongMessageError(msg).into()),
+        }
+    }
+
+    /// Current colors of all zones of a multizone light, read as `support`
+    pub fn zones(&self, support: Zones) -> Result<Vec<HSBK>, LightError> {
+        let message = match support {
+            Zones::Legacy => Message::GetColorZones {
+                start_index: 0,
+                end_index: u8::MAX,
+            },
+            Zones::Extended => Message::GetExtendedColorZone,
+        };
+        // the zones arrive in several replies, until every zone has been seen
+        let mut zones: Vec<Option<HSBK>> = Vec::new();
+        self.transact(message, false, true, |reply| {
+            let (count, index, colors) = match reply {
+                Message::StateZone {
+                    count,
+                    index,
+                    color,
+                } => (count as usize, index as usize, vec![color]),
+                Message::StateMultiZone {
+                    count,
+                    index,
+                    color0,
+                    color1,
+                    color2,
+                    color3,
+                    color4,
+                    color5,
+                    color6,
+                    color7,
+                } => (
+                    count as usize,
+                    index as usize,
+                    vec![
+                        color0, color1, color2, color3, color4, color5, color6, color7,
+                    ],
+                ),
+                Message::StateExtendedColorZones {
+                    zones_count,
+                    zone_index,
+                    colors_count,
+                    colors,
+                } => (
+                    zones_count as usize,
+                    zone_index as usize,
+                    colors[..(colors_count as usize).min(EXTENDED_ZONES)].to_vec(),
+                ),
+                _ => return None,
+            };
+            zones.resize(count, None);
+            for (zone, color) in zones.iter_mut().skip(index).zip(colors) {
+                *zone = Some(color);
+            }
+            zones.iter().copied().collect()
+        })
+    }
+
+    /// Change the zones of a multizone light to `colors` over `duration` as `support`, waiting for the light to acknowledge it
+    ///
+    /// The changes are only applied by the last message, so all zones start changing at once.
+    pub fn set_zones(
+        &self,
+        support: Zones,
+        colors: &[HSBK],
+        duration: Duration,
+    ) -> Result<(), LightError> {
+        let duration = duration.as_millis() as u32;
+        match support {
+            Zones::Legacy => {
+                let runs = runs(colors);
+                for (i, &(start, end, color)) in runs.iter().enumerate() {
+                    self.send_acked(Message::SetColorZones {
+                        start_index: start as u8,
+                        end_index: end as u8,
+                        color,
+                        duration,
+                        apply: apply(i + 1 == runs.len()),
                     })?;
                 }
-                Ok(())
             }
-            msg => Err(Box::new(WrongMessageError(msg))),
+            Zones::Extended => {
+                let chunks = colors.chunks(EXTENDED_ZONES).count();
+                for (i, chunk) in colors.chunks(EXTENDED_ZONES).enumerate() {
+                    let encode = |options: &BuildOptions| {
+                        set_extended_color_zones(
+                            options,
+                            i * EXTENDED_ZONES,
+                            chunk,
+                            duration,
+                            apply(i + 1 == chunks),
+                        )
+                    };
+                    self.transact_with(encode, true, false, acknowledged)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Size of every tile in the chain of a matrix light
+    pub fn tiles(&self) -> Result<Vec<Tile>, LightError> {
+        let encode = |options: &BuildOptions| build_raw(options, GET_DEVICE_CHAIN, Vec::new());
+        self.transact_with(encode, false, true, |raw| {
+            let payload = &raw.payload;
+            if raw.protocol_header.typ != STATE_DEVICE_CHAIN
+                || payload.len() < 2 + MAX_TILES * TILE_INFO_SIZE
+            {
+                return Ok(None);
+            }
+            let count = (payload[1 + MAX_TILES * TILE_INFO_SIZE] as usize).min(MAX_TILES);
+            let tiles = (0..count)
+                .map(|tile| {
+                    let at = 1 + tile * TILE_INFO_SIZE + TILE_WIDTH_OFFSET;
+                    Tile {
+                        width: payload[at],
+                        height: payload[at + 1],
+                    }
+                })
+                .collect();
+            Ok(Some(tiles))
+        })
+    }
+
+    /// Current colors of the pixels of every one of `tiles` of a matrix light, row by row
+    pub fn pixels(&self, tiles: &[Tile]) -> Result<Vec<Vec<HSBK>>, LightError> {
+        let mut pixels = Vec::with_capacity(tiles.len());
+        for (index, tile) in tiles.iter().enumerate() {
+            let mut colors = Vec::new();
+            for (y, count) in tile.chunks() {
+                let payload = vec![index as u8, 1, 0, 0, y, tile.width];
+                let encode = |options: &BuildOptions| build_raw(options, GET_64, payload.clone());
+                colors.extend(self.transact_with(encode, false, true, |raw| {
+                    let payload = &raw.payload;
+                    if raw.protocol_header.typ != STATE_64
+                        || payload.len() < 5 + TILE_PIXELS * 8
+                        || payload[0] != index as u8
+                        || payload[3] != y
+                    {
+                        return Ok(None);
+                    }
+                    Ok(Some(decode_colors(&payload[5..], count)))
+                })?);
+            }
+            pixels.push(colors);
+        }
+        Ok(pixels)
+    }
+
+    /// Change the pixels of every one of `tiles` of a matrix light to `pixels` over `duration`, waiting for the light to acknowledge it
+    pub fn set_pixels(
+        &self,
+        tiles: &[Tile],
+        pixels: &[Vec<HSBK>],
+        duration: Duration,
+    ) -> Result<(), LightError> {
+        let duration = duration.as_millis() as u32;
+        for (index, (tile, colors)) in tiles.iter().zip(pixels).enumerate() {
+            let mut colors = colors.as_slice();
+            for (y, count) in tile.chunks() {
+                let (chunk, rest) = colors.split_at(count.min(colors.len()));
+                colors = rest;
+                let mut payload = vec![index as u8, 1, 0, 0, y, tile.width];
+                payload.extend(duration.to_le_bytes());
+                encode_colors(&mut payload, chunk, TILE_PIXELS);
+                let encode = |options: &BuildOptions| build_raw(options, SET_64, payload.clone());
+                self.transact_with(encode, true, false, acknowledged)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Product of the light, `None` if it is not known
+    pub fn product(&self) -> Result<Option<&'static ProductInfo>, LightError> {
+        match self.request(Message::GetVersion)? {
+            Message::StateVersion {
+                vendor, product, ..
+            } => Ok(get_product_info(vendor, product)),
+            msg => Err(WrongMessageError(msg).into()),
+        }
+    }
+
+    /// Current colors of the zones or pixels of a multizone or matrix light, `None` for lights with one color
+    pub fn pattern(&self) -> Result<Option<Pattern>, LightError> {
+        match self.product()? {
+            Some(product) if product.matrix => {
+                Ok(Some(Pattern::Tiles(self.pixels(&self.tiles()?)?)))
+            }
+            Some(product) if product.multizone => {
+                Ok(Some(Pattern::Zones(self.zones(self.firmware_zones()?)?)))
+            }
+            _ => Ok(None),
         }
     }
+
+    /// Change the zones or pixels of the light to `pattern` over `duration`
+    pub fn set_pattern(&self, pattern: &Pattern, duration: Duration) -> Result<(), LightError> {
+        match pattern {
+            Pattern::Zones(zones) => self.set_zones(self.firmware_zones()?, zones, duration),
+            Pattern::Tiles(pixels) => self.set_pixels(&self.tiles()?, pixels, duration),
+        }
+    }
+}
+
+/// The value for an acknowledgement, to wait for one with [`Light::transact_with`]
+fn acknowledged(raw: &RawMessage) -> Result<Option<()>, LightError> {
+    match Message::from_raw(raw)? {
+        Message::Acknowledgement { .. } => Ok(Some(())),
+        _ => Ok(None),
+    }
+}
+
+/// Message of type `typ` with `payload`, for messages [`Message`] does not have
+pub(crate) fn build_raw(
+    options: &BuildOptions,
+    typ: u16,
+    payload: Vec<u8>,
+) -> Result<RawMessage, lifx_core::Error> {
+    let mut raw = RawMessage::build(options, Message::GetExtendedColorZone)?;
+    raw.protocol_header.typ = typ;
+    raw.payload = payload;
+    raw.frame.size = raw.packed_size() as u16;
+    Ok(raw)
+}
+
+/// Append `colors` to `payload` as a list of `count` colors, filled up with black
+pub(crate) fn encode_colors(payload: &mut Vec<u8>, colors: &[HSBK], count: usize) {
+    for index in 0..count {
+        let color = colors.get(index).copied().unwrap_or(HSBK {
+            hue: 0,
+            saturation: 0,
+            brightness: 0,
+            kelvin: 0,
+        });
+        for value in [color.hue, color.saturation, color.brightness, color.kelvin] {
+            payload.extend(value.to_le_bytes());
+        }
+    }
+}
+
+/// The first `count` colors of a list of colors in `bytes`
+pub(crate) fn decode_colors(bytes: &[u8], count: usize) -> Vec<HSBK> {
+    let value = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+    (0..count.min(bytes.len() / 8))
+        .map(|index| {
+            let at = index * 8;
+            HSBK {
+                hue: value(at),
+                saturation: value(at + 2),
+                brightness: value(at + 4),
+                kelvin: value(at + 6),
+            }
+        })
+        .collect()
+}
+
+/// Apply the zone changes with the last message only
+fn apply(last: bool) -> ApplicationRequest {
+    if last {
+        ApplicationRequest::Apply
+    } else {
+        ApplicationRequest::NoApply
+    }
+}
+
+/// First and last index of every run of equal colors in `colors`, with the color
+fn runs(colors: &[HSBK]) -> Vec<(usize, usize, HSBK)> {
+    let mut runs: Vec<(usize, usize, HSBK)> = Vec::new();
+    for (index, &color) in colors.iter().enumerate() {
+        match runs.last_mut() {
+            Some((_, end, run)) if *run == color => *end = index,
+            _ => runs.push((index, index, color)),
+        }
+    }
+    runs
+}
+
+/// `SetExtendedColorZones` changing the zones from `index` on to `colors`, built by hand as [`Message`] does not have it
+fn set_extended_color_zones(
+    options: &BuildOptions,
+    index: usize,
+    colors: &[HSBK],
+    duration: u32,
+    apply: ApplicationRequest,
+) -> Result<RawMessage, lifx_core::Error> {
+    let mut payload = Vec::with_capacity(8 + EXTENDED_ZONES * 8);
+    payload.extend(duration.to_le_bytes());
+    payload.push(apply as u8);
+    payload.extend((index as u16).to_le_bytes());
+    payload.push(colors.len().min(EXTENDED_ZONES) as u8);
+    encode_colors(&mut payload, colors, EXTENDED_ZONES);
+    build_raw(options, SET_EXTENDED_COLOR_ZONES, payload)
 }
 
 /// Interpolation to find out if current color is between before color and target color, where current fading_time matches.
@@ -125,6 +897,25 @@ pub fn matches_fade(
     current_color: HSBK,
     fading_time: Duration,
     fading_target: Duration,
+) -> bool {
+    matches_fade_within(
+        before_color,
+        target_color,
+        current_color,
+        fading_time,
+        fading_target,
+        MATCHING_THRESHOLD,
+    )
+}
+
+/// Same as [`matches_fade`] with a configurable `threshold` instead of [`MATCHING_THRESHOLD`]
+pub fn matches_fade_within(
+    before_color: HSBK,
+    target_color: HSBK,
+    current_color: HSBK,
+    fading_time: Duration,
+    fading_target: Duration,
+    threshold: f32,
 ) -> bool {
     if current_color == target_color {
         return true;
@@ -166,12 +957,12 @@ pub fn matches_fade(
         ),
     ]
     .iter()
-    .all(|&e| e <= MATCHING_THRESHOLD)
+    .all(|&e| e <= threshold)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::TAKLAMPA;
+    use crate::fake::FakeBulb;
 
     use super::*;
     use lifx_core::{EchoPayload, Service, HSBK};
@@ -224,15 +1015,29 @@ mod tests {
         assert!(res3, "fading color from 0xFFFF to 0 at 0% is 0xFFFF");
     }
 
+    #[test]
+    fn test_units() {
+        assert_eq!(from_percent(100.0), MAX);
+        assert_eq!(from_percent(150.0), MAX);
+        assert_eq!(from_percent(to_percent(MIN)), MIN);
+        assert_eq!(to_percent(0x7FFF).round(), 50.0);
+        assert_eq!(from_degrees(180.0), 0x8000);
+        assert_eq!(from_degrees(360.0), 0);
+        assert_eq!(from_degrees(-90.0), from_degrees(270.0));
+        assert_eq!(to_degrees(0x4000), 90.0);
+    }
+
     #[test]
     fn test_connect() {
-        let light = Light::new(TAKLAMPA).unwrap();
-        assert_eq!(light.device, TAKLAMPA);
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let light = Light::new(bulb.addr()).unwrap();
+        assert_eq!(light.device, bulb.addr());
     }
 
     #[test]
     fn test_raw_message() {
-        let light = Light::new(TAKLAMPA).unwrap();
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let light = Light::new(bulb.addr()).unwrap();
         let message = Message::LightSetPower {
             level: 0xFF,
             duration: 0,
@@ -251,7 +1056,8 @@ mod tests {
 
     #[test]
     fn test_create_packet() {
-        let light = Light::new(TAKLAMPA).unwrap();
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let light = Light::new(bulb.addr()).unwrap();
         let message = Message::LightSetColor {
             color: HSBK {
                 hue: 0,
@@ -269,7 +1075,8 @@ mod tests {
 
     #[test]
     fn test_echo() {
-        let light = Light::new(TAKLAMPA).unwrap();
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let light = bulb.light().unwrap();
         let payload = [5; 64];
         let message = Message::EchoRequest {
             payload: EchoPayload(payload),
@@ -287,20 +1094,118 @@ mod tests {
 
     #[test]
     fn test_service() {
-        let light = Light::new(TAKLAMPA).unwrap();
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let light = bulb.light().unwrap();
         light.send(Message::GetService).unwrap();
         let response = light.receive().unwrap();
         if let Message::StateService { port, service } = response {
-            assert_eq!(port, 56700);
+            assert_eq!(port, bulb.addr().port() as u32);
             assert_eq!(service, Service::UDP);
         } else {
             panic!("No StateService response from GetService");
         }
     }
 
+    #[test]
+    fn test_request_retry() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let light = bulb.light().unwrap();
+        bulb.drop_next(2);
+        let before = Instant::now();
+        let response = light.request(Message::GetPower).unwrap();
+        assert_eq!(response, Message::StatePower { level: 0xFFFF });
+        assert!(
+            before.elapsed() >= light.retry.timeout * 3,
+            "two attempts lost"
+        );
+        assert_eq!(bulb.received(), vec![Message::GetPower]);
+    }
+
+    #[test]
+    fn test_request_timeout() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let mut light = bulb.light().unwrap();
+        light.retry = RetryPolicy {
+            attempts: 2,
+            timeout: Duration::from_millis(50),
+            backoff: 1.0,
+        };
+        bulb.drop_next(2);
+        let err = light.request(Message::GetPower).unwrap_err();
+        assert!(matches!(err, LightError::Timeout { attempts: 2 }));
+        assert!(err.is_transient());
+        assert!(bulb.received().is_empty());
+    }
+
+    #[test]
+    fn test_unexpected_source() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let light = bulb.light().unwrap();
+        // raw message is built with source zero, which is not the source of the light
+        let bytes = light
+            .raw_message(Message::GetPower)
+            .unwrap()
+            .pack()
+            .unwrap();
+        light.socket.send(&bytes).unwrap();
+        let err = light.receive().unwrap_err();
+        assert!(matches!(
+            err,
+            LightError::UnexpectedSource { received: 0, .. }
+        ));
+    }
+
+    #[test]
+    fn test_request_skips_stale_reply() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let light = bulb.light().unwrap();
+        // reply to this message arrives while waiting for the next one
+        light.send(Message::LightGet).unwrap();
+        let response = light.request(Message::GetPower).unwrap();
+        assert!(matches!(response, Message::StatePower { .. }));
+    }
+
+    /// Clones share the socket, but each gets the replies to its own requests
+    #[test]
+    fn test_clones_share_socket() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let mut light = bulb.light().unwrap();
+        // a stolen reply would not be sent again
+        light.retry.attempts = 1;
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let light = light.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..20 {
+                        light.request(Message::GetPower).unwrap();
+                        light.color().unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_send_acked() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let light = bulb.light().unwrap();
+        bulb.drop_next(1);
+        light
+            .send_acked(Message::LightSetPower {
+                level: 0,
+                duration: 0,
+            })
+            .unwrap();
+        assert_eq!(bulb.power(), 0);
+    }
+
     #[test]
     fn test_get_color() {
-        let light = Light::new(TAKLAMPA).unwrap();
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let light = bulb.light().unwrap();
         light.send(Message::LightGet).unwrap();
         let response = light.receive().unwrap();
         println!("{:#?}", response);
@@ -312,7 +1217,8 @@ mod tests {
 
     #[test]
     fn test_all_info() {
-        let light = Light::new(TAKLAMPA).unwrap();
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let light = bulb.light().unwrap();
         for message in [
             Message::GetGroup,
             Message::GetHostFirmware,
@@ -333,20 +1239,147 @@ mod tests {
     }
 
     #[test]
-    #[ignore = "changes light state"]
     fn test_set_color() {
-        let light = Light::new(TAKLAMPA).unwrap();
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let light = bulb.light().unwrap();
+        let color = HSBK {
+            hue: 0,
+            saturation: 0,
+            brightness: 0xFFFF / 2,
+            kelvin: 3500,
+        };
         light
             .send(Message::LightSetColor {
-                color: HSBK {
-                    hue: 0,
-                    saturation: 0,
-                    brightness: 0xFFFF / 2,
-                    kelvin: 3500,
-                },
+                color,
                 duration: 0,
                 reserved: 0,
             })
             .unwrap();
+        // wait for the bulb to answer, so the color has been applied
+        light.send(Message::GetPower).unwrap();
+        light.receive().unwrap();
+        assert_eq!(bulb.color(), color);
+    }
+
+    #[test]
+    fn test_waveform() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let light = bulb.light().unwrap();
+        let before = bulb.color();
+        let mut wave = Wave {
+            waveform: Waveform::Pulse,
+            hue: None,
+            saturation: None,
+            brightness: Some(MIN),
+            kelvin: None,
+            period: Duration::from_millis(500),
+            cycles: 2.0,
+            skew: 0.25,
+            transient: true,
+        };
+        light.set_waveform(&wave).unwrap();
+        assert_eq!(bulb.color(), before, "transient wave returns to the color");
+        assert!(matches!(
+            bulb.received()[..],
+            [Message::SetWaveformOptional {
+                set_brightness: true,
+                set_hue: false,
+                period: 500,
+                skew_ratio: -16384,
+                ..
+            }]
+        ));
+
+        wave.hue = Some(0x8000);
+        wave.saturation = Some(MAX);
+        wave.kelvin = Some(3500);
+        wave.transient = false;
+        light.set_waveform(&wave).unwrap();
+        assert!(matches!(
+            bulb.received()[1],
+            Message::SetWaveform {
+                transient: false,
+                ..
+            }
+        ));
+        assert_eq!(bulb.color().hue, 0x8000, "lasting wave keeps its color");
+    }
+
+    #[test]
+    fn test_zones() {
+        let bulb = FakeBulb::multizone("Lifx Z", 100).unwrap();
+        let light = bulb.light().unwrap();
+        let zones: Vec<HSBK> = (0..100)
+            .map(|zone| HSBK {
+                hue: (zone / 10) * 1000,
+                saturation: MAX,
+                brightness: MAX,
+                kelvin: 3500,
+            })
+            .collect();
+        assert_eq!(light.zone_support().unwrap(), Some(Zones::Extended));
+        light
+            .set_zones(Zones::Extended, &zones, Duration::ZERO)
+            .unwrap();
+        assert_eq!(bulb.zones().unwrap(), zones);
+        assert_eq!(light.zones(Zones::Extended).unwrap(), zones);
+
+        bulb.set_firmware(2, 76);
+        assert_eq!(light.zone_support().unwrap(), Some(Zones::Legacy));
+        let reversed: Vec<HSBK> = zones.iter().rev().copied().collect();
+        light
+            .set_zones(Zones::Legacy, &reversed, Duration::ZERO)
+            .unwrap();
+        assert_eq!(bulb.zones().unwrap(), reversed);
+        assert_eq!(light.zones(Zones::Legacy).unwrap(), reversed);
+        let sets = bulb
+            .received()
+            .iter()
+            .filter(|message| matches!(message, Message::SetColorZones { .. }))
+            .count();
+        assert_eq!(sets, 10, "one message per run of equal colors");
+
+        let lamp = FakeBulb::new("Taklampa").unwrap();
+        assert_eq!(lamp.light().unwrap().zone_support().unwrap(), None);
+    }
+
+    #[test]
+    fn test_pixels() {
+        // a Candle and a tile bigger than one message
+        let tiles = [
+            Tile {
+                width: 5,
+                height: 6,
+            },
+            Tile {
+                width: 16,
+                height: 8,
+            },
+        ];
+        let bulb = FakeBulb::matrix("Candle", &tiles).unwrap();
+        let light = bulb.light().unwrap();
+        assert!(light.product().unwrap().unwrap().matrix);
+        assert_eq!(light.tiles().unwrap(), tiles);
+
+        let pixels: Vec<Vec<HSBK>> = tiles
+            .iter()
+            .map(|tile| {
+                (0..tile.width as u16 * tile.height as u16)
+                    .map(|pixel| HSBK {
+                        hue: pixel * 500,
+                        saturation: MAX,
+                        brightness: MAX,
+                        kelvin: 3500,
+                    })
+                    .collect()
+            })
+            .collect();
+        light.set_pixels(&tiles, &pixels, Duration::ZERO).unwrap();
+        assert_eq!(bulb.pixels().unwrap(), pixels);
+        assert_eq!(light.pixels(&tiles).unwrap(), pixels);
+
+        let dimmed = Pattern::Tiles(pixels).dimmed(MIN);
+        light.set_pattern(&dimmed, Duration::ZERO).unwrap();
+        assert_eq!(light.pattern().unwrap(), Some(dimmed));
     }
 }
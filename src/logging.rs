@@ -0,0 +1,132 @@
+//! Structured logging with [`tracing`], to standard error and rotating log files
+//!
+//! Levels are filtered per module by directives like `info,motion_sensor_lifx::light=debug`
+//! from the `[logging]` section, or from `RUST_LOG` if it is set. The filter can be changed
+//! while running, where logs go is only set up at startup.
+
+use std::error::Error;
+
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Layer, Registry};
+
+use crate::config::{LogRotation, LoggingConfig};
+
+/// Installed logger, keep it alive until exiting so log files are flushed
+pub struct Logging {
+    filter: reload::Handle<EnvFilter, Registry>,
+    from_env: bool,
+    _guard: Option<WorkerGuard>,
+}
+
+impl std::fmt::Debug for Logging {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.debug_struct("Logging")
+            .field("from_env", &self.from_env)
+            .finish_non_exhaustive()
+    }
+}
+
+impl From<LogRotation> for Rotation {
+    fn from(rotation: LogRotation) -> Self {
+        match rotation {
+            LogRotation::Minutely => Rotation::MINUTELY,
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Weekly => Rotation::WEEKLY,
+            LogRotation::Never => Rotation::NEVER,
+        }
+    }
+}
+
+/// Filter from `RUST_LOG` if set, or else from `directives`
+fn filter(directives: &str) -> Result<(EnvFilter, bool), Box<dyn Error>> {
+    match std::env::var(EnvFilter::DEFAULT_ENV) {
+        Ok(env) if !env.is_empty() => Ok((EnvFilter::try_new(env)?, true)),
+        _ => Ok((EnvFilter::try_new(directives)?, false)),
+    }
+}
+
+/// Log as configured by `config` for the rest of the process
+pub fn init(config: &LoggingConfig) -> Result<Logging, Box<dyn Error>> {
+    let (env_filter, from_env) = filter(&config.filter)?;
+    let (env_filter, handle) = reload::Layer::new(env_filter);
+
+    let console = config
+        .console
+        .then(|| fmt::layer().with_writer(std::io::stderr).boxed());
+    let (file, guard) = match &config.directory {
+        Some(directory) => {
+            let mut appender = RollingFileAppender::builder()
+                .rotation(config.rotation.into())
+                .filename_prefix(&config.file_prefix);
+            if let Some(max_files) = config.max_files {
+                appender = appender.max_log_files(max_files);
+            }
+            let (writer, guard) = tracing_appender::non_blocking(appender.build(directory)?);
+            let layer = fmt::layer().with_ansi(false).with_writer(writer).boxed();
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    tracing_subscriber::registry()
+        .with(env_filter)
+        .with(console)
+        .with(file)
+        .try_init()?;
+    Ok(Logging {
+        filter: handle,
+        from_env,
+        _guard: guard,
+    })
+}
+
+impl Logging {
+    /// Change the levels to `directives`, unless they come from `RUST_LOG`
+    pub fn set_filter(&self, directives: &str) -> Result<(), Box<dyn Error>> {
+        if self.from_env {
+            tracing::info!("log filter is set by {}", EnvFilter::DEFAULT_ENV);
+            return Ok(());
+        }
+        self.filter.reload(EnvFilter::try_new(directives)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_file() {
+        std::env::remove_var(EnvFilter::DEFAULT_ENV);
+        let directory =
+            std::env::temp_dir().join(format!("motion_sensor_lifx_logs_{}", std::process::id()));
+        let config = LoggingConfig {
+            filter: "warn".to_string(),
+            console: false,
+            directory: Some(directory.clone()),
+            file_prefix: "test.log".to_string(),
+            rotation: LogRotation::Never,
+            max_files: None,
+        };
+        let logging = init(&config).unwrap();
+        tracing::info!("hidden");
+        tracing::warn!(room = "hallway", "shown");
+        logging.set_filter("info").unwrap();
+        tracing::info!("also shown");
+        assert!(logging.set_filter("info,light=loud").is_err());
+        drop(logging);
+
+        let log = fs::read_to_string(directory.join("test.log")).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+        assert!(!log.contains("hidden"), "{}", log);
+        assert!(log.contains("WARN"), "{}", log);
+        assert!(log.contains("shown room=\"hallway\""), "{}", log);
+        assert!(log.contains("also shown"), "{}", log);
+    }
+}
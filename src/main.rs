@@ -1,142 +1,899 @@
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::error::Error;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
 use std::time::{Duration, Instant};
+use std::{process, thread};
 
-use gpio_cdev::{Chip, EventRequestFlags, EventType, LineRequestFlags};
-use lifx_core::HSBK;
+use clap::{Parser, Subcommand, ValueEnum};
+use lifx_core::{Message, HSBK};
 
+use motion_sensor_lifx::api::{self, Api};
+use motion_sensor_lifx::clock::{self, Clock};
+use motion_sensor_lifx::config::{
+    ConfigChange, ConfigError, LoggingConfig, RoomConfig, DEFAULT_CONFIG_PATH,
+};
+use motion_sensor_lifx::discovery::{BROADCAST, DISCOVERY_TIMEOUT};
+use motion_sensor_lifx::events::{DaemonEvent, Events};
+use motion_sensor_lifx::light::{self, matches_fade_within};
+use motion_sensor_lifx::logging::{self, Logging};
+use motion_sensor_lifx::metrics::Metrics;
+use motion_sensor_lifx::motion::{self, MotionEvent};
+use motion_sensor_lifx::reload::{ConfigWatcher, WATCH_INTERVAL};
+use motion_sensor_lifx::room::Lights;
+use motion_sensor_lifx::schedule::{self, SCHEDULE_INTERVAL};
+use motion_sensor_lifx::temperature::{Thermal, SCAN_INTERVAL};
 use motion_sensor_lifx::{
-    fade_target, light::matches_fade, Light, Timer, ACTION, FADE_DURATION, TAKLAMPA, TIMEOUT,
+    discover, fade_to, Config, Light, Registry, Room, Timer, ACTION, FADE_DURATION, SOCKET_TIMEOUT,
 };
+use tracing::{error, info, info_span, warn};
+
+/// Fade lifx lights when motion sensors detect no motion
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Configuration file with sensors, lights, rooms and timings
+    #[arg(short, long, global = true, default_value = DEFAULT_CONFIG_PATH)]
+    config: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run the daemon, the default if no command is given
+    Run,
+    /// List lifx devices on the LAN
+    Discover {
+        /// Address to broadcast the discovery to
+        #[arg(short, long, default_value = BROADCAST)]
+        broadcast: String,
+        /// Seconds to wait for devices to answer, the configured discovery timeout by default
+        #[arg(short, long, value_parser = parse_secs)]
+        timeout: Option<Duration>,
+    },
+    /// Show the color and power of a light
+    Get {
+        /// Light name from the config file, label, MAC or ip address
+        light: String,
+    },
+    /// Change the color or power of a light, unchanged values are kept
+    Set {
+        /// Light name from the config file, label, MAC or ip address
+        light: String,
+        /// Hue in degrees
+        #[arg(long)]
+        hue: Option<f32>,
+        /// Saturation in percent
+        #[arg(long)]
+        saturation: Option<f32>,
+        /// Brightness in percent
+        #[arg(long)]
+        brightness: Option<f32>,
+        /// Color temperature in kelvin
+        #[arg(long)]
+        kelvin: Option<u16>,
+        /// Turn the light on or off
+        #[arg(long)]
+        power: Option<Power>,
+        /// Seconds the change takes
+        #[arg(short, long, value_parser = parse_secs, default_value = "0")]
+        duration: Duration,
+    },
+    /// Fade a light like the daemon does when there is no motion
+    Fade {
+        /// Light name from the config file, label, MAC or ip address
+        light: String,
+        /// Seconds the fade takes, 180 by default
+        #[arg(short, long, value_parser = parse_secs)]
+        duration: Option<Duration>,
+        /// Brightness in percent to fade to, 0.5 by default
+        #[arg(short, long)]
+        brightness: Option<f32>,
+        /// Seconds to wait before fading
+        #[arg(long, value_parser = parse_secs, default_value = "0")]
+        delay: Duration,
+        /// Restore the color after this many seconds, if the light was not changed during the fade
+        #[arg(short, long, value_parser = parse_secs)]
+        restore: Option<Duration>,
+    },
+    /// Show the state of every room of the running daemon and when its lights fade, asking its API
+    Status {
+        /// Address of the API, the `bind` address of the `[api]` section by default
+        #[arg(short, long)]
+        address: Option<SocketAddr>,
+    },
+    /// Validate the configuration file and show what it contains
+    CheckConfig {
+        /// Also look for lights configured by label on the LAN
+        #[arg(long)]
+        discover: bool,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Power {
+    On,
+    Off,
+}
+
+/// Parse a duration given in (fractional) seconds
+fn parse_secs(secs: &str) -> Result<Duration, String> {
+    let secs: f64 = secs.parse().map_err(|err| format!("{}", err))?;
+    Duration::try_from_secs_f64(secs).map_err(|err| format!("{}", err))
+}
+
+/// Connect to the lights of every room in `config`, reusing lights in `keep` and finding lights configured by label on the LAN
+///
+/// Lights that are not found or can not be connected to are logged and left out, so the rooms
+/// run with the lights they have.
+fn connect_lights(config: &Config, keep: &Lights) -> Lights {
+    let mut names: Vec<&String> = config.rooms.iter().flat_map(|room| &room.lights).collect();
+    names.sort();
+    names.dedup();
+    let kept = |name: &str| keep.iter().find(|(kept, _)| kept == name);
+    let registry = if names.iter().any(|name| {
+        kept(name).is_none() && matches!(config.light(name), Some(light) if light.address.is_none())
+    }) {
+        match discover(BROADCAST, config.timings.discovery_timeout) {
+            Ok(registry) => Some(registry),
+            Err(err) => {
+                error!("discovery failed: {}", err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    names
+        .into_iter()
+        .filter_map(|name| {
+            if let Some(kept) = kept(name) {
+                return Some(kept.clone());
+            }
+            match connect_light(config, name, registry.as_ref()) {
+                Ok(light) => Some((name.clone(), light)),
+                Err(err) => {
+                    error!(light = %name, "leaving out light: {}", err);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Connect to light `name` of `config`, finding it in `registry` if it is configured by label
+fn connect_light(
+    config: &Config,
+    name: &str,
+    registry: Option<&Registry>,
+) -> Result<Light<SocketAddr>, Box<dyn Error>> {
+    let addr = config
+        .light(name)
+        .and_then(|light| light.resolve(registry))
+        .ok_or_else(|| format!("Light {:?} was not found on the LAN", name))?;
+    info!(light = %name, %addr, "connecting to light");
+    let mut light = Light::new(addr)?;
+    light.set_timeout(config.timings.socket_timeout)?;
+    Ok(light)
+}
+
+/// Lights of `room` picked from all connected `lights`
+fn room_lights(room: &RoomConfig, lights: &Lights) -> Lights {
+    lights
+        .iter()
+        .filter(|(name, _)| room.lights.contains(name))
+        .cloned()
+        .collect()
+}
+
+/// Apply the schedules active now in local time to `rooms`
+fn apply_schedules(rooms: &[Arc<Room>], schedules: &[schedule::ScheduleConfig]) {
+    let now = chrono::Local::now().naive_local();
+    for room in rooms {
+        room.apply_schedules(schedules, now);
+    }
+}
 
-fn main() -> Result<(), gpio_cdev::Error> {
-    let mut chip = Chip::new("/dev/gpiochip0")?;
-    let pin = 17;
-    // Error will appear here if line is occupied
-    let line = chip
-        .get_line(pin)
-        .expect(&format!("GPIO Line {} is occupied", pin));
-
-    // Get iterator over input events from line
-    let events = line
-        .events(
-            LineRequestFlags::INPUT,
-            EventRequestFlags::BOTH_EDGES,
-            "rust-program",
+/// Apply a reloaded config file to the running `rooms`, keeping their motion sources and fade state
+fn apply_changes(
+    rooms: &Mutex<Vec<Arc<Room>>>,
+    new: &Config,
+    changes: &[ConfigChange],
+    clock: &Arc<dyn Clock>,
+    events: &Events,
+    logging: &Logging,
+) {
+    for change in changes {
+        if change.needs_restart() {
+            warn!("restart needed to apply config change: {}", change);
+        } else {
+            info!("applying config change: {}", change);
+        }
+        if *change == ConfigChange::LogFilter {
+            if let Err(err) = logging.set_filter(&new.logging.filter) {
+                error!("keeping log filter: {}", err);
+            }
+        }
+    }
+    let mut rooms = rooms.lock().unwrap();
+    rooms.retain(|room| new.room(room.name()).is_some());
+
+    let reconnect: Vec<&str> = changes
+        .iter()
+        .filter_map(|change| match change {
+            ConfigChange::Light { light } => Some(light.as_str()),
+            _ => None,
+        })
+        .collect();
+    let lights_changed = changes.iter().any(|change| {
+        matches!(
+            change,
+            ConfigChange::Timings
+                | ConfigChange::RoomLights { .. }
+                | ConfigChange::RoomAdded { .. }
         )
-        .expect(&format!("Unable to receive events on GPIO line {}", pin));
+    });
+    let running: Lights = rooms.iter().flat_map(|room| room.lights()).collect();
+    let lights = if lights_changed || !reconnect.is_empty() {
+        let mut keep = running.clone();
+        // timings may change the socket timeout, so every light is reconnected
+        keep.retain(|(name, _)| {
+            !reconnect.contains(&name.as_str()) && !changes.contains(&ConfigChange::Timings)
+        });
+        let mut lights = connect_lights(new, &keep);
+        // a light that could not be connected again keeps running as it was
+        for (name, light) in running {
+            let configured = new.rooms.iter().any(|room| room.lights.contains(&name));
+            if configured && !lights.iter().any(|(connected, _)| *connected == name) {
+                warn!(light = %name, "keeping running light");
+                lights.push((name, light));
+            }
+        }
+        lights
+    } else {
+        running
+    };
 
-    let last_activity = Arc::new(Mutex::new(Instant::now()));
-    let last_activity_clone = last_activity.clone();
+    for config in &new.rooms {
+        let lights = room_lights(config, &lights);
+        match rooms.iter().find(|room| room.name() == config.name) {
+            Some(room) => room.update(config.clone(), new.timings.clone(), lights),
+            None => rooms.push(Arc::new(Room::with_events(
+                config.clone(),
+                new.timings.clone(),
+                lights,
+                clock.clone(),
+                events.clone(),
+            ))),
+        }
+    }
+    apply_schedules(&rooms, &new.schedules);
+}
 
-    let taklampa_timer = Light::new(TAKLAMPA)?;
-    let taklampa_periodic = taklampa_timer.clone();
+/// Run the daemon with the configuration file at `path`, fading lights when there is no motion
+fn run(path: PathBuf) -> Result<(), Box<dyn Error>> {
+    let config = Config::load(&path)?;
+    let logging = Arc::new(logging::init(&config.logging)?);
+    info!("loaded config from {}", path.display());
 
-    let skrivbord_timer = Light::new(TAKLAMPA)?;
-    let skrivbord_periodic = taklampa_timer.clone();
+    let clock = clock::real();
 
-    let fonster_timer = Light::new(TAKLAMPA)?;
-    let fonster_periodic = taklampa_timer.clone();
+    // Motion from every sensor is sent to the rooms it belongs to, by sensor name, a failed
+    // sensor is reopened while the others keep working
+    let (sender, receiver) = mpsc::channel::<(String, MotionEvent)>();
+    for sensor in &config.sensors {
+        let source = motion::open(sensor)?;
+        let sensor = sensor.clone();
+        let sender = sender.clone();
+        let clock_sensor = clock.clone();
+        thread::Builder::new()
+            .name(format!("sensor_{}", sensor.name))
+            .spawn(move || {
+                let _span = info_span!("sensor", name = %sensor.name).entered();
+                motion::watch(
+                    source,
+                    || motion::open(&sensor),
+                    &*clock_sensor,
+                    |event| sender.send((sensor.name.clone(), event)).is_ok(),
+                );
+            })?;
+    }
+    drop(sender);
+    let events = Events::new();
+    let lights = connect_lights(&config, &Vec::new());
+    let rooms: Vec<Arc<Room>> = config
+        .rooms
+        .iter()
+        .map(|room| {
+            Arc::new(Room::with_events(
+                room.clone(),
+                config.timings.clone(),
+                room_lights(room, &lights),
+                clock.clone(),
+                events.clone(),
+            ))
+        })
+        .collect();
+    apply_schedules(&rooms, &config.schedules);
+    let rooms = Arc::new(Mutex::new(rooms));
+    let timings = Arc::new(Mutex::new(config.timings.clone()));
+    let schedules = Arc::new(Mutex::new(config.schedules.clone()));
 
+    let rooms_scheduler = rooms.clone();
+    let schedules_scheduler = schedules.clone();
+    let clock_scheduler = clock.clone();
+    thread::Builder::new()
+        .name("scheduler".to_string())
+        .spawn(move || -> ! {
+            loop {
+                clock_scheduler.sleep(SCHEDULE_INTERVAL);
+                let rooms = rooms_scheduler.lock().unwrap().clone();
+                apply_schedules(&rooms, &schedules_scheduler.lock().unwrap());
+            }
+        })?;
+
+    let rooms_periodic = rooms.clone();
+    let timings_periodic = timings.clone();
+    let clock_periodic = clock.clone();
     thread::Builder::new()
         .name("periodic_poll".to_string())
         .spawn(move || -> ! {
-            let mut last_state: Option<HSBK> = None;
             loop {
-                // Wait one minute
-                thread::sleep(Duration::from_secs(60));
-                // Check if
-                taklampa_periodic
-                    .change_color(
-                        |current_color: HSBK| -> HSBK {
-                            if let Some(color) = last_state {
-                                let diff =
-                                    Instant::now().duration_since(*last_activity.lock().unwrap());
-                                // if color has not changed an no motion for
-                                if color == current_color && diff > Duration::from_secs(5) {
-                                    // fade to off
-                                    return fade_target(color);
-                                }
-                            }
-                            last_state = Some(current_color);
-                            current_color
-                        },
-                        FADE_DURATION,
-                    )
-                    .unwrap_or_else(|e| todo!("handle set color error gracefully: {:?}", e));
+                let poll_interval = timings_periodic.lock().unwrap().poll_interval;
+                clock_periodic.sleep(poll_interval);
+                let rooms = rooms_periodic.lock().unwrap().clone();
+                for room in rooms {
+                    room.poll();
+                }
             }
+        })?;
+
+    if let Some(state) = &config.state {
+        motion_sensor_lifx::state::spawn(
+            state.file.clone(),
+            state.max_age,
+            rooms.clone(),
+            &events,
+        )?;
+        info!("keeping fade state in {}", state.file.display());
+    }
+
+    let _api = match &config.api {
+        Some(api_config) => {
+            let metrics = match &config.metrics {
+                Some(metrics) => Some(Metrics::spawn(
+                    metrics
+                        .temperature_file
+                        .clone()
+                        .map(|file| Thermal::new(file, SCAN_INTERVAL)),
+                    &events,
+                )?),
+                None => None,
+            };
+            let api = Api::spawn(api_config.bind, rooms.clone(), metrics)?;
+            info!("API listening on http://{}", api_config.bind);
+            Some(api)
+        }
+        None => None,
+    };
+
+    #[cfg(feature = "mqtt")]
+    let _bridge = match &config.mqtt {
+        Some(mqtt) => {
+            let bridge = motion_sensor_lifx::mqtt::Bridge::spawn(mqtt, rooms.clone(), &events)?;
+            info!("MQTT bridge to {}:{}", mqtt.host, mqtt.port);
+            Some(bridge)
+        }
+        None => None,
+    };
+    #[cfg(not(feature = "mqtt"))]
+    if config.mqtt.is_some() {
+        warn!("ignoring [mqtt] in config file, built without the mqtt feature");
+    }
+
+    let rooms_reload = rooms.clone();
+    let events_reload = events.clone();
+    let _watcher = ConfigWatcher::spawn(path, config, WATCH_INTERVAL, move |new, changes| {
+        *timings.lock().unwrap() = new.timings.clone();
+        *schedules.lock().unwrap() = new.schedules.clone();
+        apply_changes(
+            &rooms_reload,
+            new,
+            changes,
+            &clock,
+            &events_reload,
+            &logging,
+        );
+    })?;
+
+    for room in rooms.lock().unwrap().iter() {
+        info!(room = %room.name(), "waiting for motion");
+    }
+
+    // Wait for motion events, this loop will go forever for GPIO lines
+    for (sensor, event) in receiver {
+        info!(sensor = %sensor, "motion {}", event.motion);
+        events.publish(DaemonEvent::Motion {
+            sensor: sensor.clone(),
+            motion: event.motion,
+        });
+        let rooms = rooms.lock().unwrap().clone();
+        for room in rooms.iter().filter(|room| room.has_sensor(&sensor)) {
+            room.motion(&event);
+        }
+    }
+
+    // replayed sensors end, let the lights time out and fade before exiting
+    let wait = rooms
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|room| {
+            let config = room.config();
+            config.timeout + config.fade_duration
         })
-        .unwrap();
+        .max()
+        .unwrap_or_default();
+    info!("no more events from sensors, exiting in {:?}", wait);
+    thread::sleep(wait);
 
-    // Is Some of (before fade color, instant fading started) if currently fading
-    let mut before_fade: Option<(HSBK, Instant)> = None;
-
-    let timer = Timer::new(TIMEOUT, move |action| match action {
-        ACTION::START { restarted: false } => {
-            println!("Started!");
-            // if fading
-            if let Some((before_color, fading_started)) = before_fade {
-                taklampa_timer
-                    .change_color(
-                        |current_color| {
-                            if matches_fade(
-                                before_color,
-                                fade_target(before_color),
-                                current_color,
-                                fading_started.elapsed(),
-                                FADE_DURATION,
-                            ) {
-                                println!("Light on from faded state");
-                                before_color
-                            } else {
-                                println!("Light changed during fade or off");
-                                current_color
-                            }
-                        },
-                        Duration::from_millis(100),
-                    )
-                    .unwrap_or_else(|e| todo!("handle set color error gracefully: {:?}", e));
-            }
-            before_fade = None;
+    Ok(())
+}
+
+/// Load the configuration file at `path` if it exists, commands other than `run` work without it
+fn load_optional(path: &PathBuf) -> Result<Option<Config>, ConfigError> {
+    match Config::load(path) {
+        Ok(config) => Ok(Some(config)),
+        Err(ConfigError::Io(_, err)) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Find `light` by name in `config`, ip address, or label or MAC on the LAN, in that order
+fn find_light(light: &str, config: Option<&Config>) -> Result<Light<SocketAddr>, Box<dyn Error>> {
+    let timings = config
+        .map(|config| config.timings.clone())
+        .unwrap_or_default();
+    let addr = if let Some(configured) = config.and_then(|config| config.light(light)) {
+        let registry = match configured.address {
+            Some(_) => None,
+            None => Some(discover(BROADCAST, timings.discovery_timeout)?),
+        };
+        configured
+            .resolve(registry.as_ref())
+            .ok_or_else(|| format!("Light {:?} was not found on the LAN", light))?
+    } else if let Ok(addr) = light.parse::<SocketAddr>() {
+        addr
+    } else if let Ok(ip) = light.parse::<IpAddr>() {
+        SocketAddr::new(ip, 56700)
+    } else {
+        let registry = discover(BROADCAST, timings.discovery_timeout)?;
+        let device = registry
+            .by_label(light)
+            .or_else(|| registry.by_mac(light))
+            .ok_or_else(|| format!("Light {:?} was not found on the LAN", light))?;
+        let mut found = device.light()?;
+        found.set_timeout(timings.socket_timeout)?;
+        return Ok(found);
+    };
+    let mut found = Light::new(addr)?;
+    found.set_timeout(timings.socket_timeout)?;
+    Ok(found)
+}
+
+fn print_discover(
+    config: Option<&Config>,
+    broadcast: &str,
+    timeout: Option<Duration>,
+) -> Result<(), Box<dyn Error>> {
+    let timeout = timeout
+        .or(config.map(|config| config.timings.discovery_timeout))
+        .unwrap_or(DISCOVERY_TIMEOUT);
+    let registry = discover(broadcast, timeout)?;
+    for device in &registry {
+        println!("{}", device);
+    }
+    println!("Found {} devices", registry.len());
+    Ok(())
+}
+
+fn print_light(light: &Light<SocketAddr>) -> Result<(), Box<dyn Error>> {
+    match light.request(Message::LightGet)? {
+        Message::LightState {
+            color,
+            power,
+            label,
+            ..
+        } => {
+            println!("{:?} at {}", label.to_string(), light.device);
+            println!("  power       {}", if power == 0 { "off" } else { "on" });
+            println!("  hue         {:.1}°", light::to_degrees(color.hue));
+            println!("  saturation  {:.1}%", light::to_percent(color.saturation));
+            println!("  brightness  {:.1}%", light::to_percent(color.brightness));
+            println!("  kelvin      {}", color.kelvin);
+            Ok(())
         }
-        ACTION::START { restarted: true } => println!("Restarted!"),
-        ACTION::TIMEOUT => {
-            println!("Timeout!");
-            taklampa_timer
-                .change_color(
-                    |color| {
-                        // save color before fade, to be able to restore
-                        before_fade = Some((color, Instant::now()));
-                        fade_target(color)
-                    },
-                    FADE_DURATION,
-                )
-                .unwrap_or_else(|e| todo!("handle set color error gracefully: {:?}", e));
+        msg => Err(light::WrongMessageError(msg).into()),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn set_light(
+    light: &Light<SocketAddr>,
+    hue: Option<f32>,
+    saturation: Option<f32>,
+    brightness: Option<f32>,
+    kelvin: Option<u16>,
+    power: Option<Power>,
+    duration: Duration,
+) -> Result<(), Box<dyn Error>> {
+    light.change_color(
+        |color| HSBK {
+            hue: hue.map_or(color.hue, light::from_degrees),
+            saturation: saturation.map_or(color.saturation, light::from_percent),
+            brightness: brightness.map_or(color.brightness, light::from_percent),
+            kelvin: kelvin.unwrap_or(color.kelvin),
+        },
+        duration,
+    )?;
+    if let Some(power) = power {
+        light.send_acked(Message::LightSetPower {
+            level: if power == Power::On { light::MAX } else { 0 },
+            duration: duration.as_millis() as u32,
+        })?;
+    }
+    print_light(light)
+}
+
+/// Fade `light` to `brightness` after `delay` with a [`Timer`], then restore it after `restore` if it still matches the fade
+fn fade_light(
+    light: Light<SocketAddr>,
+    duration: Duration,
+    brightness: u16,
+    delay: Duration,
+    restore: Option<Duration>,
+    threshold: f32,
+) -> Result<(), Box<dyn Error>> {
+    let (sender, receiver) = mpsc::channel();
+    let fading = light.clone();
+    let timer = Timer::new(delay, move |action| {
+        if action == ACTION::TIMEOUT {
+            let mut before = None;
+            let result = fading.change_color(
+                |color| {
+                    before = Some(color);
+                    fade_to(color, brightness)
+                },
+                duration,
+            );
+            sender.send(result.map(|()| before)).unwrap();
         }
     });
+    let before = receiver.recv()??;
+    let started = Instant::now();
+    timer.destroy().map_err(|_| "Timer thread panicked")?;
+    let Some(before) = before else {
+        return Ok(());
+    };
+    println!(
+        "Fading to {:.1}% over {:?}",
+        light::to_percent(brightness),
+        duration
+    );
 
-    println!("Program started and waiting for events on GPIO pin {}", pin);
-
-    // Wait for GPIO events, this loop will go forever
-    for event in events {
-        let evt = event?;
-        match evt.event_type() {
-            // If PIR detects motion
-            EventType::RisingEdge => {
-                println!("Motion on");
-                // Stop timer
-                timer.start().unwrap();
-            }
-            // If PIR detects no motion for ~10 seconds
-            EventType::FallingEdge => {
-                println!("Motion off");
-                // Restart timer
-                timer.start().unwrap();
+    if let Some(restore) = restore {
+        thread::sleep(restore);
+        light.change_color(
+            |current| {
+                if matches_fade_within(
+                    before,
+                    fade_to(before, brightness),
+                    current,
+                    started.elapsed(),
+                    duration,
+                    threshold,
+                ) {
+                    println!("Restoring color from before the fade");
+                    before
+                } else {
+                    println!("Light changed during fade, not restoring");
+                    current
+                }
+            },
+            Duration::from_millis(100),
+        )?;
+    }
+    Ok(())
+}
+
+/// Show the state of every room of the daemon with its API at `address`, or at the address in `config`
+fn print_status(
+    config: Option<&Config>,
+    address: Option<SocketAddr>,
+) -> Result<(), Box<dyn Error>> {
+    let mut addr = address
+        .or(config
+            .and_then(|config| config.api.as_ref())
+            .map(|api| api.bind))
+        .ok_or("No [api] section in the config file, give the address of the API with --address")?;
+    // the API listens on every interface, ask it on this machine
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    let timeout = config.map_or(SOCKET_TIMEOUT, |config| config.timings.socket_timeout);
+    for room in api::fetch_rooms(addr, timeout)? {
+        let timer = match room.remaining {
+            _ if room.paused => ", paused".to_string(),
+            _ if !room.enabled => ", fading disabled by a schedule".to_string(),
+            Some(remaining) if room.frozen => {
+                format!(", frozen {:.0}s before the lights fade", remaining)
             }
-        }
-        *last_activity_clone.lock().unwrap() = Instant::now();
+            Some(remaining) => format!(", lights fade in {:.0}s", remaining),
+            None => String::new(),
+        };
+        println!("Room {:?} {}{}", room.name, room.state, timer);
     }
-    eprintln!("Program reached end, no events in gpio_cdev Iterator");
+    Ok(())
+}
 
+fn check_config(path: &PathBuf, resolve: bool) -> Result<(), Box<dyn Error>> {
+    let config = Config::load(path)?;
+    println!("{} is valid", path.display());
+    for sensor in &config.sensors {
+        match (&sensor.replay, sensor.pin) {
+            (Some(replay), _) => println!(
+                "Sensor {:?} replayed from {}",
+                sensor.name,
+                replay.display()
+            ),
+            (None, Some(pin)) => println!(
+                "Sensor {:?} on {} line {}",
+                sensor.name,
+                sensor.chip.display(),
+                pin
+            ),
+            (None, None) => unreachable!("validated sensor has pin or replay"),
+        }
+    }
+    let registry = if resolve && config.needs_discovery() {
+        Some(discover(BROADCAST, config.timings.discovery_timeout)?)
+    } else {
+        None
+    };
+    for configured in &config.lights {
+        let at = match (configured.resolve(registry.as_ref()), &configured.label) {
+            (Some(addr), _) => addr.to_string(),
+            (None, Some(label)) if resolve => format!("label {:?}, not found on the LAN", label),
+            (None, Some(label)) => format!("label {:?}", label),
+            (None, None) => unreachable!("validated light has address or label"),
+        };
+        println!("Light {:?} at {}", configured.name, at);
+    }
+    for room in &config.rooms {
+        println!(
+            "Room {:?} with sensors {:?} and lights {:?}, fading to {}% over {:?} after {:?}{}",
+            room.name,
+            room.sensors,
+            room.lights,
+            room.fade_brightness,
+            room.fade_duration,
+            room.timeout,
+            if room.power_off {
+                ", then powering off"
+            } else {
+                ""
+            }
+        );
+    }
+    for schedule in &config.schedules {
+        let now = chrono::Local::now().naive_local();
+        println!(
+            "Schedule {:?} for {} on {} from {} to {}{}",
+            schedule.name,
+            if schedule.rooms.is_empty() {
+                "all rooms".to_string()
+            } else {
+                format!("rooms {:?}", schedule.rooms)
+            },
+            if schedule.days.is_empty() {
+                "every day".to_string()
+            } else {
+                format!("{:?}", schedule.days)
+            },
+            schedule.start.format("%H:%M"),
+            schedule.end.format("%H:%M"),
+            if schedule.is_active(now) {
+                ", active now"
+            } else {
+                ""
+            }
+        );
+    }
     Ok(())
 }
+
+fn main() {
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Run);
+    // the daemon logs as configured, other commands only print warnings
+    let _logging = match command {
+        Command::Run => None,
+        _ => logging::init(&LoggingConfig {
+            filter: "warn".to_string(),
+            ..LoggingConfig::default()
+        })
+        .ok(),
+    };
+    let result = match command {
+        Command::Run => run(cli.config),
+        Command::CheckConfig { discover } => check_config(&cli.config, discover),
+        command => load_optional(&cli.config)
+            .map_err(|err| err.into())
+            .and_then(|config| match command {
+                Command::Discover { broadcast, timeout } => {
+                    print_discover(config.as_ref(), &broadcast, timeout)
+                }
+                Command::Get { light } => print_light(&find_light(&light, config.as_ref())?),
+                Command::Set {
+                    light,
+                    hue,
+                    saturation,
+                    brightness,
+                    kelvin,
+                    power,
+                    duration,
+                } => set_light(
+                    &find_light(&light, config.as_ref())?,
+                    hue,
+                    saturation,
+                    brightness,
+                    kelvin,
+                    power,
+                    duration,
+                ),
+                Command::Fade {
+                    light,
+                    duration,
+                    brightness,
+                    delay,
+                    restore,
+                } => fade_light(
+                    find_light(&light, config.as_ref())?,
+                    duration.unwrap_or(FADE_DURATION),
+                    brightness.map_or(light::MIN, light::from_percent),
+                    delay,
+                    restore,
+                    config
+                        .map(|config| config.timings.matching_threshold)
+                        .unwrap_or(motion_sensor_lifx::MATCHING_THRESHOLD),
+                ),
+                Command::Status { address } => print_status(config.as_ref(), address),
+                Command::Run | Command::CheckConfig { .. } => unreachable!(),
+            }),
+    };
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+    #[cfg(feature = "testing")]
+    use motion_sensor_lifx::fake::FakeBulb;
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+        let cli = Cli::parse_from(["motion_sensor_lifx", "set", "Taklampa", "--hue", "90"]);
+        assert!(matches!(cli.command, Some(Command::Set { hue: Some(hue), .. }) if hue == 90.0));
+        assert_eq!(cli.config, PathBuf::from(DEFAULT_CONFIG_PATH));
+        let cli = Cli::parse_from(["motion_sensor_lifx", "--config", "lights.toml"]);
+        assert!(cli.command.is_none());
+        assert_eq!(cli.config, PathBuf::from("lights.toml"));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_find_light() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let light = find_light(&bulb.addr().to_string(), None).unwrap();
+        assert_eq!(light.device, bulb.addr());
+        assert!(find_light("not a light", None).is_err());
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_set_light() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let light = find_light(&bulb.addr().to_string(), None).unwrap();
+        set_light(
+            &light,
+            Some(180.0),
+            None,
+            Some(100.0),
+            None,
+            Some(Power::Off),
+            Duration::ZERO,
+        )
+        .unwrap();
+        assert_eq!(bulb.color().hue, 0x8000);
+        assert_eq!(bulb.color().brightness, light::MAX);
+        assert_eq!(bulb.power(), 0);
+    }
+
+    /// Lights that are not found are left out instead of stopping the daemon
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_connect_lights() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let config: Config = format!(
+            r#"
+            [timings]
+            discovery_timeout = 0.05
+
+            [[sensors]]
+            name = "pir"
+            pin = 4
+
+            [[lights]]
+            name = "lamp"
+            address = "{}"
+
+            [[lights]]
+            name = "strip"
+            label = "Nowhere to be found"
+
+            [[rooms]]
+            name = "bedroom"
+            sensors = ["pir"]
+            lights = ["lamp", "strip"]
+            "#,
+            bulb.addr()
+        )
+        .parse()
+        .unwrap();
+        let lights = connect_lights(&config, &Vec::new());
+        let names: Vec<&str> = lights.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["lamp"]);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_print_status() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let config = RoomConfig::test("bedroom", Duration::from_secs(10));
+        let lights = vec![("lamp".to_string(), bulb.light().unwrap())];
+        let room = Room::new(config, Default::default(), lights);
+        let rooms = Arc::new(Mutex::new(vec![Arc::new(room)]));
+        let api = Api::spawn("127.0.0.1:0".parse().unwrap(), rooms, None).unwrap();
+        print_status(None, api.addr()).unwrap();
+        assert!(print_status(None, None).is_err(), "no address");
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_fade_light() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let before = bulb.color();
+        let light = find_light(&bulb.addr().to_string(), None).unwrap();
+        fade_light(
+            light,
+            Duration::from_millis(500),
+            light::MIN,
+            Duration::from_millis(50),
+            Some(Duration::from_millis(100)),
+            motion_sensor_lifx::MATCHING_THRESHOLD,
+        )
+        .unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(bulb.color(), before, "restored while fading");
+    }
+}
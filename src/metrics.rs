@@ -0,0 +1,413 @@
+//! Prometheus metrics of motion, timers, fades and lights, served on `GET /metrics` by the [API](crate::api)
+//!
+//! | Metric                                       | Type      | Labels             |
+//! |----------------------------------------------|-----------|--------------------|
+//! | `motion_sensor_lifx_motion_events_total`     | counter   | `sensor`, `motion` |
+//! | `motion_sensor_lifx_timer_events_total`      | counter   | `room`, `action`   |
+//! | `motion_sensor_lifx_fades_total`             | counter   | `room`, `outcome`  |
+//! | `motion_sensor_lifx_light_request_seconds`   | histogram | `light`            |
+//! | `motion_sensor_lifx_light_errors_total`      | counter   | `light`            |
+//! | `motion_sensor_lifx_light_brightness_percent`| gauge     | `light`            |
+//! | `motion_sensor_lifx_cpu_temperature_celsius` | gauge     |                    |
+//!
+//! Counters and the brightness of the lights are kept from the [`Events`] of the daemon, so a
+//! scrape does not wait for lights. The CPU temperature is read when scraped.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crate::events::{DaemonEvent, Events};
+use crate::light;
+use crate::room::all_lights;
+use crate::temperature::Thermal;
+use crate::{Room, ACTION};
+
+/// Upper bounds in seconds of the light request latency buckets
+pub const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+/// Content type of the text exposition format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Latencies of the requests to one light
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Histogram {
+    /// Requests per bucket of [`LATENCY_BUCKETS`], not cumulative
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, latency: Duration) {
+        let secs = latency.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+}
+
+/// Counters kept from events, by label values
+#[derive(Debug, Default)]
+struct Counters {
+    motion: BTreeMap<(String, String), u64>,
+    timer: BTreeMap<(String, String), u64>,
+    fades: BTreeMap<(String, String), u64>,
+    latency: BTreeMap<String, Histogram>,
+    errors: BTreeMap<String, u64>,
+    /// Brightness in percent the lights were last set to or read at by a room
+    brightness: BTreeMap<String, f32>,
+}
+
+/// Metrics of the daemon, clones count into the same metrics
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    counters: Arc<Mutex<Counters>>,
+    /// CPU temperature sensor, no temperature gauge if missing
+    thermal: Option<Thermal>,
+}
+
+impl Metrics {
+    /// Metrics without any counts, with the CPU temperature read from `thermal`
+    pub fn new(thermal: Option<Thermal>) -> Self {
+        Self {
+            counters: Arc::default(),
+            thermal,
+        }
+    }
+
+    /// Count every event published on `events` from now on, until every clone is dropped
+    pub fn spawn(thermal: Option<Thermal>, events: &Events) -> std::io::Result<Self> {
+        let metrics = Self::new(thermal);
+        let receiver = events.subscribe();
+        let counters = Arc::downgrade(&metrics.counters);
+        thread::Builder::new()
+            .name("metrics".to_string())
+            .spawn(move || {
+                for event in receiver {
+                    let Some(counters) = Weak::upgrade(&counters) else {
+                        break;
+                    };
+                    count(&mut counters.lock().unwrap(), &event);
+                }
+            })?;
+        Ok(metrics)
+    }
+
+    /// Count `event`
+    pub fn record(&self, event: &DaemonEvent) {
+        count(&mut self.counters.lock().unwrap(), event);
+    }
+
+    /// Metrics in the Prometheus text format, with the brightness of the lights of `rooms`
+    pub fn render(&self, rooms: &[Arc<Room>]) -> String {
+        let lights = all_lights(rooms);
+        let counters = self.counters.lock().unwrap();
+        let mut out = String::new();
+        header(
+            &mut out,
+            "motion_events_total",
+            "counter",
+            "Motion events per sensor",
+        );
+        for ((sensor, motion), count) in &counters.motion {
+            sample(
+                &mut out,
+                "motion_events_total",
+                &[("sensor", sensor), ("motion", motion)],
+                *count,
+            );
+        }
+        header(
+            &mut out,
+            "timer_events_total",
+            "counter",
+            "Timer starts, restarts and timeouts per room",
+        );
+        for ((room, action), count) in &counters.timer {
+            sample(
+                &mut out,
+                "timer_events_total",
+                &[("room", room), ("action", action)],
+                *count,
+            );
+        }
+        header(
+            &mut out,
+            "fades_total",
+            "counter",
+            "Fades of lights started, restored and aborted per room",
+        );
+        for ((room, outcome), count) in &counters.fades {
+            sample(
+                &mut out,
+                "fades_total",
+                &[("room", room), ("outcome", outcome)],
+                *count,
+            );
+        }
+
+        header(
+            &mut out,
+            "light_request_seconds",
+            "histogram",
+            "Latency of requests to lights",
+        );
+        for (light, histogram) in &counters.latency {
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                sample(
+                    &mut out,
+                    "light_request_seconds_bucket",
+                    &[("light", light), ("le", &bound.to_string())],
+                    cumulative,
+                );
+            }
+            sample(
+                &mut out,
+                "light_request_seconds_bucket",
+                &[("light", light), ("le", "+Inf")],
+                histogram.count,
+            );
+            sample(
+                &mut out,
+                "light_request_seconds_sum",
+                &[("light", light)],
+                histogram.sum,
+            );
+            sample(
+                &mut out,
+                "light_request_seconds_count",
+                &[("light", light)],
+                histogram.count,
+            );
+        }
+        header(
+            &mut out,
+            "light_errors_total",
+            "counter",
+            "Failed requests to lights",
+        );
+        for (light, count) in &counters.errors {
+            sample(&mut out, "light_errors_total", &[("light", light)], *count);
+        }
+
+        header(
+            &mut out,
+            "light_brightness_percent",
+            "gauge",
+            "Brightness of lights when last set or read by a room",
+        );
+        let brightness = counters
+            .brightness
+            .iter()
+            .filter(|(light, _)| lights.iter().any(|(name, _)| name == *light));
+        for (light, percent) in brightness {
+            sample(
+                &mut out,
+                "light_brightness_percent",
+                &[("light", light)],
+                percent,
+            );
+        }
+        if let Some(temp) = self.thermal.as_ref().and_then(|t| t.get_temp().ok()) {
+            header(
+                &mut out,
+                "cpu_temperature_celsius",
+                "gauge",
+                "Temperature of the CPU",
+            );
+            sample(&mut out, "cpu_temperature_celsius", &[], temp);
+        }
+        out
+    }
+}
+
+fn count(counters: &mut Counters, event: &DaemonEvent) {
+    match event {
+        DaemonEvent::Motion { sensor, motion } => {
+            *counters
+                .motion
+                .entry((sensor.clone(), motion.to_string()))
+                .or_default() += 1;
+        }
+        DaemonEvent::Timer { room, action } => {
+            let action = match action {
+                ACTION::START { restarted: false } => "start",
+                ACTION::START { restarted: true } => "restart",
+                ACTION::MILESTONE { name } => name.as_ref(),
+                ACTION::TIMEOUT => "timeout",
+            };
+            *counters
+                .timer
+                .entry((room.clone(), action.to_string()))
+                .or_default() += 1;
+        }
+        DaemonEvent::Fade {
+            room,
+            outcome,
+            light: _,
+        } => {
+            *counters
+                .fades
+                .entry((room.clone(), outcome.to_string()))
+                .or_default() += 1;
+        }
+        DaemonEvent::Request {
+            light,
+            latency,
+            failed,
+        } => {
+            counters
+                .latency
+                .entry(light.clone())
+                .or_default()
+                .observe(*latency);
+            if *failed {
+                *counters.errors.entry(light.clone()).or_default() += 1;
+            }
+        }
+        DaemonEvent::Light {
+            light,
+            color: Some(color),
+        }
+        | DaemonEvent::Observed { light, color, .. } => {
+            counters
+                .brightness
+                .insert(light.clone(), light::to_percent(color.brightness));
+        }
+        DaemonEvent::Room { .. } | DaemonEvent::Light { color: None, .. } => {}
+    }
+}
+
+/// Write the help and type lines of metric `name`
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP motion_sensor_lifx_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE motion_sensor_lifx_{} {}", name, kind);
+}
+
+/// Write a sample of metric `name` with `labels`
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+    let _ = write!(out, "motion_sensor_lifx_{}", name);
+    if !labels.is_empty() {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(label, value)| format!("{}=\"{}\"", label, escape(value)))
+            .collect();
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(out, " {}", value);
+}
+
+/// Label value with backslashes, quotes and newlines escaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{RoomConfig, Timings};
+    use crate::events::FadeOutcome;
+    use crate::fade_target;
+    use crate::fake::FakeBulb;
+    use crate::motion::Motion;
+    use lifx_core::HSBK;
+    use std::fs;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape(r#"kök "2"\n"#), r#"kök \"2\"\\n"#);
+        assert_eq!(escape("a\nb"), "a\\nb");
+    }
+
+    #[test]
+    fn test_render() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let config = RoomConfig::test("bedroom", Duration::from_secs(10));
+        let lights = vec![("lamp".to_string(), bulb.light().unwrap())];
+        let rooms = vec![Arc::new(Room::new(config, Timings::default(), lights))];
+        let temperature_file =
+            std::env::temp_dir().join(format!("motion_sensor_lifx_temp_{}", std::process::id()));
+        fs::write(&temperature_file, "48312\n").unwrap();
+        let thermal = Thermal::new(temperature_file.clone(), Duration::from_secs(1));
+
+        let events = Events::new();
+        let metrics = Metrics::spawn(Some(thermal), &events).unwrap();
+        for motion in [Motion::On, Motion::Off, Motion::On] {
+            events.publish(DaemonEvent::Motion {
+                sensor: "hallway".to_string(),
+                motion,
+            });
+        }
+        events.publish(DaemonEvent::Timer {
+            room: "bedroom".to_string(),
+            action: ACTION::START { restarted: true },
+        });
+        events.publish(DaemonEvent::Fade {
+            room: "bedroom".to_string(),
+            light: "lamp".to_string(),
+            outcome: FadeOutcome::Aborted,
+        });
+        events.publish(DaemonEvent::Request {
+            light: "lamp".to_string(),
+            latency: Duration::from_millis(30),
+            failed: true,
+        });
+        events.publish(DaemonEvent::Observed {
+            light: "lamp".to_string(),
+            color: fade_target(bulb.color()),
+            powered: true,
+        });
+        events.publish(DaemonEvent::Light {
+            light: "lamp".to_string(),
+            color: Some(HSBK {
+                brightness: light::MAX,
+                ..bulb.color()
+            }),
+        });
+        // not a light of the rooms anymore
+        events.publish(DaemonEvent::Observed {
+            light: "gone".to_string(),
+            color: bulb.color(),
+            powered: true,
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        let text = metrics.render(&rooms);
+        assert!(
+            bulb.received().is_empty(),
+            "scraping does not ask the lights"
+        );
+        fs::remove_file(&temperature_file).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        for expected in [
+            r#"motion_sensor_lifx_motion_events_total{sensor="hallway",motion="on"} 2"#,
+            r#"motion_sensor_lifx_motion_events_total{sensor="hallway",motion="off"} 1"#,
+            r#"motion_sensor_lifx_timer_events_total{room="bedroom",action="restart"} 1"#,
+            r#"motion_sensor_lifx_fades_total{room="bedroom",outcome="aborted"} 1"#,
+            r#"motion_sensor_lifx_light_request_seconds_bucket{light="lamp",le="5"} 1"#,
+            r#"motion_sensor_lifx_light_request_seconds_bucket{light="lamp",le="+Inf"} 1"#,
+            r#"motion_sensor_lifx_light_request_seconds_count{light="lamp"} 1"#,
+            r#"motion_sensor_lifx_light_errors_total{light="lamp"} 1"#,
+            r#"motion_sensor_lifx_light_brightness_percent{light="lamp"} 100"#,
+            r#"motion_sensor_lifx_cpu_temperature_celsius 48.312"#,
+            "# TYPE motion_sensor_lifx_light_request_seconds histogram",
+        ] {
+            assert!(
+                lines.contains(&expected),
+                "{} missing in\n{}",
+                expected,
+                text
+            );
+        }
+        assert!(!text.contains(r#"light="gone""#), "{}", text);
+    }
+}
@@ -0,0 +1,473 @@
+//! Sources of timestamped motion events, from a PIR sensor or without any GPIO chip
+//!
+//! A [`MotionSource`] is either a [`GpioMotion`] reading the edges of a GPIO line, a
+//! [`ScriptedMotion`] playing events from memory, a [`ReplayMotion`] playing events from a file,
+//! or the receiving end of a channel of [`Motion`].
+//!
+//! # Replay file format
+//!
+//! One event per line, with the seconds since the replay started and `on` or `off`. Empty lines
+//! and lines starting with `#` are ignored.
+//!
+//! ```text
+//! # someone walks in, stays for a minute and leaves
+//! 0.5 on
+//! 10 off
+//! 55 on
+//! 65 off
+//! ```
+
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{fmt, fs, io};
+
+use gpio_cdev::{Chip, EventRequestFlags, EventType, LineEventHandle, LineRequestFlags};
+
+use crate::clock::Clock;
+use crate::config::SensorConfig;
+
+/// Delay before reopening a failed motion source, doubled for every attempt that fails
+pub const REOPEN_DELAY: Duration = Duration::from_secs(1);
+/// Longest delay between attempts to reopen a failed motion source
+pub const MAX_REOPEN_DELAY: Duration = Duration::from_secs(60);
+
+/// State reported by a motion sensor
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Motion {
+    /// Motion detected
+    On,
+    /// No motion detected for a while, ~10 seconds for the PIR sensor
+    Off,
+}
+
+impl fmt::Display for Motion {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Motion::On => write!(fmt, "on"),
+            Motion::Off => write!(fmt, "off"),
+        }
+    }
+}
+
+/// A change of [`Motion`] and when it happened
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MotionEvent {
+    pub motion: Motion,
+    pub timestamp: Instant,
+}
+
+impl MotionEvent {
+    /// Event happening now
+    pub fn now(motion: Motion) -> Self {
+        Self {
+            motion,
+            timestamp: Instant::now(),
+        }
+    }
+}
+
+/// Errors when opening or reading a motion source
+#[derive(Debug)]
+pub enum MotionError {
+    /// GPIO chip or line could not be opened or read
+    Gpio(gpio_cdev::Error),
+    /// Replay file could not be read
+    Io(PathBuf, io::Error),
+    /// Replay file has an invalid line, numbered from 1
+    Parse { line: usize, message: String },
+    /// Sensor has neither a GPIO pin nor a replay file
+    NoSource(String),
+}
+
+impl fmt::Display for MotionError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MotionError::Gpio(err) => write!(fmt, "GPIO error: {}", err),
+            MotionError::Io(path, err) => {
+                write!(
+                    fmt,
+                    "could not read replay file {}: {}",
+                    path.display(),
+                    err
+                )
+            }
+            MotionError::Parse { line, message } => {
+                write!(fmt, "invalid replay file on line {}: {}", line, message)
+            }
+            MotionError::NoSource(sensor) => {
+                write!(fmt, "sensor {:?} has no pin or replay file", sensor)
+            }
+        }
+    }
+}
+
+impl Error for MotionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MotionError::Gpio(err) => Some(err),
+            MotionError::Io(_, err) => Some(err),
+            MotionError::Parse { .. } | MotionError::NoSource(_) => None,
+        }
+    }
+}
+
+impl From<gpio_cdev::Error> for MotionError {
+    fn from(err: gpio_cdev::Error) -> Self {
+        MotionError::Gpio(err)
+    }
+}
+
+/// Something producing motion events, like a PIR sensor
+pub trait MotionSource {
+    /// Block until the next event, `None` if the source has no more events
+    fn next_event(&mut self) -> Result<Option<MotionEvent>, MotionError>;
+}
+
+/// Open the motion source of `sensor`, the replay file if there is one or else its GPIO line
+pub fn open(sensor: &SensorConfig) -> Result<Box<dyn MotionSource + Send>, MotionError> {
+    match (&sensor.replay, sensor.pin) {
+        (Some(path), _) => Ok(Box::new(ReplayMotion::load(path)?)),
+        (None, Some(pin)) => Ok(Box::new(GpioMotion::open(&sensor.chip, pin)?)),
+        (None, None) => Err(MotionError::NoSource(sensor.name.clone())),
+    }
+}
+
+/// PIR sensor on a GPIO line, motion is on while the line is high
+#[derive(Debug)]
+pub struct GpioMotion {
+    events: LineEventHandle,
+}
+
+impl GpioMotion {
+    /// Request events on both edges of line `pin` on the GPIO `chip`
+    pub fn open<P: AsRef<Path>>(chip: P, pin: u32) -> Result<Self, MotionError> {
+        let mut chip = Chip::new(chip)?;
+        // Error will appear here if line is occupied
+        let line = chip.get_line(pin)?;
+        let events = line.events(
+            LineRequestFlags::INPUT,
+            EventRequestFlags::BOTH_EDGES,
+            "rust-program",
+        )?;
+        Ok(Self { events })
+    }
+}
+
+impl MotionSource for GpioMotion {
+    fn next_event(&mut self) -> Result<Option<MotionEvent>, MotionError> {
+        let Some(event) = self.events.next() else {
+            return Ok(None);
+        };
+        let motion = match event?.event_type() {
+            EventType::RisingEdge => Motion::On,
+            EventType::FallingEdge => Motion::Off,
+        };
+        Ok(Some(MotionEvent::now(motion)))
+    }
+}
+
+/// Events played from memory, each at an offset from when the first event was requested
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScriptedMotion {
+    steps: Vec<(Duration, Motion)>,
+    next: usize,
+    started: Option<Instant>,
+}
+
+impl ScriptedMotion {
+    /// Script with `steps` of offsets and motion, sorted by offset
+    pub fn new<I: IntoIterator<Item = (Duration, Motion)>>(steps: I) -> Self {
+        let mut steps: Vec<_> = steps.into_iter().collect();
+        steps.sort_by_key(|(offset, _)| *offset);
+        Self {
+            steps,
+            next: 0,
+            started: None,
+        }
+    }
+
+    /// Steps that have not been played yet
+    pub fn remaining(&self) -> &[(Duration, Motion)] {
+        &self.steps[self.next..]
+    }
+}
+
+impl MotionSource for ScriptedMotion {
+    fn next_event(&mut self) -> Result<Option<MotionEvent>, MotionError> {
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let Some(&(offset, motion)) = self.steps.get(self.next) else {
+            return Ok(None);
+        };
+        self.next += 1;
+        let timestamp = started + offset;
+        thread::sleep(timestamp.saturating_duration_since(Instant::now()));
+        Ok(Some(MotionEvent { motion, timestamp }))
+    }
+}
+
+/// Events played from a replay file, see the [module documentation](self) for the format
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplayMotion {
+    script: ScriptedMotion,
+}
+
+impl ReplayMotion {
+    /// Read and parse the replay file at `path`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, MotionError> {
+        let path = path.as_ref();
+        fs::read_to_string(path)
+            .map_err(|err| MotionError::Io(path.to_path_buf(), err))?
+            .parse()
+    }
+
+    /// Steps that have not been played yet
+    pub fn remaining(&self) -> &[(Duration, Motion)] {
+        self.script.remaining()
+    }
+}
+
+impl FromStr for ReplayMotion {
+    type Err = MotionError;
+
+    fn from_str(contents: &str) -> Result<Self, Self::Err> {
+        let mut steps = Vec::new();
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parse_error = |message: String| MotionError::Parse {
+                line: index + 1,
+                message,
+            };
+            let mut parts = line.split_whitespace();
+            let (Some(secs), Some(motion), None) = (parts.next(), parts.next(), parts.next())
+            else {
+                return Err(parse_error(format!(
+                    "expected \"<seconds> on|off\", got {:?}",
+                    line
+                )));
+            };
+            let offset = secs
+                .parse()
+                .ok()
+                .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                .ok_or_else(|| parse_error(format!("invalid seconds {:?}", secs)))?;
+            let motion = match motion {
+                "on" => Motion::On,
+                "off" => Motion::Off,
+                _ => return Err(parse_error(format!("expected on or off, got {:?}", motion))),
+            };
+            steps.push((offset, motion));
+        }
+        Ok(Self {
+            script: ScriptedMotion::new(steps),
+        })
+    }
+}
+
+impl MotionSource for ReplayMotion {
+    fn next_event(&mut self) -> Result<Option<MotionEvent>, MotionError> {
+        self.script.next_event()
+    }
+}
+
+/// Motion sent on a channel, timestamped when received, until every sender is dropped
+impl MotionSource for Receiver<Motion> {
+    fn next_event(&mut self) -> Result<Option<MotionEvent>, MotionError> {
+        Ok(self.recv().ok().map(MotionEvent::now))
+    }
+}
+
+impl<S: MotionSource + ?Sized> MotionSource for Box<S> {
+    fn next_event(&mut self) -> Result<Option<MotionEvent>, MotionError> {
+        (**self).next_event()
+    }
+}
+
+/// Pass every event of `source` to `send`, until the source has no more events or `send` returns false
+///
+/// A failing source is logged and replaced by [`reopen`](FnMut), waiting on `clock` between
+/// attempts from [`REOPEN_DELAY`] up to [`MAX_REOPEN_DELAY`].
+pub fn watch<S, R, F>(mut source: S, mut reopen: R, clock: &dyn Clock, mut send: F)
+where
+    S: MotionSource,
+    R: FnMut() -> Result<S, MotionError>,
+    F: FnMut(MotionEvent) -> bool,
+{
+    loop {
+        match source.next_event() {
+            Ok(Some(event)) => {
+                if !send(event) {
+                    return;
+                }
+            }
+            Ok(None) => {
+                tracing::warn!("no more events from sensor");
+                return;
+            }
+            Err(err) => {
+                let mut delay = REOPEN_DELAY;
+                tracing::error!("sensor failed: {}, reopening in {:?}", err, delay);
+                source = loop {
+                    clock.sleep(delay);
+                    match reopen() {
+                        Ok(source) => break source,
+                        Err(err) => {
+                            delay = (delay * 2).min(MAX_REOPEN_DELAY);
+                            tracing::error!(
+                                "could not reopen sensor: {}, retrying in {:?}",
+                                err,
+                                delay
+                            );
+                        }
+                    }
+                };
+                tracing::info!("sensor reopened");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::sync::{mpsc, Arc};
+
+    /// Play all events of `source`
+    fn collect<S: MotionSource>(mut source: S) -> Vec<MotionEvent> {
+        let mut events = Vec::new();
+        while let Some(event) = source.next_event().unwrap() {
+            events.push(event);
+        }
+        events
+    }
+
+    #[test]
+    fn test_scripted() {
+        let started = Instant::now();
+        let script = ScriptedMotion::new([
+            (Duration::from_millis(100), Motion::Off),
+            (Duration::from_millis(50), Motion::On),
+        ]);
+        let events = collect(script);
+        assert_eq!(
+            events.iter().map(|e| e.motion).collect::<Vec<_>>(),
+            [Motion::On, Motion::Off],
+            "sorted by offset"
+        );
+        let offset = events[1].timestamp - events[0].timestamp;
+        assert_eq!(offset, Duration::from_millis(50));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_replay() {
+        let replay: ReplayMotion = "# comment\n\n0 on\n 0.05 off \n0.1 on\n".parse().unwrap();
+        assert_eq!(
+            replay.remaining(),
+            [
+                (Duration::ZERO, Motion::On),
+                (Duration::from_millis(50), Motion::Off),
+                (Duration::from_millis(100), Motion::On),
+            ]
+        );
+        assert_eq!(collect(replay).len(), 3);
+    }
+
+    #[test]
+    fn test_replay_errors() {
+        let error = |contents: &str| contents.parse::<ReplayMotion>().unwrap_err().to_string();
+        assert_eq!(
+            error("0 on\n1 maybe"),
+            r#"invalid replay file on line 2: expected on or off, got "maybe""#
+        );
+        assert_eq!(
+            error("-1 on"),
+            r#"invalid replay file on line 1: invalid seconds "-1""#
+        );
+        assert_eq!(
+            error("1 on off"),
+            r#"invalid replay file on line 1: expected "<seconds> on|off", got "1 on off""#
+        );
+        assert!(matches!(
+            ReplayMotion::load("/nonexistent/replay"),
+            Err(MotionError::Io(..))
+        ));
+    }
+
+    #[test]
+    fn test_channel() {
+        let (sender, receiver) = mpsc::channel();
+        sender.send(Motion::On).unwrap();
+        sender.send(Motion::Off).unwrap();
+        drop(sender);
+        let mut source: Box<dyn MotionSource> = Box::new(receiver);
+        assert_eq!(source.next_event().unwrap().unwrap().motion, Motion::On);
+        assert_eq!(source.next_event().unwrap().unwrap().motion, Motion::Off);
+        assert!(source.next_event().unwrap().is_none());
+    }
+
+    /// Source failing on its first read
+    struct Failing;
+
+    impl MotionSource for Failing {
+        fn next_event(&mut self) -> Result<Option<MotionEvent>, MotionError> {
+            Err(MotionError::NoSource("hallway".to_string()))
+        }
+    }
+
+    #[test]
+    fn test_watch_reopens() {
+        let clock = Arc::new(MockClock::new());
+        let clock_watch = clock.clone();
+        let (sender, receiver) = mpsc::channel();
+        let watching = thread::spawn(move || {
+            let mut attempts = 0;
+            let reopen = || -> Result<Box<dyn MotionSource>, MotionError> {
+                attempts += 1;
+                match attempts {
+                    1 => Err(MotionError::NoSource("hallway".to_string())),
+                    _ => Ok(Box::new(ScriptedMotion::new([(
+                        Duration::ZERO,
+                        Motion::On,
+                    )]))),
+                }
+            };
+            let failing: Box<dyn MotionSource> = Box::new(Failing);
+            watch(failing, reopen, &*clock_watch, |event| {
+                sender.send(event.motion).is_ok()
+            });
+            attempts
+        });
+        // waits REOPEN_DELAY for the first attempt and twice as long for the second
+        for _ in 0..3 {
+            clock.wait_for_sleepers(1);
+            assert!(receiver.try_recv().is_err());
+            clock.advance(REOPEN_DELAY);
+        }
+        assert_eq!(watching.join().unwrap(), 2);
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![Motion::On]);
+    }
+
+    #[test]
+    fn test_open() {
+        let sensor = SensorConfig {
+            name: "hallway".to_string(),
+            chip: PathBuf::from("/nonexistent/gpiochip"),
+            pin: Some(17),
+            replay: None,
+        };
+        assert!(matches!(open(&sensor), Err(MotionError::Gpio(_))));
+        let sensor = SensorConfig {
+            pin: None,
+            ..sensor
+        };
+        assert!(matches!(open(&sensor), Err(MotionError::NoSource(_))));
+    }
+}
@@ -0,0 +1,625 @@
+//! MQTT bridge publishing motion, timers, rooms and lights, and taking commands, with the `mqtt` feature
+//!
+//! Topics start with the `topic_prefix` of the `[mqtt]` section, `motion_sensor_lifx` by default:
+//!
+//! | Topic                          | Payload                                               |
+//! |--------------------------------|-------------------------------------------------------|
+//! | `<prefix>/status`              | `online`, or `offline` when the daemon stops (retained) |
+//! | `<prefix>/sensors/<sensor>/motion` | `on` or `off` (retained)                          |
+//! | `<prefix>/rooms/<room>/timer`  | `start`, `restart`, `timeout` or a milestone like `warning` |
+//! | `<prefix>/rooms/<room>/state`  | [`RoomStatus`] as JSON (retained)                     |
+//! | `<prefix>/lights/<light>/state` | [`LightStatus`] as JSON (retained)                   |
+//!
+//! Commands are taken from these topics:
+//!
+//! | Topic                              | Payload                                           |
+//! |------------------------------------|---------------------------------------------------|
+//! | `<prefix>/rooms/<room>/pause/set`  | `true`/`ON` to pause motion control, `false`/`OFF` to resume |
+//! | `<prefix>/rooms/<room>/timeout/set` | Timeout in seconds, empty to go back to the configured timeout |
+//! | `<prefix>/lights/<light>/set`      | [`LightChange`] as JSON                           |
+//!
+//! ```text
+//! $ mosquitto_sub -v -t 'motion_sensor_lifx/#'
+//! $ mosquitto_pub -t motion_sensor_lifx/rooms/bedroom/timeout/set -m 120
+//! ```
+//!
+//! With a `discovery_prefix` the entities are announced to Home Assistant, see [`homeassistant`].
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::{fmt, io};
+
+use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, QoS};
+
+use crate::api::{LightChange, LightStatus, RoomStatus};
+use crate::config::MqttConfig;
+use crate::events::{DaemonEvent, Events};
+use crate::homeassistant;
+use crate::room::{all_lights, with_light, Rooms};
+use crate::{Room, ACTION};
+
+/// Delay before connecting to the broker again after the connection failed
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Keep alive interval of the connection to the broker
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+/// Number of requests to the broker that can be queued before publishing blocks
+const CAPACITY: usize = 64;
+
+/// Topics of the bridge, all below one prefix
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Topics {
+    prefix: String,
+}
+
+impl Topics {
+    pub fn new(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+        }
+    }
+
+    /// Availability of the daemon, also the last will
+    pub fn status(&self) -> String {
+        format!("{}/status", self.prefix)
+    }
+
+    pub fn motion(&self, sensor: &str) -> String {
+        format!("{}/sensors/{}/motion", self.prefix, sensor)
+    }
+
+    pub fn timer(&self, room: &str) -> String {
+        format!("{}/rooms/{}/timer", self.prefix, room)
+    }
+
+    pub fn room_state(&self, room: &str) -> String {
+        format!("{}/rooms/{}/state", self.prefix, room)
+    }
+
+    pub fn light_state(&self, light: &str) -> String {
+        format!("{}/lights/{}/state", self.prefix, light)
+    }
+
+    pub fn pause(&self, room: &str) -> String {
+        format!("{}/rooms/{}/pause/set", self.prefix, room)
+    }
+
+    pub fn timeout(&self, room: &str) -> String {
+        format!("{}/rooms/{}/timeout/set", self.prefix, room)
+    }
+
+    pub fn light_set(&self, light: &str) -> String {
+        format!("{}/lights/{}/set", self.prefix, light)
+    }
+
+    /// Filters matching every command topic
+    pub fn commands(&self) -> Vec<String> {
+        vec![self.pause("+"), self.timeout("+"), self.light_set("+")]
+    }
+}
+
+/// A message for the broker
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Publication {
+    pub topic: String,
+    pub payload: String,
+    /// If the broker keeps the message for later subscribers
+    pub retain: bool,
+}
+
+/// Publication of the state of `room`
+pub fn room_state(topics: &Topics, room: &Room) -> Publication {
+    Publication {
+        topic: topics.room_state(room.name()),
+        payload: serde_json::to_string(&RoomStatus::new(room)).expect("status serializes"),
+        retain: true,
+    }
+}
+
+/// Publication of the state of light `name` of `rooms`, `None` if it is unknown or does not answer
+pub fn light_state(topics: &Topics, rooms: &[Arc<Room>], name: &str) -> Option<Publication> {
+    match with_light(rooms, name, |light| LightStatus::read(name, light))? {
+        Ok(status) => Some(Publication {
+            topic: topics.light_state(name),
+            payload: serde_json::to_string(&status).expect("status serializes"),
+            retain: true,
+        }),
+        Err(err) => {
+            tracing::warn!(light = %name, "could not read light for MQTT: {}", err);
+            None
+        }
+    }
+}
+
+/// State of every room and light
+pub fn full_state(topics: &Topics, rooms: &[Arc<Room>]) -> Vec<Publication> {
+    let mut publications: Vec<_> = rooms.iter().map(|room| room_state(topics, room)).collect();
+    for (name, _) in all_lights(rooms) {
+        publications.extend(light_state(topics, rooms, &name));
+    }
+    publications
+}
+
+/// Publications for `event`
+pub fn publications(topics: &Topics, rooms: &[Arc<Room>], event: &DaemonEvent) -> Vec<Publication> {
+    let room_state = |name: &str| {
+        rooms
+            .iter()
+            .find(|room| room.name() == name)
+            .map(|room| room_state(topics, room))
+    };
+    match event {
+        DaemonEvent::Motion { sensor, motion } => vec![Publication {
+            topic: topics.motion(sensor),
+            payload: motion.to_string(),
+            retain: true,
+        }],
+        DaemonEvent::Timer { room, action } => {
+            let payload = match action {
+                ACTION::START { restarted: false } => "start",
+                ACTION::START { restarted: true } => "restart",
+                ACTION::MILESTONE { name } => name.as_ref(),
+                ACTION::TIMEOUT => "timeout",
+            };
+            let timer = Publication {
+                topic: topics.timer(room),
+                payload: payload.to_string(),
+                retain: false,
+            };
+            std::iter::once(timer).chain(room_state(room)).collect()
+        }
+        DaemonEvent::Room { room } => room_state(room).into_iter().collect(),
+        DaemonEvent::Light { light, .. } => light_state(topics, rooms, light).into_iter().collect(),
+        DaemonEvent::Fade { .. } | DaemonEvent::Request { .. } | DaemonEvent::Observed { .. } => {
+            Vec::new()
+        }
+    }
+}
+
+/// A command received from the broker
+#[derive(Clone, Debug, PartialEq)]
+pub enum MqttCommand {
+    /// Pause or resume motion control of `room`
+    Pause { room: String, paused: bool },
+    /// Set the timeout of `room`, `None` for the timeout from schedules and the config file
+    SetTimeout {
+        room: String,
+        timeout: Option<Duration>,
+    },
+    /// Change `light`
+    SetLight { light: String, change: LightChange },
+}
+
+impl MqttCommand {
+    /// Parse a message on a command `topic`
+    pub fn parse(topics: &Topics, topic: &str, payload: &[u8]) -> Result<Self, String> {
+        let payload = std::str::from_utf8(payload)
+            .map_err(|err| format!("payload is not UTF-8: {}", err))?
+            .trim();
+        let rest = topic
+            .strip_prefix(&topics.prefix)
+            .and_then(|rest| rest.strip_prefix('/'))
+            .ok_or_else(|| format!("not a command topic: {}", topic))?;
+        let segments: Vec<&str> = rest.split('/').collect();
+        match segments[..] {
+            ["rooms", room, "pause", "set"] => {
+                let paused = match payload.to_lowercase().as_str() {
+                    "true" | "on" | "1" => true,
+                    "false" | "off" | "0" => false,
+                    _ => return Err(format!("expected true or false, got {:?}", payload)),
+                };
+                Ok(MqttCommand::Pause {
+                    room: room.to_string(),
+                    paused,
+                })
+            }
+            ["rooms", room, "timeout", "set"] => {
+                let timeout = if payload.is_empty() {
+                    None
+                } else {
+                    let secs: f64 = payload
+                        .parse()
+                        .map_err(|_| format!("expected seconds, got {:?}", payload))?;
+                    match Duration::try_from_secs_f64(secs) {
+                        Ok(timeout) if !timeout.is_zero() => Some(timeout),
+                        _ => return Err(format!("timeout must be more than zero, got {}", secs)),
+                    }
+                };
+                Ok(MqttCommand::SetTimeout {
+                    room: room.to_string(),
+                    timeout,
+                })
+            }
+            ["lights", light, "set"] => Ok(MqttCommand::SetLight {
+                light: light.to_string(),
+                change: serde_json::from_str(payload).map_err(|err| err.to_string())?,
+            }),
+            _ => Err(format!("not a command topic: {}", topic)),
+        }
+    }
+
+    /// Apply the command to `rooms` and their lights
+    pub fn apply(&self, rooms: &[Arc<Room>]) -> Result<(), String> {
+        let find_room = |name: &str| {
+            rooms
+                .iter()
+                .find(|room| room.name() == name)
+                .ok_or_else(|| format!("unknown room {:?}", name))
+        };
+        match self {
+            MqttCommand::Pause { room, paused: true } => find_room(room)?.pause(),
+            MqttCommand::Pause {
+                room,
+                paused: false,
+            } => find_room(room)?.resume(),
+            MqttCommand::SetTimeout { room, timeout } => find_room(room)?.set_timeout(*timeout),
+            MqttCommand::SetLight {
+                light: name,
+                change,
+            } => {
+                with_light(rooms, name, |light| change.apply(light))
+                    .ok_or_else(|| format!("unknown light {:?}", name))?
+                    .map_err(|err| format!("light {:?}: {}", name, err))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Connection to an MQTT broker, publishing events and the state of `rooms` until dropped
+pub struct Bridge {
+    client: Client,
+    topics: Topics,
+    stopped: Arc<AtomicBool>,
+}
+
+impl fmt::Debug for Bridge {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("Bridge")
+            .field("topics", &self.topics)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Bridge {
+    /// Connect to the broker of `config`, reconnecting whenever the connection fails
+    pub fn spawn(config: &MqttConfig, rooms: Rooms, events: &Events) -> io::Result<Self> {
+        let topics = Topics::new(&config.topic_prefix);
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(KEEP_ALIVE);
+        options.set_last_will(LastWill::new(
+            topics.status(),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.as_deref().unwrap_or_default());
+        }
+        let (client, mut connection) = Client::new(options, CAPACITY);
+        let stopped = Arc::new(AtomicBool::new(false));
+        let discovery_prefix = config.discovery_prefix.clone();
+        let client_id = config.client_id.clone();
+
+        let (client_connection, topics_connection, rooms_connection, stopped_connection) = (
+            client.clone(),
+            topics.clone(),
+            rooms.clone(),
+            stopped.clone(),
+        );
+        thread::Builder::new()
+            .name("mqtt".to_string())
+            .spawn(move || {
+                for notification in connection.iter() {
+                    if stopped_connection.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let rooms = rooms_connection.lock().unwrap().clone();
+                    match notification {
+                        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                            tracing::info!("connected to MQTT broker");
+                            let topics = &topics_connection;
+                            for filter in topics.commands() {
+                                let _ = client_connection.subscribe(filter, QoS::AtLeastOnce);
+                            }
+                            let online = Publication {
+                                topic: topics.status(),
+                                payload: "online".to_string(),
+                                retain: true,
+                            };
+                            let discovery = discovery_prefix.as_deref().map(|prefix| {
+                                homeassistant::discovery(topics, prefix, &client_id, &rooms)
+                            });
+                            let state = full_state(topics, &rooms);
+                            let publications = std::iter::once(online)
+                                .chain(discovery.into_iter().flatten())
+                                .chain(state);
+                            for publication in publications {
+                                let _ = publish(&client_connection, publication);
+                            }
+                        }
+                        Ok(Event::Incoming(Packet::Publish(message))) => {
+                            let result = MqttCommand::parse(
+                                &topics_connection,
+                                &message.topic,
+                                &message.payload,
+                            )
+                            .and_then(|command| command.apply(&rooms));
+                            if let Err(err) = result {
+                                tracing::warn!(topic = %message.topic, "MQTT command failed: {}", err);
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(err) => {
+                            tracing::warn!(
+                                "MQTT connection failed: {}, reconnecting in {:?}",
+                                err,
+                                RECONNECT_DELAY
+                            );
+                            thread::sleep(RECONNECT_DELAY);
+                        }
+                    }
+                }
+            })?;
+
+        let (client_events, topics_events, rooms_events) =
+            (client.clone(), topics.clone(), rooms.clone());
+        let received = events.subscribe();
+        thread::Builder::new()
+            .name("mqtt_events".to_string())
+            .spawn(move || {
+                for event in received {
+                    let rooms = rooms_events.lock().unwrap().clone();
+                    for publication in publications(&topics_events, &rooms, &event) {
+                        if publish(&client_events, publication).is_err() {
+                            return;
+                        }
+                    }
+                }
+            })?;
+
+        let (client_state, topics_state, stopped_state) =
+            (client.clone(), topics.clone(), stopped.clone());
+        let interval = config.state_interval;
+        thread::Builder::new()
+            .name("mqtt_state".to_string())
+            .spawn(move || {
+                while !stopped_state.load(Ordering::SeqCst) {
+                    thread::sleep(interval);
+                    let rooms = rooms.lock().unwrap().clone();
+                    for publication in full_state(&topics_state, &rooms) {
+                        if publish(&client_state, publication).is_err() {
+                            return;
+                        }
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            client,
+            topics,
+            stopped,
+        })
+    }
+}
+
+fn publish(client: &Client, publication: Publication) -> Result<(), rumqttc::ClientError> {
+    client.publish(
+        publication.topic,
+        QoS::AtLeastOnce,
+        publication.retain,
+        publication.payload,
+    )
+}
+
+impl Drop for Bridge {
+    fn drop(&mut self) {
+        let _ = self
+            .client
+            .publish(self.topics.status(), QoS::AtLeastOnce, true, "offline");
+        self.stopped.store(true, Ordering::SeqCst);
+        let _ = self.client.disconnect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{RoomConfig, Timings};
+    use crate::fake::FakeBulb;
+    use crate::motion::Motion;
+
+    fn rooms(bulb: &FakeBulb) -> Vec<Arc<Room>> {
+        rooms_with_events(bulb, Events::new())
+    }
+
+    fn rooms_with_events(bulb: &FakeBulb, events: Events) -> Vec<Arc<Room>> {
+        let config = RoomConfig::test("bedroom", Duration::from_secs(10));
+        let lights = vec![("lamp".to_string(), bulb.light().unwrap())];
+        vec![Arc::new(Room::with_events(
+            config,
+            Timings::default(),
+            lights,
+            crate::clock::real(),
+            events,
+        ))]
+    }
+
+    #[test]
+    fn test_parse() {
+        let topics = Topics::new("home/pir");
+        assert_eq!(
+            topics.commands(),
+            [
+                "home/pir/rooms/+/pause/set",
+                "home/pir/rooms/+/timeout/set",
+                "home/pir/lights/+/set"
+            ]
+        );
+        let parse =
+            |topic: &str, payload: &str| MqttCommand::parse(&topics, topic, payload.as_bytes());
+        assert_eq!(
+            parse("home/pir/rooms/bedroom/pause/set", "ON"),
+            Ok(MqttCommand::Pause {
+                room: "bedroom".to_string(),
+                paused: true
+            })
+        );
+        assert_eq!(
+            parse("home/pir/rooms/bedroom/timeout/set", "90.5"),
+            Ok(MqttCommand::SetTimeout {
+                room: "bedroom".to_string(),
+                timeout: Some(Duration::from_millis(90500))
+            })
+        );
+        assert_eq!(
+            parse("home/pir/rooms/bedroom/timeout/set", ""),
+            Ok(MqttCommand::SetTimeout {
+                room: "bedroom".to_string(),
+                timeout: None
+            })
+        );
+        assert_eq!(
+            parse("home/pir/lights/lamp/set", r#"{"brightness": 50}"#),
+            Ok(MqttCommand::SetLight {
+                light: "lamp".to_string(),
+                change: LightChange {
+                    brightness: Some(50.0),
+                    ..Default::default()
+                }
+            })
+        );
+        assert!(parse("home/pir/rooms/bedroom/pause/set", "maybe").is_err());
+        assert!(parse("home/pir/rooms/bedroom/timeout/set", "0").is_err());
+        assert!(parse("home/other/rooms/bedroom/pause/set", "on").is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let rooms = rooms(&bulb);
+        let topics = Topics::new("pir");
+        let apply = |topic: &str, payload: &str| {
+            MqttCommand::parse(&topics, topic, payload.as_bytes())?.apply(&rooms)
+        };
+        apply("pir/rooms/bedroom/pause/set", "true").unwrap();
+        assert!(rooms[0].is_paused());
+        apply("pir/rooms/bedroom/timeout/set", "120").unwrap();
+        assert_eq!(rooms[0].settings().timeout, Duration::from_secs(120));
+        apply("pir/lights/lamp/set", r#"{"hue": 180}"#).unwrap();
+        assert_eq!(bulb.color().hue, 0x8000);
+        assert_eq!(
+            apply("pir/rooms/kitchen/pause/set", "false"),
+            Err(r#"unknown room "kitchen""#.to_string())
+        );
+    }
+
+    #[test]
+    fn test_publications() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let rooms = rooms(&bulb);
+        let topics = Topics::new("pir");
+        let motion = DaemonEvent::Motion {
+            sensor: "hallway".to_string(),
+            motion: Motion::On,
+        };
+        assert_eq!(
+            publications(&topics, &rooms, &motion),
+            vec![Publication {
+                topic: "pir/sensors/hallway/motion".to_string(),
+                payload: "on".to_string(),
+                retain: true,
+            }]
+        );
+
+        let timer = DaemonEvent::Timer {
+            room: "bedroom".to_string(),
+            action: ACTION::TIMEOUT,
+        };
+        let published = publications(&topics, &rooms, &timer);
+        assert_eq!(published[0].topic, "pir/rooms/bedroom/timer");
+        assert_eq!(published[0].payload, "timeout");
+        assert_eq!(published[1].topic, "pir/rooms/bedroom/state");
+        let state: serde_json::Value = serde_json::from_str(&published[1].payload).unwrap();
+        assert_eq!(state["state"], "vacant");
+
+        let light = DaemonEvent::Light {
+            light: "lamp".to_string(),
+            color: None,
+        };
+        let published = publications(&topics, &rooms, &light);
+        assert_eq!(published[0].topic, "pir/lights/lamp/state");
+        assert!(published[0].payload.contains(r#""label":"Taklampa""#));
+
+        assert_eq!(full_state(&topics, &rooms).len(), 2);
+    }
+
+    /// Needs a broker like mosquitto on localhost:1883
+    #[test]
+    #[ignore]
+    fn test_broker() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let events = Events::new();
+        let rooms: Rooms = Arc::new(std::sync::Mutex::new(rooms_with_events(
+            &bulb,
+            events.clone(),
+        )));
+        let config: MqttConfig = toml::from_str(
+            "host = \"localhost\"\nclient_id = \"motion_sensor_lifx_test\"\ntopic_prefix = \"motion_sensor_lifx_test\"\ndiscovery_prefix = \"motion_sensor_lifx_test_ha\"",
+        )
+        .unwrap();
+        let bridge = Bridge::spawn(&config, rooms.clone(), &events).unwrap();
+
+        let mut options = MqttOptions::new("motion_sensor_lifx_test_client", "localhost", 1883);
+        options.set_keep_alive(KEEP_ALIVE);
+        let (client, mut connection) = Client::new(options, CAPACITY);
+        client
+            .subscribe("motion_sensor_lifx_test/#", QoS::AtLeastOnce)
+            .unwrap();
+        client
+            .subscribe("motion_sensor_lifx_test_ha/#", QoS::AtLeastOnce)
+            .unwrap();
+        thread::sleep(Duration::from_millis(500));
+        client
+            .publish(
+                "motion_sensor_lifx_test/rooms/bedroom/pause/set",
+                QoS::AtLeastOnce,
+                false,
+                "on",
+            )
+            .unwrap();
+        events.publish(DaemonEvent::Motion {
+            sensor: "hallway".to_string(),
+            motion: Motion::On,
+        });
+
+        // the test client only talks to the broker while its connection is iterated
+        let mut motion = false;
+        let mut paused = false;
+        let mut discovered = false;
+        for notification in connection.iter().take(100) {
+            if let Ok(Event::Incoming(Packet::Publish(message))) = notification {
+                if message.topic == "motion_sensor_lifx_test/sensors/hallway/motion" {
+                    assert_eq!(&message.payload[..], b"on");
+                    motion = true;
+                }
+                if message.topic == "motion_sensor_lifx_test/rooms/bedroom/state" {
+                    let state: serde_json::Value =
+                        serde_json::from_slice(&message.payload).unwrap();
+                    paused |= state["paused"] == true;
+                }
+                discovered |= message.topic
+                    == "motion_sensor_lifx_test_ha/light/motion_sensor_lifx_test/lamp_light/config";
+            }
+            if motion && paused && discovered {
+                break;
+            }
+        }
+        assert!(motion);
+        assert!(paused);
+        assert!(discovered, "retained discovery config");
+        assert!(rooms.lock().unwrap()[0].is_paused());
+        drop(bridge);
+    }
+}
@@ -0,0 +1,841 @@
+//! State machine deciding when the lights of a room fade and are restored
+//!
+//! [`Presence`] is driven by motion events, timeouts and colors observed on the lights, and
+//! answers with [`Command`]s for the lights. It does no I/O and never reads the clock itself, so
+//! every transition can be tested with made up instants.
+//!
+//! ```text
+//!            motion on                 motion off
+//!   ┌─────────────────────> Occupied ─────────────────> Vacant
+//!   │                          │ timeout                  │ timeout
+//!   │                          └─────────> Fading <───────┘
+//!   │                                       │   ^ timeout
+//!   │                                       │ Warning <── warning before the timeout, if set
+//!   │ motion                                │ fade done
+//!   ├─────────────────────────────────────  Off
+//!   │                                       │ light changed during fade or while off
+//!   └─────────────────────────────────  ManualOverride ── left on for timeout ──> Fading
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use lifx_core::HSBK;
+
+use crate::config::{RoomConfig, Timings};
+use crate::light::{matches_fade_within, Wave};
+use crate::motion::Motion;
+use crate::{fade_to, WARNING_DURATION};
+
+/// State of a room
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// Motion detected and not yet reported as ended
+    Occupied,
+    /// Motion has ended, counting down to the timeout
+    Vacant,
+    /// Lights have been dimmed a little as a warning that the timeout is near
+    Warning { started: Instant },
+    /// Lights are fading after the timeout
+    Fading { started: Instant },
+    /// Lights have faded
+    Off,
+    /// A light was changed during the fade or while off, it is left alone until motion or until
+    /// it has been left on without motion for the timeout
+    ManualOverride { since: Instant },
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            State::Occupied => write!(fmt, "occupied"),
+            State::Vacant => write!(fmt, "vacant"),
+            State::Warning { .. } => write!(fmt, "warning"),
+            State::Fading { .. } => write!(fmt, "fading"),
+            State::Off => write!(fmt, "off"),
+            State::ManualOverride { .. } => write!(fmt, "manual override"),
+        }
+    }
+}
+
+/// Fade of one light, to be able to restore the color from before the fade
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fade {
+    pub before: HSBK,
+    pub target: HSBK,
+    pub started: Instant,
+    pub duration: Duration,
+    /// If the light is powered off once the fade is done
+    pub power_off: bool,
+    /// If the light has been powered off by [`Command::PowerOff`]
+    pub powered_off: bool,
+}
+
+impl Fade {
+    /// If `current` and `powered` are where the fade should be at `at`, meaning the light has not been changed
+    ///
+    /// A light powered on after [`Command::PowerOff`], or powered off before it, was switched by hand.
+    pub fn matches(&self, current: HSBK, powered: bool, at: Instant, threshold: f32) -> bool {
+        powered != self.powered_off
+            && matches_fade_within(
+                self.before,
+                self.target,
+                current,
+                at.saturating_duration_since(self.started),
+                self.duration,
+                threshold,
+            )
+    }
+
+    /// If the fade has reached its target at `at`
+    pub fn is_done(&self, at: Instant) -> bool {
+        at >= self.started + self.duration
+    }
+}
+
+/// Warning before the lights fade, see [`State::Warning`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Warning {
+    /// Time before the timeout the lights are dimmed
+    pub before: Duration,
+    /// Part of their brightness the lights are dimmed by, from 0 to 1
+    pub dim: f32,
+    /// Waveform played on the dimmed lights
+    pub cue: Option<Wave>,
+}
+
+/// Settings of a room used by the state machine
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Settings {
+    /// Time without motion before the lights fade
+    pub timeout: Duration,
+    pub fade_duration: Duration,
+    /// Brightness the lights fade to
+    pub fade_brightness: u16,
+    /// See [`matches_fade_within`]
+    pub threshold: f32,
+    /// If the lights are faded at all, disabled by schedules, see [`crate::schedule`]
+    pub enabled: bool,
+    /// If the lights are powered off once faded
+    pub power_off: bool,
+    /// Waveform played on the lights restored by motion
+    pub restore_cue: Option<Wave>,
+    /// Warning before the lights fade
+    pub warning: Option<Warning>,
+}
+
+impl Settings {
+    pub fn new(room: &RoomConfig, timings: &Timings) -> Self {
+        Self {
+            timeout: room.timeout,
+            fade_duration: room.fade_duration,
+            fade_brightness: room.fade_brightness(),
+            threshold: timings.matching_threshold,
+            enabled: true,
+            power_off: room.power_off,
+            restore_cue: room.restore_cue.map(|cue| cue.wave()),
+            warning: room.warning.map(|warning| Warning {
+                before: warning.before,
+                dim: warning.dim / 100.0,
+                cue: warning.cue.map(|cue| cue.wave()),
+            }),
+        }
+    }
+}
+
+/// Inputs of the state machine
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// A sensor of the room reported motion
+    Motion { motion: Motion, at: Instant },
+    /// The room timer reached the warning before the timeout, with the current colors of the lights that are powered on
+    Warning {
+        at: Instant,
+        colors: Vec<(String, HSBK)>,
+    },
+    /// The room timer ran out, with the current colors of the lights that are powered on
+    Timeout {
+        at: Instant,
+        colors: Vec<(String, HSBK)>,
+    },
+    /// Current color and power of a light, read when polling the lights
+    Observed {
+        light: String,
+        color: HSBK,
+        powered: bool,
+        at: Instant,
+    },
+    /// Fade the lights now as if the timeout was reached, even when disabled, with their current colors
+    Fade {
+        at: Instant,
+        colors: Vec<(String, HSBK)>,
+    },
+    /// Restore the faded lights as if motion ended at `at`
+    Restore { at: Instant },
+}
+
+/// Outputs of the state machine, to be applied to the lights in order
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Change `light` to `color` over `duration`
+    SetColor {
+        light: String,
+        color: HSBK,
+        duration: Duration,
+    },
+    /// Restore `light` to its color from before `fade`, and power it on if it was powered off,
+    /// if it still matches the fade when applied
+    Restore { light: String, fade: Fade },
+    /// Power `light` off after its fade is done
+    PowerOff { light: String },
+    /// Dim `light` to `color` over `duration` as a warning, and play the warning cue
+    Dim {
+        light: String,
+        color: HSBK,
+        duration: Duration,
+    },
+    /// Undo `dim` of `light` by the warning, if it still matches when applied
+    Undim { light: String, dim: Fade },
+}
+
+/// Motion-to-light state machine of one room
+#[derive(Clone, Debug, PartialEq)]
+pub struct Presence {
+    state: State,
+    settings: Settings,
+    last_motion: Instant,
+    /// Fade per light name, for lights that have been faded and not restored or changed
+    fades: HashMap<String, Fade>,
+    /// Dim per light name, for lights dimmed by the warning and not faded, restored or changed
+    warnings: HashMap<String, Fade>,
+}
+
+impl Presence {
+    /// Start [`State::Vacant`], as if motion ended at `now`
+    pub fn new(settings: Settings, now: Instant) -> Self {
+        Self {
+            state: State::Vacant,
+            settings,
+            last_motion: now,
+            fades: HashMap::new(),
+            warnings: HashMap::new(),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn settings(&self) -> Settings {
+        self.settings
+    }
+
+    /// Change the settings, a running fade keeps its settings
+    pub fn set_settings(&mut self, settings: Settings) {
+        self.settings = settings;
+    }
+
+    /// Instant of the latest motion, or of the start if there was none
+    pub fn last_motion(&self) -> Instant {
+        self.last_motion
+    }
+
+    /// Fade per light name of lights that are fading or faded
+    pub fn fades(&self) -> &HashMap<String, Fade> {
+        &self.fades
+    }
+
+    /// Dim per light name of lights dimmed by the warning
+    pub fn warnings(&self) -> &HashMap<String, Fade> {
+        &self.warnings
+    }
+
+    /// Take over `fades` started before a restart, going to [`State::Fading`], or [`State::Off`] if they are done at `at`
+    pub fn resume_fades(&mut self, fades: HashMap<String, Fade>, at: Instant) {
+        if fades.is_empty() {
+            return;
+        }
+        let started = fades.values().map(|fade| fade.started).min().unwrap_or(at);
+        self.state = if fades.values().all(|fade| fade.is_done(at)) {
+            State::Off
+        } else {
+            State::Fading { started }
+        };
+        self.fades = fades;
+    }
+
+    /// Handle `event`, returning the commands for the lights
+    pub fn handle(&mut self, event: Event) -> Vec<Command> {
+        match event {
+            Event::Motion { motion, at } => self.motion(motion, at),
+            Event::Warning { at, colors } => self.warning(at, colors),
+            Event::Timeout { at, colors } => self.timeout(at, colors),
+            Event::Observed {
+                light,
+                color,
+                powered,
+                at,
+            } => self.observed(light, color, powered, at),
+            Event::Fade { at, colors } => self.force_fade(at, colors),
+            Event::Restore { at } => self.motion(Motion::Off, at),
+        }
+    }
+
+    fn motion(&mut self, motion: Motion, at: Instant) -> Vec<Command> {
+        self.last_motion = self.last_motion.max(at);
+        self.state = match motion {
+            Motion::On => State::Occupied,
+            Motion::Off => State::Vacant,
+        };
+        let mut restore: Vec<_> = self.fades.drain().collect();
+        restore.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut undim: Vec<_> = self.warnings.drain().collect();
+        undim.sort_by(|(a, _), (b, _)| a.cmp(b));
+        restore
+            .into_iter()
+            .map(|(light, fade)| Command::Restore { light, fade })
+            .chain(
+                undim
+                    .into_iter()
+                    .map(|(light, dim)| Command::Undim { light, dim }),
+            )
+            .collect()
+    }
+
+    fn warning(&mut self, at: Instant, colors: Vec<(String, HSBK)>) -> Vec<Command> {
+        let Some(warning) = self.settings.warning else {
+            return Vec::new();
+        };
+        // a warning from before the latest motion is stale
+        let idle = at.saturating_duration_since(self.last_motion) + warning.before
+            >= self.settings.timeout;
+        if !idle || !self.settings.enabled || !matches!(self.state, State::Occupied | State::Vacant)
+        {
+            return Vec::new();
+        }
+        self.state = State::Warning { started: at };
+        colors
+            .into_iter()
+            .filter(|(_, color)| color.brightness > self.settings.fade_brightness)
+            .map(|(light, color)| {
+                let dimmed = (f32::from(color.brightness) * (1.0 - warning.dim)) as u16;
+                let dim = Fade {
+                    before: color,
+                    target: fade_to(color, dimmed.max(self.settings.fade_brightness)),
+                    started: at,
+                    duration: WARNING_DURATION,
+                    power_off: false,
+                    powered_off: false,
+                };
+                self.warnings.insert(light.clone(), dim);
+                Command::Dim {
+                    light,
+                    color: dim.target,
+                    duration: dim.duration,
+                }
+            })
+            .collect()
+    }
+
+    fn timeout(&mut self, at: Instant, colors: Vec<(String, HSBK)>) -> Vec<Command> {
+        // a timeout from before the latest motion is stale
+        let idle = at.saturating_duration_since(self.last_motion) >= self.settings.timeout;
+        if !idle
+            || !self.settings.enabled
+            || !matches!(
+                self.state,
+                State::Occupied | State::Vacant | State::Warning { .. }
+            )
+        {
+            return Vec::new();
+        }
+        self.fade_all(at, colors)
+    }
+
+    fn force_fade(&mut self, at: Instant, colors: Vec<(String, HSBK)>) -> Vec<Command> {
+        if matches!(self.state, State::Fading { .. } | State::Off) {
+            return Vec::new();
+        }
+        self.fade_all(at, colors)
+    }
+
+    /// Fade every light in `colors`, going to [`State::Fading`], or [`State::Off`] if all are dark already
+    fn fade_all(&mut self, at: Instant, colors: Vec<(String, HSBK)>) -> Vec<Command> {
+        let commands: Vec<_> = colors
+            .into_iter()
+            .filter_map(|(light, color)| self.fade(light, color, at))
+            .collect();
+        // lights dimmed by the warning that did not answer stay dimmed
+        self.warnings.clear();
+        self.state = if self.fades.is_empty() {
+            State::Off
+        } else {
+            State::Fading { started: at }
+        };
+        commands
+    }
+
+    fn observed(&mut self, light: String, color: HSBK, powered: bool, at: Instant) -> Vec<Command> {
+        match self.state {
+            State::Fading { .. } | State::Off => {
+                let mut commands = Vec::new();
+                if let Some(fade) = self.fades.get_mut(&light) {
+                    if !fade.matches(color, powered, at, self.settings.threshold) {
+                        // changed by someone else, the light is no longer ours to restore
+                        self.fades.remove(&light);
+                        if self.fades.is_empty() {
+                            self.state = State::ManualOverride { since: at };
+                            return Vec::new();
+                        }
+                    } else if fade.power_off && !fade.powered_off && fade.is_done(at) {
+                        fade.powered_off = true;
+                        commands.push(Command::PowerOff { light });
+                    }
+                }
+                if self.fades.values().all(|fade| fade.is_done(at)) {
+                    self.state = State::Off;
+                }
+                commands
+            }
+            State::Warning { .. } => {
+                // changed by someone else, the light is no longer ours to undim
+                let threshold = self.settings.threshold;
+                self.warnings.retain(|name, dim| {
+                    *name != light || dim.matches(color, powered, at, threshold)
+                });
+                Vec::new()
+            }
+            State::ManualOverride { since } => {
+                let left_on = at.saturating_duration_since(since.max(self.last_motion));
+                if left_on < self.settings.timeout || !self.settings.enabled || !powered {
+                    return Vec::new();
+                }
+                let command = self.fade(light, color, at);
+                if command.is_some() {
+                    self.state = State::Fading { started: at };
+                }
+                command.into_iter().collect()
+            }
+            State::Occupied | State::Vacant => Vec::new(),
+        }
+    }
+
+    /// Start fading `light` from `color` at `at`, unless it is already at or below the fade brightness
+    ///
+    /// A light dimmed by the warning fades on from where it is, as if it had been fading since
+    /// before the warning, and is restored to its color from before the warning.
+    fn fade(&mut self, light: String, color: HSBK, at: Instant) -> Option<Command> {
+        let before = match self.warnings.remove(&light) {
+            Some(dim) if dim.matches(color, true, at, self.settings.threshold) => dim.before,
+            _ => color,
+        };
+        if before.brightness <= self.settings.fade_brightness {
+            return None;
+        }
+        let target = fade_to(before, self.settings.fade_brightness);
+        let done = f32::from(before.brightness.saturating_sub(color.brightness))
+            / f32::from(before.brightness - target.brightness);
+        let duration = self.settings.fade_duration;
+        let elapsed = duration.mul_f32(done.min(1.0));
+        let fade = Fade {
+            before,
+            target,
+            started: at.checked_sub(elapsed).unwrap_or(at),
+            duration,
+            power_off: self.settings.power_off,
+            powered_off: false,
+        };
+        self.fades.insert(light.clone(), fade);
+        Some(Command::SetColor {
+            light,
+            color: fade.target,
+            duration: duration - elapsed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::light;
+
+    const WHITE: HSBK = HSBK {
+        hue: 0,
+        saturation: 0,
+        brightness: light::MAX,
+        kelvin: 3500,
+    };
+    const RED: HSBK = HSBK {
+        hue: 0,
+        saturation: light::MAX,
+        brightness: light::MAX,
+        kelvin: 3500,
+    };
+
+    fn settings() -> Settings {
+        Settings {
+            timeout: Duration::from_secs(600),
+            fade_duration: Duration::from_secs(180),
+            fade_brightness: light::MIN,
+            threshold: crate::MATCHING_THRESHOLD,
+            enabled: true,
+            power_off: false,
+            restore_cue: None,
+            warning: None,
+        }
+    }
+
+    fn secs(start: Instant, secs: u64) -> Instant {
+        start + Duration::from_secs(secs)
+    }
+
+    fn timeout(presence: &mut Presence, at: Instant) -> Vec<Command> {
+        presence.handle(Event::Timeout {
+            at,
+            colors: vec![("lamp".to_string(), WHITE)],
+        })
+    }
+
+    fn observed(presence: &mut Presence, color: HSBK, at: Instant) -> Vec<Command> {
+        presence.handle(Event::Observed {
+            light: "lamp".to_string(),
+            color,
+            powered: true,
+            at,
+        })
+    }
+
+    #[test]
+    fn test_motion() {
+        let start = Instant::now();
+        let mut presence = Presence::new(settings(), start);
+        assert_eq!(presence.state(), State::Vacant);
+        let commands = presence.handle(Event::Motion {
+            motion: Motion::On,
+            at: secs(start, 1),
+        });
+        assert!(commands.is_empty());
+        assert_eq!(presence.state(), State::Occupied);
+        presence.handle(Event::Motion {
+            motion: Motion::Off,
+            at: secs(start, 2),
+        });
+        assert_eq!(presence.state(), State::Vacant);
+    }
+
+    #[test]
+    fn test_fade_and_restore() {
+        let start = Instant::now();
+        let mut presence = Presence::new(settings(), start);
+        let commands = timeout(&mut presence, secs(start, 600));
+        assert_eq!(
+            commands,
+            vec![Command::SetColor {
+                light: "lamp".to_string(),
+                color: fade_to(WHITE, light::MIN),
+                duration: Duration::from_secs(180),
+            }]
+        );
+        assert_eq!(
+            presence.state(),
+            State::Fading {
+                started: secs(start, 600)
+            }
+        );
+
+        // halfway through the fade
+        let half = HSBK {
+            brightness: (light::MAX - light::MIN) / 2 + light::MIN,
+            ..WHITE
+        };
+        assert!(observed(&mut presence, half, secs(start, 690)).is_empty());
+        assert!(matches!(presence.state(), State::Fading { .. }));
+        observed(&mut presence, fade_to(WHITE, light::MIN), secs(start, 800));
+        assert_eq!(presence.state(), State::Off);
+
+        let commands = presence.handle(Event::Motion {
+            motion: Motion::On,
+            at: secs(start, 900),
+        });
+        assert!(matches!(
+            &commands[..],
+            [Command::Restore { light, fade }] if light == "lamp" && fade.before == WHITE
+        ));
+        assert_eq!(presence.state(), State::Occupied);
+        assert!(presence.fades().is_empty());
+    }
+
+    #[test]
+    fn test_stale_timeout() {
+        let start = Instant::now();
+        let mut presence = Presence::new(settings(), start);
+        presence.handle(Event::Motion {
+            motion: Motion::Off,
+            at: secs(start, 300),
+        });
+        // timer started before the motion ran out
+        assert!(timeout(&mut presence, secs(start, 600)).is_empty());
+        assert_eq!(presence.state(), State::Vacant);
+        assert_eq!(timeout(&mut presence, secs(start, 900)).len(), 1);
+    }
+
+    #[test]
+    fn test_already_dark() {
+        let start = Instant::now();
+        let mut presence = Presence::new(settings(), start);
+        let commands = presence.handle(Event::Timeout {
+            at: secs(start, 600),
+            colors: vec![("lamp".to_string(), fade_to(WHITE, light::MIN))],
+        });
+        assert!(commands.is_empty());
+        assert_eq!(presence.state(), State::Off);
+    }
+
+    #[test]
+    fn test_manual_override() {
+        let start = Instant::now();
+        let mut presence = Presence::new(settings(), start);
+        timeout(&mut presence, secs(start, 600));
+        // turned red from the app during the fade
+        assert!(observed(&mut presence, RED, secs(start, 660)).is_empty());
+        assert_eq!(
+            presence.state(),
+            State::ManualOverride {
+                since: secs(start, 660)
+            }
+        );
+        // a late timeout does not fade the light again
+        assert!(timeout(&mut presence, secs(start, 700)).is_empty());
+        assert!(observed(&mut presence, RED, secs(start, 1200)).is_empty());
+
+        // left on without motion for the timeout
+        let commands = observed(&mut presence, RED, secs(start, 1260));
+        assert_eq!(
+            commands,
+            vec![Command::SetColor {
+                light: "lamp".to_string(),
+                color: fade_to(RED, light::MIN),
+                duration: Duration::from_secs(180),
+            }]
+        );
+        assert!(matches!(presence.state(), State::Fading { .. }));
+    }
+
+    #[test]
+    fn test_override_ends_with_motion() {
+        let start = Instant::now();
+        let mut presence = Presence::new(settings(), start);
+        timeout(&mut presence, secs(start, 600));
+        observed(&mut presence, RED, secs(start, 660));
+        let commands = presence.handle(Event::Motion {
+            motion: Motion::On,
+            at: secs(start, 700),
+        });
+        assert!(commands.is_empty(), "changed light is not restored");
+        assert_eq!(presence.state(), State::Occupied);
+        // polling while occupied never fades
+        assert!(observed(&mut presence, RED, secs(start, 5000)).is_empty());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let start = Instant::now();
+        let disabled = Settings {
+            enabled: false,
+            ..settings()
+        };
+        let mut presence = Presence::new(disabled, start);
+        assert!(timeout(&mut presence, secs(start, 600)).is_empty());
+        assert_eq!(
+            presence.state(),
+            State::Vacant,
+            "waits for the schedule to end"
+        );
+
+        presence.set_settings(settings());
+        assert_eq!(timeout(&mut presence, secs(start, 700)).len(), 1);
+        observed(&mut presence, RED, secs(start, 760));
+        presence.set_settings(disabled);
+        assert!(observed(&mut presence, RED, secs(start, 2000)).is_empty());
+        assert!(matches!(presence.state(), State::ManualOverride { .. }));
+    }
+
+    #[test]
+    fn test_forced() {
+        let start = Instant::now();
+        let mut presence = Presence::new(
+            Settings {
+                enabled: false,
+                ..settings()
+            },
+            start,
+        );
+        presence.handle(Event::Motion {
+            motion: Motion::On,
+            at: secs(start, 10),
+        });
+        let commands = presence.handle(Event::Fade {
+            at: secs(start, 20),
+            colors: vec![("lamp".to_string(), WHITE)],
+        });
+        assert_eq!(
+            commands.len(),
+            1,
+            "forced before the timeout and while disabled"
+        );
+        assert!(matches!(presence.state(), State::Fading { .. }));
+
+        let commands = presence.handle(Event::Restore {
+            at: secs(start, 30),
+        });
+        assert!(matches!(&commands[..], [Command::Restore { .. }]));
+        assert_eq!(presence.state(), State::Vacant);
+        assert_eq!(presence.last_motion(), secs(start, 30));
+    }
+
+    #[test]
+    fn test_power_off() {
+        let start = Instant::now();
+        let settings = Settings {
+            power_off: true,
+            ..settings()
+        };
+        let faded = fade_to(WHITE, settings.fade_brightness);
+        let observe = |presence: &mut Presence, powered: bool, at: Instant| {
+            presence.handle(Event::Observed {
+                light: "lamp".to_string(),
+                color: faded,
+                powered,
+                at,
+            })
+        };
+        let mut presence = Presence::new(settings, start);
+        timeout(&mut presence, secs(start, 600));
+        assert!(observe(&mut presence, true, secs(start, 700)).is_empty());
+        assert_eq!(
+            observe(&mut presence, true, secs(start, 800)),
+            vec![Command::PowerOff {
+                light: "lamp".to_string()
+            }]
+        );
+        assert_eq!(presence.state(), State::Off);
+        assert!(observe(&mut presence, false, secs(start, 900)).is_empty());
+        assert_eq!(presence.state(), State::Off);
+
+        let commands = presence.handle(Event::Motion {
+            motion: Motion::On,
+            at: secs(start, 1000),
+        });
+        assert!(matches!(
+            &commands[..],
+            [Command::Restore { fade, .. }] if fade.powered_off && fade.before == WHITE
+        ));
+
+        // switched on by hand after powering off
+        let mut presence = Presence::new(settings, start);
+        timeout(&mut presence, secs(start, 600));
+        observe(&mut presence, true, secs(start, 800));
+        assert!(observe(&mut presence, true, secs(start, 900)).is_empty());
+        assert!(matches!(presence.state(), State::ManualOverride { .. }));
+    }
+
+    #[test]
+    fn test_warning() {
+        let start = Instant::now();
+        let settings = Settings {
+            warning: Some(Warning {
+                before: Duration::from_secs(30),
+                dim: 0.2,
+                cue: None,
+            }),
+            ..settings()
+        };
+        let warning = |presence: &mut Presence, at: Instant| {
+            presence.handle(Event::Warning {
+                at,
+                colors: vec![("lamp".to_string(), WHITE)],
+            })
+        };
+        let mut presence = Presence::new(settings, start);
+        assert!(warning(&mut presence, secs(start, 500)).is_empty(), "stale");
+        let dimmed = fade_to(WHITE, (f32::from(light::MAX) * 0.8) as u16);
+        assert_eq!(
+            warning(&mut presence, secs(start, 570)),
+            vec![Command::Dim {
+                light: "lamp".to_string(),
+                color: dimmed,
+                duration: WARNING_DURATION,
+            }]
+        );
+        assert_eq!(
+            presence.state(),
+            State::Warning {
+                started: secs(start, 570)
+            }
+        );
+
+        // motion during the warning undims at once
+        let commands = presence.handle(Event::Motion {
+            motion: Motion::Off,
+            at: secs(start, 580),
+        });
+        assert!(matches!(
+            &commands[..],
+            [Command::Undim { light, dim }] if light == "lamp" && dim.before == WHITE
+        ));
+        assert_eq!(presence.state(), State::Vacant);
+        assert!(timeout(&mut presence, secs(start, 600)).is_empty());
+
+        // the fade goes on from the dimmed light
+        warning(&mut presence, secs(start, 1150));
+        assert!(observed(&mut presence, dimmed, secs(start, 1170)).is_empty());
+        let commands = presence.handle(Event::Timeout {
+            at: secs(start, 1180),
+            colors: vec![("lamp".to_string(), dimmed)],
+        });
+        let [Command::SetColor {
+            color, duration, ..
+        }] = &commands[..]
+        else {
+            panic!("expected a fade, got {:?}", commands);
+        };
+        assert_eq!(*color, fade_to(WHITE, light::MIN));
+        assert!(*duration < Duration::from_secs(150), "{:?}", duration);
+        assert_eq!(presence.fades()["lamp"].before, WHITE);
+        assert!(presence.warnings().is_empty());
+        assert!(observed(&mut presence, dimmed, secs(start, 1180)).is_empty());
+        assert!(matches!(presence.state(), State::Fading { .. }));
+    }
+
+    #[test]
+    fn test_resume_fades() {
+        let start = Instant::now();
+        let fade = Fade {
+            before: WHITE,
+            target: fade_to(WHITE, settings().fade_brightness),
+            started: start,
+            duration: Duration::from_secs(10),
+            power_off: false,
+            powered_off: false,
+        };
+        let mut presence = Presence::new(settings(), secs(start, 5));
+        presence.resume_fades(HashMap::new(), secs(start, 5));
+        assert_eq!(presence.state(), State::Vacant);
+        presence.resume_fades(HashMap::from([("lamp".to_string(), fade)]), secs(start, 5));
+        assert_eq!(presence.state(), State::Fading { started: start });
+
+        let mut presence = Presence::new(settings(), secs(start, 60));
+        presence.resume_fades(HashMap::from([("lamp".to_string(), fade)]), secs(start, 60));
+        assert_eq!(presence.state(), State::Off);
+        assert!(timeout(&mut presence, secs(start, 600)).is_empty());
+        let commands = presence.handle(Event::Motion {
+            motion: Motion::On,
+            at: secs(start, 700),
+        });
+        assert!(matches!(
+            &commands[..],
+            [Command::Restore { light, fade }] if light == "lamp" && fade.before == WHITE
+        ));
+    }
+}
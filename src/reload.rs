@@ -0,0 +1,191 @@
+//! Watch the configuration file and reload it when it changes or on `SIGHUP`
+//!
+//! The file is polled instead of using inotify, since editors often replace the file instead of
+//! writing to it. A reloaded configuration that fails to parse or validate is logged and ignored,
+//! the running configuration is kept.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use signal_hook::consts::SIGHUP;
+
+use crate::config::{Config, ConfigChange};
+
+/// Interval the configuration file is checked for changes
+pub const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Thread watching a configuration file, stopped when dropped
+#[derive(Debug)]
+pub struct ConfigWatcher {
+    thread: Option<JoinHandle<()>>,
+    running: Arc<AtomicBool>,
+}
+
+impl ConfigWatcher {
+    /// Watch the file at `path`, calling `on_change` with the new configuration and its changes
+    /// compared to the previous configuration, starting from `current`.
+    ///
+    /// The file is checked every `interval`, or as soon as `SIGHUP` is received.
+    pub fn spawn<F>(
+        path: PathBuf,
+        current: Config,
+        interval: Duration,
+        mut on_change: F,
+    ) -> Result<Self, io::Error>
+    where
+        F: 'static + FnMut(&Config, &[ConfigChange]) + Send,
+    {
+        let hangup = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(SIGHUP, hangup.clone())?;
+
+        let running = Arc::new(AtomicBool::new(true));
+        let running_inner = running.clone();
+        let mut contents = fs::read_to_string(&path).ok();
+        let mut current = current;
+
+        let thread = thread::Builder::new()
+            .name("config_watcher".to_string())
+            .spawn(move || {
+                // sleep in short steps so a hangup is handled quickly
+                let step = interval.min(Duration::from_millis(100));
+                let mut waited = Duration::ZERO;
+                while running_inner.load(Ordering::Relaxed) {
+                    thread::sleep(step);
+                    waited += step;
+                    let forced = hangup.swap(false, Ordering::Relaxed);
+                    if !forced && waited < interval {
+                        continue;
+                    }
+                    waited = Duration::ZERO;
+
+                    let new_contents = match fs::read_to_string(&path) {
+                        Ok(new_contents) => new_contents,
+                        Err(err) => {
+                            tracing::warn!(
+                                "could not read config file {}: {}",
+                                path.display(),
+                                err
+                            );
+                            continue;
+                        }
+                    };
+                    if !forced && contents.as_ref() == Some(&new_contents) {
+                        continue;
+                    }
+                    contents = Some(new_contents.clone());
+
+                    let new = match new_contents.parse::<Config>() {
+                        Ok(new) => new,
+                        Err(err) => {
+                            tracing::error!("keeping running config, reload failed: {}", err);
+                            continue;
+                        }
+                    };
+                    let changes = current.diff(&new);
+                    tracing::info!(
+                        "reloaded config from {} with {} changes",
+                        path.display(),
+                        changes.len()
+                    );
+                    if !changes.is_empty() {
+                        on_change(&new, &changes);
+                    }
+                    current = new;
+                }
+            })?;
+
+        Ok(Self {
+            thread: Some(thread),
+            running,
+        })
+    }
+}
+
+impl Drop for ConfigWatcher {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    const EXAMPLE: &str = include_str!("../motion_sensor_lifx.toml");
+
+    fn temp_config(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "motion_sensor_lifx_{}_{}.toml",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, EXAMPLE).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_reload_on_change() {
+        let path = temp_config("change");
+        let config: Config = EXAMPLE.parse().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let _watcher = ConfigWatcher::spawn(
+            path.clone(),
+            config,
+            Duration::from_millis(50),
+            move |new, changes| sender.send((new.clone(), changes.to_vec())).unwrap(),
+        )
+        .unwrap();
+
+        // invalid config is ignored
+        fs::write(&path, "rooms = 5").unwrap();
+        thread::sleep(Duration::from_millis(200));
+        fs::write(&path, EXAMPLE.replace("timeout = 600", "timeout = 60")).unwrap();
+
+        let (new, changes) = receiver.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert_eq!(new.rooms[0].timeout, Duration::from_secs(60));
+        assert_eq!(
+            changes,
+            vec![ConfigChange::RoomTimeout {
+                room: "bedroom".to_string(),
+                timeout: Duration::from_secs(60)
+            }]
+        );
+        assert!(receiver.try_recv().is_err(), "only one reload");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_reload_on_hangup() {
+        let path = temp_config("hangup");
+        let config: Config = EXAMPLE.parse().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        // watch interval is too long to notice the change without a hangup
+        let _watcher = ConfigWatcher::spawn(
+            path.clone(),
+            config,
+            Duration::from_secs(60),
+            move |new, _changes| sender.send(new.clone()).unwrap(),
+        )
+        .unwrap();
+
+        fs::write(
+            &path,
+            EXAMPLE.replace("fade_duration = 180", "fade_duration = 5"),
+        )
+        .unwrap();
+        signal_hook::low_level::raise(SIGHUP).unwrap();
+
+        let new = receiver.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert_eq!(new.rooms[0].fade_duration, Duration::from_secs(5));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,1425 @@
+//! A room binding any number of motion sensors to any number of lights, with its own [`Timer`]
+//!
+//! Motion, timeouts and polled colors are fed to the room's [`Presence`] state machine, and the
+//! commands it answers with are applied to the lights. Events are handled one at a time, so the
+//! timer, the sensors and the poll can not interleave their changes to the lights.
+//!
+//! Active [schedules](crate::schedule) override the settings of the room from the config file,
+//! they are applied with [`Room::apply_schedules`].
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::NaiveDateTime;
+use lifx_core::HSBK;
+use tracing::{debug, error, info, info_span, warn};
+
+use crate::clock::{self, Clock};
+use crate::config::{RoomConfig, Timings, WARNING_MILESTONE};
+use crate::events::{DaemonEvent, Events, FadeOutcome};
+use crate::light::{LightError, Pattern};
+use crate::motion::MotionEvent;
+use crate::presence::{Command, Event, Fade, Presence, Settings, State};
+use crate::schedule::{self, Overrides, ScheduleConfig};
+use crate::{Light, Milestone, Timer, ACTION, SIGNAL};
+
+/// Lights with their names from the config file
+pub type Lights = Vec<(String, Light<SocketAddr>)>;
+
+/// Running rooms, shared between the daemon and the API and bridges so reloaded rooms show up there
+pub type Rooms = Arc<Mutex<Vec<Arc<Room>>>>;
+
+/// Every light of `rooms`, once per name
+pub fn all_lights(rooms: &[Arc<Room>]) -> Lights {
+    let mut lights: Lights = Vec::new();
+    for (name, light) in rooms.iter().flat_map(|room| room.lights()) {
+        if !lights.iter().any(|(known, _)| *known == name) {
+            lights.push((name, light));
+        }
+    }
+    lights
+}
+
+/// Run `operation` on light `name` of `rooms` while no room with the light handles an event, `None` if no room has it
+///
+/// Requests from the API and bridges go through here, so they can not interleave with fades and
+/// restores of the rooms.
+pub fn with_light<T, F>(rooms: &[Arc<Room>], name: &str, operation: F) -> Option<T>
+where
+    F: FnOnce(&Light<SocketAddr>) -> T,
+{
+    let rooms: Vec<&Arc<Room>> = rooms
+        .iter()
+        .filter(|room| room.lights().iter().any(|(light, _)| light == name))
+        .collect();
+    let (_, light) = rooms
+        .first()?
+        .lights()
+        .into_iter()
+        .find(|(light, _)| light == name)?;
+    // locked in the order of `rooms`, so callers locking several rooms can not deadlock
+    let _handling: Vec<_> = rooms
+        .iter()
+        .map(|room| room.inner.handling.lock().unwrap())
+        .collect();
+    Some(operation(&light))
+}
+
+/// Number of times a failed light operation is run before giving up
+pub const RECOVERY_ATTEMPTS: u32 = 3;
+/// Delay before running a failed light operation again, doubled for every attempt
+pub const RECOVERY_DELAY: Duration = Duration::from_secs(1);
+/// Duration of restoring a light to its color from before the fade
+const RESTORE_DURATION: Duration = Duration::from_millis(100);
+
+/// Milestones of the room timer, the milestones of `config` and the warning of `settings`
+fn milestones(config: &RoomConfig, settings: &Settings) -> Vec<Milestone> {
+    let warning = settings
+        .warning
+        .filter(|warning| warning.before < settings.timeout)
+        .map(|warning| Milestone {
+            name: WARNING_MILESTONE.into(),
+            after: settings.timeout - warning.before,
+        });
+    config
+        .milestones
+        .iter()
+        .map(|milestone| Milestone {
+            name: milestone.name.as_str().into(),
+            after: milestone.after,
+        })
+        .chain(warning)
+        .collect()
+}
+
+/// Run light `operation`, retrying transient errors after waiting on `clock` and logging the error instead of panicking if it keeps failing
+pub fn recover<F>(clock: &dyn Clock, what: &str, mut operation: F)
+where
+    F: FnMut() -> Result<(), LightError>,
+{
+    let mut delay = RECOVERY_DELAY;
+    for attempt in 1..=RECOVERY_ATTEMPTS {
+        match operation() {
+            Ok(()) => return,
+            Err(err) if err.is_transient() && attempt < RECOVERY_ATTEMPTS => {
+                warn!(attempt, "{} failed: {}, retrying in {:?}", what, err, delay);
+                clock.sleep(delay);
+                delay *= 2;
+            }
+            Err(err) => {
+                error!("{} failed, giving up: {}", what, err);
+                return;
+            }
+        }
+    }
+}
+
+/// Settings and lights of a room, replaced when the config file is reloaded
+#[derive(Debug)]
+struct Shared {
+    config: RoomConfig,
+    timings: Timings,
+    /// Settings changed by the active schedules
+    overrides: Overrides,
+    /// Motion control paused through the API, until resumed
+    paused: bool,
+    /// Timeout set through the API, taking precedence over schedules and the config file
+    timeout: Option<Duration>,
+    /// Timer frozen through the API, until unfrozen
+    frozen: bool,
+    lights: Lights,
+}
+
+impl Shared {
+    /// Settings of the room with the active schedules and the API settings applied
+    fn settings(&self) -> Settings {
+        let settings = Settings::new(&self.overrides.apply(&self.config), &self.timings);
+        Settings {
+            enabled: self.overrides.enabled() && !self.paused,
+            timeout: self.timeout.unwrap_or(settings.timeout),
+            ..settings
+        }
+    }
+}
+
+/// State of a room shared with its timer thread
+#[derive(Debug)]
+struct Inner {
+    name: String,
+    shared: Mutex<Shared>,
+    /// Locked while an event is handled and its commands are applied, including retries
+    handling: Mutex<()>,
+    /// Locked while the state machine handles an event, not while the lights are changed
+    presence: Mutex<Presence>,
+    clock: Arc<dyn Clock>,
+    /// Zones or pixels from before the fade of faded multizone and matrix lights, restored instead of one color
+    patterns: Mutex<HashMap<String, Pattern>>,
+    events: Events,
+}
+
+impl Inner {
+    fn lights(&self) -> Lights {
+        self.shared.lock().unwrap().lights.clone()
+    }
+
+    /// Handle the event made by `event` from the room's lights, then apply the commands
+    fn handle<F>(&self, event: F)
+    where
+        F: FnOnce(&Lights) -> Vec<Event>,
+    {
+        let _span = info_span!("room", name = %self.name).entered();
+        let _handling = self.handling.lock().unwrap();
+        let lights = self.lights();
+        for event in event(&lights) {
+            let (commands, settings) = {
+                let mut presence = self.presence.lock().unwrap();
+                let before = presence.state();
+                let mut fading: Vec<String> = presence.fades().keys().cloned().collect();
+                let commands = presence.handle(event);
+                if presence.state() != before {
+                    info!(from = %before, to = %presence.state(), "state changed");
+                    self.changed();
+                }
+                // fades dropped without a restore were given up on because the light changed
+                fading.retain(|light| {
+                    !presence.fades().contains_key(light)
+                        && !commands.iter().any(
+                            |command| matches!(command, Command::Restore { light: restored, .. } if restored == light),
+                        )
+                });
+                for light in fading {
+                    self.faded(&light, FadeOutcome::Aborted);
+                }
+                (commands, presence.settings())
+            };
+            // the presence lock is released, a light retried for seconds does not hold up the status
+            self.apply(&lights, commands, settings);
+            let presence = self.presence.lock().unwrap();
+            self.patterns.lock().unwrap().retain(|light, _| {
+                presence.fades().contains_key(light) || presence.warnings().contains_key(light)
+            });
+        }
+    }
+
+    /// Fade `light` to `color`, or every zone or pixel of a multizone or matrix light to the brightness of `color` keeping its colors
+    fn fade_light(
+        &self,
+        name: &str,
+        light: &Light<SocketAddr>,
+        color: HSBK,
+        duration: Duration,
+    ) -> Result<(), LightError> {
+        let Some(pattern) = light.pattern()? else {
+            return light.set_color(color, duration);
+        };
+        let faded = pattern.dimmed(color.brightness);
+        // a retried fade reads the pattern while fading, keep the first one
+        self.patterns
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_insert(pattern);
+        light.set_pattern(&faded, duration)
+    }
+
+    /// Restore `light` to `before` over `duration`, or to its zones or pixels from before the fade if it is a multizone or matrix light
+    fn restore_light(
+        &self,
+        name: &str,
+        light: &Light<SocketAddr>,
+        before: HSBK,
+        duration: Duration,
+    ) -> Result<(), LightError> {
+        let pattern = self.patterns.lock().unwrap().get(name).cloned();
+        match pattern {
+            Some(pattern) => light.set_pattern(&pattern, duration),
+            None => light.set_color(before, duration),
+        }
+    }
+
+    /// Publish `outcome` of the fade of `light`
+    fn faded(&self, light: &str, outcome: FadeOutcome) {
+        self.events.publish(DaemonEvent::Fade {
+            room: self.name.clone(),
+            light: light.to_string(),
+            outcome,
+        });
+    }
+
+    /// Run `request` to light `name`, publishing how long it took and if it failed
+    fn request<T, F>(&self, name: &str, request: F) -> Result<T, LightError>
+    where
+        F: FnOnce() -> Result<T, LightError>,
+    {
+        let started = Instant::now();
+        let result = request();
+        self.events.publish(DaemonEvent::Request {
+            light: name.to_string(),
+            latency: started.elapsed(),
+            failed: result.is_err(),
+        });
+        result
+    }
+
+    /// Current color and power of every light that answers, lights that do not are left out
+    fn states(&self, lights: &Lights) -> Vec<(String, HSBK, bool)> {
+        lights
+            .iter()
+            .filter_map(|(name, light)| {
+                let mut state = None;
+                recover(&*self.clock, "Read color", || {
+                    state = Some(self.request(name, || light.state())?);
+                    Ok(())
+                });
+                let (color, powered) = state?;
+                self.events.publish(DaemonEvent::Observed {
+                    light: name.clone(),
+                    color,
+                    powered,
+                });
+                Some((name.clone(), color, powered))
+            })
+            .collect()
+    }
+
+    /// Current color of every light that answers and is powered on, the lights to fade
+    fn colors(&self, lights: &Lights) -> Vec<(String, HSBK)> {
+        self.states(lights)
+            .into_iter()
+            .filter(|(_, _, powered)| *powered)
+            .map(|(name, color, _)| (name, color))
+            .collect()
+    }
+
+    /// Publish that the state or settings of the room changed
+    fn changed(&self) {
+        self.events.publish(DaemonEvent::Room {
+            room: self.name.clone(),
+        });
+    }
+
+    fn apply(&self, lights: &Lights, commands: Vec<Command>, settings: Settings) {
+        for command in commands {
+            let name = match &command {
+                Command::SetColor { light, .. }
+                | Command::Restore { light, .. }
+                | Command::PowerOff { light }
+                | Command::Dim { light, .. }
+                | Command::Undim { light, .. } => light,
+            };
+            let Some((_, light)) = lights.iter().find(|(light, _)| light == name) else {
+                continue;
+            };
+            let color = match command {
+                Command::SetColor {
+                    color, duration, ..
+                } => {
+                    self.faded(name, FadeOutcome::Started);
+                    recover(&*self.clock, "Fade", || {
+                        self.request(name, || self.fade_light(name, light, color, duration))
+                    });
+                    Some(color)
+                }
+                Command::Restore { fade, .. } => {
+                    let mut outcome = None;
+                    let mut restored = None;
+                    recover(&*self.clock, "Restore", || {
+                        self.request(name, || {
+                            let (color, powered) = light.state()?;
+                            if !fade.matches(color, powered, self.clock.now(), settings.threshold)
+                            {
+                                info!(light = %name, "light changed during fade or off, leaving it");
+                                outcome = Some(FadeOutcome::Aborted);
+                                restored = Some(color);
+                                return Ok(());
+                            }
+                            info!(light = %name, "restoring light from faded state");
+                            outcome = Some(FadeOutcome::Restored);
+                            restored = Some(fade.before);
+                            // a wave changes the whole light, it would flatten zones and pixels
+                            let cue = settings
+                                .restore_cue
+                                .filter(|_| !self.patterns.lock().unwrap().contains_key(name));
+                            // the cue starts from the restored color instead of halfway the restore
+                            let duration = if cue.is_some() {
+                                Duration::ZERO
+                            } else {
+                                RESTORE_DURATION
+                            };
+                            self.restore_light(name, light, fade.before, duration)?;
+                            if !powered {
+                                light.set_power(true, duration)?;
+                            }
+                            if let Some(cue) = &cue {
+                                light.set_waveform(cue)?;
+                            }
+                            Ok(())
+                        })
+                    });
+                    if let Some(outcome) = outcome {
+                        self.faded(name, outcome);
+                    }
+                    restored
+                }
+                Command::PowerOff { .. } => {
+                    info!(light = %name, "powering off faded light");
+                    recover(&*self.clock, "Power off", || {
+                        self.request(name, || light.set_power(false, Duration::ZERO))
+                    });
+                    None
+                }
+                Command::Dim {
+                    color, duration, ..
+                } => {
+                    info!(light = %name, "dimming light as a warning");
+                    let cue = settings.warning.and_then(|warning| warning.cue);
+                    recover(&*self.clock, "Dim", || {
+                        self.request(name, || {
+                            self.fade_light(name, light, color, duration)?;
+                            // a wave changes the whole light, it would flatten zones and pixels
+                            match &cue {
+                                Some(cue) if !self.patterns.lock().unwrap().contains_key(name) => {
+                                    light.set_waveform(cue)
+                                }
+                                _ => Ok(()),
+                            }
+                        })
+                    });
+                    Some(color)
+                }
+                Command::Undim { dim, .. } => {
+                    let mut undimmed = None;
+                    recover(&*self.clock, "Undim", || {
+                        self.request(name, || {
+                            let (color, powered) = light.state()?;
+                            if !dim.matches(color, powered, self.clock.now(), settings.threshold) {
+                                info!(light = %name, "light changed during warning, leaving it");
+                                undimmed = Some(color);
+                                return Ok(());
+                            }
+                            info!(light = %name, "undimming light after warning");
+                            undimmed = Some(dim.before);
+                            self.restore_light(name, light, dim.before, RESTORE_DURATION)
+                        })
+                    });
+                    undimmed
+                }
+            };
+            self.events.publish(DaemonEvent::Light {
+                light: name.clone(),
+                color,
+            });
+        }
+    }
+}
+
+/// Sensors and lights controlled together by one timer
+#[derive(Debug)]
+pub struct Room {
+    inner: Arc<Inner>,
+    timer: Timer,
+}
+
+impl Room {
+    /// Create a room with its settings from `config` and connected `lights`, the timer starts right away
+    pub fn new(config: RoomConfig, timings: Timings, lights: Lights) -> Self {
+        Self::with_clock(config, timings, lights, clock::real())
+    }
+
+    /// Create a room like [`Room::new`] with its timer and fades running on `clock`
+    pub fn with_clock(
+        config: RoomConfig,
+        timings: Timings,
+        lights: Lights,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self::with_events(config, timings, lights, clock, Events::new())
+    }
+
+    /// Create a room like [`Room::with_clock`] publishing its timer, state and light changes to `events`
+    pub fn with_events(
+        config: RoomConfig,
+        timings: Timings,
+        lights: Lights,
+        clock: Arc<dyn Clock>,
+        events: Events,
+    ) -> Self {
+        let milestones = milestones(&config, &Settings::new(&config, &timings));
+        let inner = Arc::new(Inner {
+            name: config.name.clone(),
+            handling: Mutex::new(()),
+            presence: Mutex::new(Presence::new(Settings::new(&config, &timings), clock.now())),
+            shared: Mutex::new(Shared {
+                config,
+                timings,
+                overrides: Overrides::default(),
+                paused: false,
+                timeout: None,
+                frozen: false,
+                lights,
+            }),
+            patterns: Mutex::new(HashMap::new()),
+            clock: clock.clone(),
+            events,
+        });
+        let settings = inner.presence.lock().unwrap().settings();
+        let inner_timer = inner.clone();
+        let timer = Timer::with_clock(settings.timeout, clock, move |action| {
+            inner_timer.events.publish(DaemonEvent::Timer {
+                room: inner_timer.name.clone(),
+                action: action.clone(),
+            });
+            let _span = info_span!("room", name = %inner_timer.name).entered();
+            match action {
+                ACTION::START { restarted } => debug!(restarted, "timer started"),
+                ACTION::MILESTONE { name } if &*name == WARNING_MILESTONE => {
+                    info!("warning before the timeout");
+                    inner_timer.handle(|lights| {
+                        vec![Event::Warning {
+                            at: inner_timer.clock.now(),
+                            colors: inner_timer.colors(lights),
+                        }]
+                    });
+                }
+                ACTION::MILESTONE { name } => info!(%name, "timer milestone reached"),
+                ACTION::TIMEOUT => {
+                    info!("timed out");
+                    inner_timer.handle(|lights| {
+                        vec![Event::Timeout {
+                            at: inner_timer.clock.now(),
+                            colors: inner_timer.colors(lights),
+                        }]
+                    });
+                }
+            }
+        });
+        timer.set_milestones(milestones).unwrap();
+        Self { inner, timer }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    /// Settings of the room from the config file, see [`Room::settings`] for the settings in use
+    pub fn config(&self) -> RoomConfig {
+        self.inner.shared.lock().unwrap().config.clone()
+    }
+
+    /// Settings in use, with the active schedules applied
+    pub fn settings(&self) -> Settings {
+        self.inner.presence.lock().unwrap().settings()
+    }
+
+    /// Settings changed by the active schedules
+    pub fn overrides(&self) -> Overrides {
+        self.inner.shared.lock().unwrap().overrides.clone()
+    }
+
+    /// Current lights of the room
+    pub fn lights(&self) -> Lights {
+        self.inner.lights()
+    }
+
+    pub fn timer(&self) -> &Timer {
+        &self.timer
+    }
+
+    /// Clock the room's timer and fades run on
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.inner.clock
+    }
+
+    /// Current state of the room's state machine
+    pub fn state(&self) -> State {
+        self.inner.presence.lock().unwrap().state()
+    }
+
+    /// If motion from the sensor with name `sensor` applies to this room
+    pub fn has_sensor(&self, sensor: &str) -> bool {
+        self.inner
+            .shared
+            .lock()
+            .unwrap()
+            .config
+            .sensors
+            .iter()
+            .any(|name| name == sensor)
+    }
+
+    /// If any light has been faded and not restored yet
+    pub fn is_fading(&self) -> bool {
+        !self.inner.presence.lock().unwrap().fades().is_empty()
+    }
+
+    /// Fade per light name of the lights that are fading or faded
+    pub fn fades(&self) -> HashMap<String, Fade> {
+        self.inner.presence.lock().unwrap().fades().clone()
+    }
+
+    /// Zones or pixels from before the fade per light name, of the faded multizone and matrix lights
+    pub fn patterns(&self) -> HashMap<String, Pattern> {
+        self.inner.patterns.lock().unwrap().clone()
+    }
+
+    /// Take over `fades` and `patterns` of the room's lights from before a restart, so motion restores them, see [`crate::state`]
+    pub fn resume_fades(
+        &self,
+        mut fades: HashMap<String, Fade>,
+        mut patterns: HashMap<String, Pattern>,
+    ) {
+        let lights = self.lights();
+        fades.retain(|light, _| lights.iter().any(|(name, _)| name == light));
+        if fades.is_empty() {
+            return;
+        }
+        patterns.retain(|light, _| fades.contains_key(light));
+        *self.inner.patterns.lock().unwrap() = patterns;
+        info!(room = %self.inner.name, lights = ?fades.keys(), "resuming fades");
+        self.inner
+            .presence
+            .lock()
+            .unwrap()
+            .resume_fades(fades, self.inner.clock.now());
+        self.inner.changed();
+    }
+
+    /// Handle motion from one of the room's sensors, restarting the timer
+    pub fn motion(&self, event: &MotionEvent) {
+        self.inner.handle(|_| {
+            vec![Event::Motion {
+                motion: event.motion,
+                at: event.timestamp,
+            }]
+        });
+        self.timer.start().unwrap();
+    }
+
+    /// Read the colors of the lights, to notice lights changed during a fade or left on without motion
+    pub fn poll(&self) {
+        self.inner.handle(|lights| {
+            self.inner
+                .states(lights)
+                .into_iter()
+                .map(|(light, color, powered)| Event::Observed {
+                    light,
+                    color,
+                    powered,
+                    at: self.inner.clock.now(),
+                })
+                .collect()
+        });
+    }
+
+    /// Replace settings, timings and lights after the config file is reloaded, keeping the fade state
+    pub fn update(&self, config: RoomConfig, timings: Timings, lights: Lights) {
+        {
+            let mut shared = self.inner.shared.lock().unwrap();
+            shared.config = config;
+            shared.timings = timings;
+            shared.lights = lights;
+        }
+        self.retune();
+    }
+
+    /// Apply the `schedules` active for this room at local time `at`
+    pub fn apply_schedules(&self, schedules: &[ScheduleConfig], at: NaiveDateTime) {
+        self.set_overrides(schedule::overrides(schedules, self.name(), at));
+    }
+
+    /// Replace the settings changed by schedules, restarting the timer if motion control is enabled again
+    pub fn set_overrides(&self, overrides: Overrides) {
+        {
+            let mut shared = self.inner.shared.lock().unwrap();
+            if shared.overrides == overrides {
+                return;
+            }
+            info!(room = %self.inner.name, "now using {}", overrides);
+            shared.overrides = overrides;
+        }
+        self.retune();
+    }
+
+    /// If motion control has been paused with [`Room::pause`]
+    pub fn is_paused(&self) -> bool {
+        self.inner.shared.lock().unwrap().paused
+    }
+
+    /// Pause motion control, leaving the lights alone until [`Room::resume`]
+    pub fn pause(&self) {
+        self.set_paused(true);
+    }
+
+    /// Resume motion control paused with [`Room::pause`], counting down to the timeout from now
+    pub fn resume(&self) {
+        self.set_paused(false);
+    }
+
+    fn set_paused(&self, paused: bool) {
+        self.inner.shared.lock().unwrap().paused = paused;
+        info!(
+            room = %self.inner.name,
+            "{}",
+            if paused { "paused" } else { "resumed" }
+        );
+        self.retune();
+    }
+
+    /// Instant of the latest motion, or of the start if there was none
+    pub fn last_motion(&self) -> Instant {
+        self.inner.presence.lock().unwrap().last_motion()
+    }
+
+    /// Timeout set with [`Room::set_timeout`]
+    pub fn timeout_override(&self) -> Option<Duration> {
+        self.inner.shared.lock().unwrap().timeout
+    }
+
+    /// Use `timeout` instead of the timeout from schedules and the config file, until set to `None`
+    pub fn set_timeout(&self, timeout: Option<Duration>) {
+        self.inner.shared.lock().unwrap().timeout = timeout;
+        info!(room = %self.inner.name, ?timeout, "timeout set");
+        self.retune();
+    }
+
+    /// Time left until the timer runs out, frozen with the timer, `None` if it is not running
+    pub fn remaining(&self) -> Option<Duration> {
+        self.timer.remaining()
+    }
+
+    /// If the timer has been frozen with [`Room::freeze`]
+    pub fn is_frozen(&self) -> bool {
+        self.inner.shared.lock().unwrap().frozen
+    }
+
+    /// Freeze the timer, like during a movie, so the lights do not fade until [`Room::unfreeze`]
+    ///
+    /// Unlike [`Room::pause`] motion is still handled, it restarts the frozen countdown.
+    pub fn freeze(&self) {
+        self.set_frozen(true);
+    }
+
+    /// Continue the countdown frozen with [`Room::freeze`] where it was
+    pub fn unfreeze(&self) {
+        self.set_frozen(false);
+    }
+
+    fn set_frozen(&self, frozen: bool) {
+        self.inner.shared.lock().unwrap().frozen = frozen;
+        info!(
+            room = %self.inner.name,
+            "{}",
+            if frozen { "frozen" } else { "unfrozen" }
+        );
+        if frozen {
+            self.timer.pause().unwrap();
+        } else {
+            self.timer.resume().unwrap();
+        }
+        self.inner.changed();
+    }
+
+    /// Push the timeout back `by`, if the timer has not run out
+    pub fn extend(&self, by: Duration) {
+        info!(room = %self.inner.name, ?by, "timeout extended");
+        self.timer.extend(by).unwrap();
+        self.inner.changed();
+    }
+
+    /// Fade the lights now, without waiting for the timeout
+    pub fn force_timeout(&self) {
+        self.inner.handle(|lights| {
+            vec![Event::Fade {
+                at: self.inner.clock.now(),
+                colors: self.inner.colors(lights),
+            }]
+        });
+    }
+
+    /// Restore the faded lights now, restarting the timer as if motion just ended
+    pub fn restore(&self) {
+        self.inner.handle(|_| {
+            vec![Event::Restore {
+                at: self.inner.clock.now(),
+            }]
+        });
+        self.timer.start().unwrap();
+    }
+
+    /// Pass the settings in use to the timer and state machine
+    fn retune(&self) {
+        let (settings, milestones) = {
+            let shared = self.inner.shared.lock().unwrap();
+            let settings = shared.settings();
+            (settings, milestones(&shared.config, &settings))
+        };
+        if let Err(err) = self.timer.set_timeout(settings.timeout) {
+            error!(room = %self.inner.name, "could not set timeout: {}", err);
+        }
+        if let Err(err) = self.timer.set_milestones(milestones) {
+            error!(room = %self.inner.name, "could not set milestones: {}", err);
+        }
+        let (was_enabled, warning) = {
+            let mut presence = self.inner.presence.lock().unwrap();
+            let was_enabled = presence.settings().enabled;
+            presence.set_settings(settings);
+            (
+                was_enabled,
+                matches!(presence.state(), State::Warning { .. }),
+            )
+        };
+        if settings.enabled && !was_enabled {
+            // timeouts while disabled were ignored, count down from now
+            self.timer.start().unwrap();
+        } else if !settings.enabled && warning {
+            // the lights will not fade, take back the warning
+            self.restore();
+        }
+        self.inner.changed();
+    }
+}
+
+impl Drop for Room {
+    fn drop(&mut self) {
+        // the timer thread holds the shared state, stop it with the room
+        let _ = self.timer.signal(SIGNAL::TERMINATE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::config::{MilestoneConfig, WarningConfig};
+    use crate::cue::CueConfig;
+    use crate::fake::FakeBulb;
+    use crate::light;
+    use crate::motion::Motion;
+    use crate::{fade_to, FADE_DURATION, TIMEOUT};
+    use lifx_core::Message;
+    use std::sync::mpsc;
+    use std::thread;
+
+    fn config(timeout: Duration) -> RoomConfig {
+        RoomConfig::test("bedroom", timeout)
+    }
+
+    fn room(bulb: &FakeBulb, timeout: Duration) -> Room {
+        let lights = vec![("lamp".to_string(), bulb.light().unwrap())];
+        Room::new(config(timeout), Timings::default(), lights)
+    }
+
+    fn mock_room(bulb: &FakeBulb, clock: &Arc<MockClock>, config: RoomConfig) -> Room {
+        let lights = vec![("lamp".to_string(), bulb.light().unwrap())];
+        Room::with_clock(config, Timings::default(), lights, clock.clone())
+    }
+
+    fn motion(clock: &MockClock, motion: Motion) -> MotionEvent {
+        MotionEvent {
+            motion,
+            timestamp: clock.now(),
+        }
+    }
+
+    #[test]
+    fn test_fade_and_restore() {
+        let clock = Arc::new(MockClock::new());
+        let bulb = FakeBulb::with_clock("Taklampa", clock.clone()).unwrap();
+        let before = bulb.color();
+        let room = mock_room(&bulb, &clock, config(Duration::from_millis(250)));
+        assert!(room.has_sensor("hallway"));
+        assert!(!room.has_sensor("door"));
+        clock.wait_for_sleepers(1);
+
+        clock.advance(Duration::from_millis(250));
+        clock.wait_for_sleepers(1);
+        assert!(room.is_fading());
+        assert!(bulb.is_fading());
+
+        room.motion(&motion(&clock, Motion::On));
+        clock.advance(RESTORE_DURATION);
+        assert!(!room.is_fading());
+        assert_eq!(bulb.color(), before);
+    }
+
+    #[test]
+    fn test_changed_during_fade() {
+        let clock = Arc::new(MockClock::new());
+        let bulb = FakeBulb::with_clock("Taklampa", clock.clone()).unwrap();
+        let room = mock_room(&bulb, &clock, config(Duration::from_millis(250)));
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_millis(250));
+        clock.wait_for_sleepers(1);
+        assert!(room.is_fading());
+
+        // halfway through the fade the light is set back to full brightness
+        clock.advance(Duration::from_secs(1));
+        let changed = HSBK {
+            hue: 0x1234,
+            saturation: 0,
+            brightness: light::MAX,
+            kelvin: 2700,
+        };
+        bulb.set_color(changed);
+        room.motion(&motion(&clock, Motion::On));
+        clock.advance(RESTORE_DURATION);
+        assert_eq!(bulb.color(), changed, "changed light is not restored");
+    }
+
+    #[test]
+    fn test_independent_rooms() {
+        let clock = Arc::new(MockClock::new());
+        let bedroom = FakeBulb::with_clock("Taklampa", clock.clone()).unwrap();
+        let kitchen = FakeBulb::with_clock("Fönster", clock.clone()).unwrap();
+        let bedroom_room = mock_room(&bedroom, &clock, config(Duration::from_millis(100)));
+        let kitchen_room = mock_room(&kitchen, &clock, config(Duration::from_secs(10)));
+        clock.wait_for_sleepers(2);
+        clock.advance(Duration::from_millis(100));
+        clock.wait_for_sleepers(2);
+        assert!(bedroom_room.is_fading());
+        assert!(!kitchen_room.is_fading());
+        assert!(!kitchen.is_fading());
+    }
+
+    /// Light requests from outside the room wait for the event it is handling
+    #[test]
+    fn test_with_light() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let rooms = vec![Arc::new(room(&bulb, Duration::from_secs(10)))];
+        assert!(with_light(&rooms, "door", |_| ()).is_none());
+        let handling = rooms[0].inner.handling.lock().unwrap();
+        let rooms_thread = rooms.clone();
+        let request = thread::spawn(move || {
+            with_light(&rooms_thread, "lamp", |light| light.color().unwrap())
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(!request.is_finished());
+        drop(handling);
+        assert_eq!(request.join().unwrap(), Some(bulb.color()));
+    }
+
+    #[test]
+    fn test_poll() {
+        let clock = Arc::new(MockClock::new());
+        let bulb = FakeBulb::with_clock("Taklampa", clock.clone()).unwrap();
+        let room = mock_room(&bulb, &clock, config(Duration::from_millis(250)));
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_millis(250));
+        clock.wait_for_sleepers(1);
+        assert!(matches!(room.state(), State::Fading { .. }));
+        clock.advance(Duration::from_secs(1));
+        bulb.set_color(HSBK {
+            hue: 0x1234,
+            saturation: 0,
+            brightness: light::MAX,
+            kelvin: 2700,
+        });
+        room.poll();
+        assert!(matches!(room.state(), State::ManualOverride { .. }));
+        assert!(!room.is_fading());
+    }
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = Arc::new(MockClock::new());
+        let bulb = FakeBulb::with_clock("Taklampa", clock.clone()).unwrap();
+        let before = bulb.color();
+        let config = RoomConfig {
+            timeout: TIMEOUT,
+            fade_duration: FADE_DURATION,
+            ..config(TIMEOUT)
+        };
+        let room = mock_room(&bulb, &clock, config);
+        // let the timer thread start waiting before moving the clock
+        clock.wait_for_sleepers(1);
+
+        clock.advance(TIMEOUT - Duration::from_secs(1));
+        clock.wait_for_sleepers(1);
+        assert_eq!(room.state(), State::Vacant);
+        clock.advance(Duration::from_secs(1));
+        clock.wait_for_sleepers(1);
+        assert!(matches!(room.state(), State::Fading { .. }));
+
+        // halfway through the fade the light still matches it
+        clock.advance(FADE_DURATION / 2);
+        room.poll();
+        assert!(matches!(room.state(), State::Fading { .. }));
+        clock.advance(FADE_DURATION / 2);
+        room.poll();
+        assert_eq!(room.state(), State::Off);
+        assert_eq!(bulb.color(), fade_to(before, light::MIN));
+
+        room.motion(&motion(&clock, Motion::On));
+        assert_eq!(room.state(), State::Occupied);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(bulb.color(), before);
+    }
+
+    /// Milestones from the config file are published when reached, next to the warning
+    #[test]
+    fn test_milestones() {
+        let clock = Arc::new(MockClock::new());
+        let bulb = FakeBulb::with_clock("Taklampa", clock.clone()).unwrap();
+        let config = RoomConfig {
+            milestones: vec![MilestoneConfig {
+                name: "halfway".to_string(),
+                after: TIMEOUT / 2,
+            }],
+            warning: Some(WarningConfig {
+                before: Duration::from_secs(60),
+                dim: 20.0,
+                cue: None,
+            }),
+            ..config(TIMEOUT)
+        };
+        let events = Events::new();
+        let received = events.subscribe();
+        let lights = vec![("lamp".to_string(), bulb.light().unwrap())];
+        let room = Room::with_events(config, Timings::default(), lights, clock.clone(), events);
+        let names: Vec<_> = room
+            .timer()
+            .milestones()
+            .unwrap()
+            .into_iter()
+            .map(|milestone| milestone.name)
+            .collect();
+        assert_eq!(names, ["halfway".into(), "warning".into()]);
+        clock.wait_for_sleepers(1);
+
+        clock.advance(TIMEOUT / 2);
+        clock.wait_for_sleepers(1);
+        let milestones: Vec<_> = received
+            .try_iter()
+            .filter_map(|event| match event {
+                DaemonEvent::Timer {
+                    action: ACTION::MILESTONE { name },
+                    ..
+                } => Some(name),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(milestones, ["halfway".into()]);
+        assert_eq!(room.state(), State::Vacant);
+    }
+
+    /// A failing light is retried on the room's clock, without holding up the state of the room
+    #[test]
+    fn test_recover() {
+        let clock = Arc::new(MockClock::new());
+        let bulb = FakeBulb::with_clock("Taklampa", clock.clone()).unwrap();
+        let mut light = bulb.light().unwrap();
+        light.retry = light::RetryPolicy {
+            attempts: 1,
+            timeout: Duration::from_millis(20),
+            backoff: 1.0,
+        };
+        let config = config(TIMEOUT);
+        let lights = vec![("lamp".to_string(), light)];
+        let room = Arc::new(Room::with_clock(
+            config,
+            Timings::default(),
+            lights,
+            clock.clone(),
+        ));
+        // reading the color is lost twice, it is read again after one and two seconds
+        bulb.drop_next(2);
+        let room_force = room.clone();
+        let forcing = thread::spawn(move || room_force.force_timeout());
+        // the timer thread and the retrying thread
+        clock.wait_for_sleepers(2);
+
+        let (sender, receiver) = mpsc::channel();
+        let room_state = room.clone();
+        thread::spawn(move || sender.send(room_state.state()).unwrap());
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(1)),
+            Ok(State::Vacant)
+        );
+        assert!(!forcing.is_finished());
+        clock.advance(RECOVERY_DELAY);
+        clock.wait_for_sleepers(2);
+        assert!(!forcing.is_finished());
+        clock.advance(RECOVERY_DELAY * 2);
+        forcing.join().unwrap();
+        assert!(matches!(room.state(), State::Fading { .. }));
+    }
+
+    /// The remaining time is answered while the timeout is handled, also with a light retried
+    #[test]
+    fn test_remaining_during_timeout() {
+        let clock = Arc::new(MockClock::new());
+        let bulb = FakeBulb::with_clock("Taklampa", clock.clone()).unwrap();
+        let mut light = bulb.light().unwrap();
+        light.retry = light::RetryPolicy {
+            attempts: 1,
+            timeout: Duration::from_millis(20),
+            backoff: 1.0,
+        };
+        let lights = vec![("lamp".to_string(), light)];
+        let config = config(Duration::from_millis(250));
+        let room = Arc::new(Room::with_clock(
+            config,
+            Timings::default(),
+            lights,
+            clock.clone(),
+        ));
+        clock.wait_for_sleepers(1);
+        // reading the color at the timeout is lost, it is read again after a second
+        bulb.drop_next(1);
+        clock.advance(Duration::from_millis(250));
+        clock.wait_for_sleepers(1);
+
+        let (sender, receiver) = mpsc::channel();
+        let room_remaining = room.clone();
+        thread::spawn(move || sender.send(room_remaining.remaining()).unwrap());
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(1)), Ok(None));
+        assert!(!room.is_fading());
+        clock.advance(RECOVERY_DELAY);
+        clock.wait_for_sleepers(1);
+        assert!(room.is_fading());
+    }
+
+    #[test]
+    fn test_update() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let room = room(&bulb, Duration::from_secs(10));
+        let mut config = room.config();
+        config.timeout = Duration::from_secs(20);
+        config.sensors = vec!["door".to_string()];
+        room.update(config, Timings::default(), Vec::new());
+        assert_eq!(room.timer().timeout().unwrap(), Duration::from_secs(20));
+        assert!(room.has_sensor("door"));
+        assert!(room.lights().is_empty());
+    }
+
+    #[test]
+    fn test_schedules() {
+        let bulb = FakeBulb::new("Taklampa").unwrap();
+        let room = room(&bulb, Duration::from_secs(10));
+        let schedules: Vec<ScheduleConfig> = vec![ScheduleConfig {
+            name: "night".to_string(),
+            rooms: Vec::new(),
+            days: Vec::new(),
+            start: "23:00".parse().unwrap(),
+            end: "07:00".parse().unwrap(),
+            priority: 0,
+            enabled: None,
+            timeout: Some(Duration::from_secs(120)),
+            fade_duration: None,
+            fade_brightness: Some(50.0),
+        }];
+        let night = "2024-01-01T23:30:00".parse().unwrap();
+        room.apply_schedules(&schedules, night);
+        assert_eq!(room.overrides().active, ["night"]);
+        assert_eq!(room.timer().timeout().unwrap(), Duration::from_secs(120));
+        assert_eq!(room.settings().fade_brightness, light::from_percent(50.0));
+        assert_eq!(room.config().timeout, Duration::from_secs(10));
+
+        // a reload keeps the schedule
+        let mut config = room.config();
+        config.timeout = Duration::from_secs(20);
+        room.update(config, Timings::default(), room.lights());
+        assert_eq!(room.timer().timeout().unwrap(), Duration::from_secs(120));
+
+        room.apply_schedules(&schedules, "2024-01-02T08:00:00".parse().unwrap());
+        assert!(room.overrides().active.is_empty());
+        assert_eq!(room.timer().timeout().unwrap(), Duration::from_secs(20));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let clock = Arc::new(MockClock::new());
+        let bulb = FakeBulb::with_clock("Taklampa", clock.clone()).unwrap();
+        let room = mock_room(&bulb, &clock, config(Duration::from_millis(250)));
+        room.set_overrides(Overrides {
+            enabled: Some(false),
+            active: vec!["work".to_string()],
+            ..Default::default()
+        });
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_millis(400));
+        clock.wait_for_sleepers(1);
+        assert_eq!(room.state(), State::Vacant);
+        assert!(!bulb.is_fading());
+
+        room.set_overrides(Overrides::default());
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_millis(250));
+        clock.wait_for_sleepers(1);
+        assert!(room.is_fading(), "timer restarted when enabled again");
+    }
+
+    #[test]
+    fn test_pause_and_force() {
+        let clock = Arc::new(MockClock::new());
+        let bulb = FakeBulb::with_clock("Taklampa", clock.clone()).unwrap();
+        let before = bulb.color();
+        let room = mock_room(&bulb, &clock, config(Duration::from_millis(250)));
+        assert_eq!(room.remaining(), Some(Duration::from_millis(250)));
+        room.pause();
+        assert!(room.is_paused());
+        assert!(!room.settings().enabled);
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_millis(400));
+        clock.wait_for_sleepers(1);
+        assert_eq!(room.state(), State::Vacant);
+        assert_eq!(room.remaining(), None, "timer ran out");
+
+        room.force_timeout();
+        assert!(room.is_fading());
+        room.restore();
+        assert!(!room.is_fading());
+        clock.advance(RESTORE_DURATION);
+        assert_eq!(bulb.color(), before);
+
+        room.resume();
+        assert!(!room.is_paused());
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_millis(250));
+        clock.wait_for_sleepers(1);
+        assert!(room.is_fading());
+    }
+
+    #[test]
+    fn test_freeze() {
+        let clock = Arc::new(MockClock::new());
+        let bulb = FakeBulb::with_clock("Taklampa", clock.clone()).unwrap();
+        let room = mock_room(&bulb, &clock, config(TIMEOUT));
+        clock.wait_for_sleepers(1);
+        clock.advance(TIMEOUT - Duration::from_secs(60));
+        room.freeze();
+        assert!(room.is_frozen());
+        clock.wait_for_sleepers(1);
+        clock.advance(TIMEOUT);
+        clock.wait_for_sleepers(1);
+        assert_eq!(room.state(), State::Vacant, "frozen during the movie");
+        assert_eq!(room.remaining(), Some(Duration::from_secs(60)));
+
+        room.unfreeze();
+        room.extend(Duration::from_secs(60));
+        clock.wait_for_sleepers(1);
+        assert!(!room.is_frozen());
+        assert_eq!(room.remaining(), Some(Duration::from_secs(120)));
+        clock.advance(Duration::from_secs(120));
+        clock.wait_for_sleepers(1);
+        assert!(room.is_fading());
+    }
+
+    #[test]
+    fn test_power_off() {
+        let clock = Arc::new(MockClock::new());
+        let bulb = FakeBulb::with_clock("Taklampa", clock.clone()).unwrap();
+        let before = bulb.color();
+        let config = RoomConfig {
+            fade_duration: Duration::from_millis(50),
+            power_off: true,
+            ..config(Duration::from_millis(300))
+        };
+        let room = mock_room(&bulb, &clock, config);
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_millis(300));
+        clock.wait_for_sleepers(1);
+        assert!(matches!(room.state(), State::Fading { .. }));
+        clock.advance(Duration::from_millis(50));
+        room.poll();
+        assert_eq!(room.state(), State::Off);
+        assert_eq!(bulb.power(), 0, "powered off after the fade");
+
+        room.motion(&motion(&clock, Motion::On));
+        clock.advance(RESTORE_DURATION);
+        assert_eq!(bulb.power(), light::MAX);
+        assert_eq!(bulb.color(), before);
+    }
+
+    #[test]
+    fn test_multizone() {
+        let bulb = FakeBulb::multizone("Lifx Z", 16).unwrap();
+        let zones: Vec<HSBK> = (0..16)
+            .map(|zone| HSBK {
+                hue: zone * 4096,
+                saturation: light::MAX,
+                brightness: light::MAX,
+                kelvin: 3500,
+            })
+            .collect();
+        bulb.set_zones(&zones);
+        let clock = Arc::new(MockClock::new());
+        let room = mock_room(&bulb, &clock, config(Duration::from_millis(250)));
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_millis(250));
+        clock.wait_for_sleepers(1);
+        assert!(room.is_fading());
+        let faded: Vec<HSBK> = zones
+            .iter()
+            .map(|&zone| fade_to(zone, light::MIN))
+            .collect();
+        assert_eq!(bulb.zones().unwrap(), faded, "zones keep their colors");
+        assert_eq!(room.patterns()["lamp"], Pattern::Zones(zones.clone()));
+
+        room.motion(&motion(&clock, Motion::On));
+        assert_eq!(bulb.zones().unwrap(), zones);
+        assert!(room.patterns().is_empty());
+    }
+
+    #[test]
+    fn test_matrix() {
+        let tiles = [light::Tile {
+            width: 8,
+            height: 8,
+        }; 2];
+        let bulb = FakeBulb::matrix("Tiles", &tiles).unwrap();
+        let pixels: Vec<Vec<HSBK>> = (0..2)
+            .map(|tile| {
+                (0..64)
+                    .map(|pixel| HSBK {
+                        hue: pixel * 1000,
+                        saturation: light::MAX,
+                        brightness: light::MAX - tile * 1000,
+                        kelvin: 3500,
+                    })
+                    .collect()
+            })
+            .collect();
+        bulb.set_pixels(&pixels);
+        let clock = Arc::new(MockClock::new());
+        let room = mock_room(&bulb, &clock, config(Duration::from_millis(250)));
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_millis(250));
+        clock.wait_for_sleepers(1);
+        assert!(room.is_fading());
+        let faded: Vec<Vec<HSBK>> = pixels
+            .iter()
+            .map(|tile| {
+                tile.iter()
+                    .map(|&pixel| fade_to(pixel, light::MIN))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(bulb.pixels().unwrap(), faded, "pixels dimmed uniformly");
+
+        room.motion(&motion(&clock, Motion::On));
+        assert_eq!(bulb.pixels().unwrap(), pixels, "pattern restored exactly");
+    }
+
+    #[test]
+    fn test_restore_cue() {
+        let clock = Arc::new(MockClock::new());
+        let bulb = FakeBulb::with_clock("Taklampa", clock.clone()).unwrap();
+        let before = bulb.color();
+        let cue: CueConfig = toml::from_str("waveform = \"sine\"\nbrightness = 30").unwrap();
+        let config = RoomConfig {
+            restore_cue: Some(cue),
+            ..config(Duration::from_millis(250))
+        };
+        let room = mock_room(&bulb, &clock, config);
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_millis(250));
+        clock.wait_for_sleepers(1);
+        assert!(room.is_fading());
+        assert!(!bulb
+            .received()
+            .iter()
+            .any(|message| matches!(message, Message::SetWaveformOptional { .. })));
+
+        room.motion(&motion(&clock, Motion::On));
+        assert_eq!(bulb.color(), before);
+        assert!(matches!(
+            bulb.received().last(),
+            Some(Message::SetWaveformOptional {
+                set_brightness: true,
+                transient: true,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_warning() {
+        let clock = Arc::new(MockClock::new());
+        let bulb = FakeBulb::with_clock("Taklampa", clock.clone()).unwrap();
+        let before = bulb.color();
+        let warning: WarningConfig = toml::from_str("before = 0.6\ndim = 50").unwrap();
+        let config = RoomConfig {
+            warning: Some(warning),
+            ..config(Duration::from_millis(1000))
+        };
+        let room = mock_room(&bulb, &clock, config);
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_millis(400));
+        clock.wait_for_sleepers(1);
+        assert!(matches!(room.state(), State::Warning { .. }));
+        assert!(!room.is_fading());
+        assert!(bulb.is_fading(), "dimming");
+
+        room.motion(&motion(&clock, Motion::Off));
+        clock.advance(RESTORE_DURATION);
+        assert_eq!(room.state(), State::Vacant);
+        assert_eq!(bulb.color(), before, "undimmed at once");
+
+        // warned again, then faded from the dimmed light
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_millis(400));
+        clock.wait_for_sleepers(1);
+        assert!(matches!(room.state(), State::Warning { .. }));
+        clock.advance(Duration::from_millis(600));
+        clock.wait_for_sleepers(1);
+        assert!(matches!(room.state(), State::Fading { .. }));
+        assert_eq!(room.fades()["lamp"].before, before);
+    }
+
+    #[test]
+    fn test_events() {
+        let clock = Arc::new(MockClock::new());
+        let bulb = FakeBulb::with_clock("Taklampa", clock.clone()).unwrap();
+        let before = bulb.color();
+        let events = Events::new();
+        let received = events.subscribe();
+        let config = config(Duration::from_millis(250));
+        let lights = vec![("lamp".to_string(), bulb.light().unwrap())];
+        let room = Room::with_events(config, Timings::default(), lights, clock.clone(), events);
+        room.set_timeout(Some(Duration::from_millis(100)));
+        assert_eq!(room.timer().timeout().unwrap(), Duration::from_millis(100));
+        assert_eq!(room.timeout_override(), Some(Duration::from_millis(100)));
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_millis(100));
+        clock.wait_for_sleepers(1);
+
+        let (requests, received): (Vec<_>, Vec<_>) = received
+            .try_iter()
+            .partition(|event| matches!(event, DaemonEvent::Request { .. }));
+        let room = || "bedroom".to_string();
+        assert_eq!(
+            received,
+            vec![
+                DaemonEvent::Room { room: room() },
+                DaemonEvent::Timer {
+                    room: room(),
+                    action: ACTION::TIMEOUT
+                },
+                DaemonEvent::Observed {
+                    light: "lamp".to_string(),
+                    color: before,
+                    powered: true
+                },
+                DaemonEvent::Room { room: room() },
+                DaemonEvent::Fade {
+                    room: room(),
+                    light: "lamp".to_string(),
+                    outcome: FadeOutcome::Started
+                },
+                DaemonEvent::Light {
+                    light: "lamp".to_string(),
+                    color: Some(fade_to(before, light::MIN))
+                },
+            ]
+        );
+        // reading the colors at the timeout, then fading
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|request| matches!(
+            request,
+            DaemonEvent::Request { light, failed: false, .. } if light == "lamp"
+        )));
+    }
+}
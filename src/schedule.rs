@@ -0,0 +1,378 @@
+//! Time-of-day schedules that enable, disable or retune the motion timer of rooms
+//!
+//! A schedule is active on its `days` from `start` until `end` local time. A schedule ending
+//! before it starts runs past midnight, and belongs to the day it started on: a Friday schedule
+//! from 23:00 to 07:00 is active until Saturday 07:00.
+//!
+//! # Precedence
+//!
+//! Every setting is taken from the active schedule with the highest `priority` that sets it, if
+//! several schedules have the same priority the one declared last in the file wins. Settings no
+//! active schedule sets are taken from the room.
+//!
+//! ```toml
+//! [[schedules]]
+//! name = "night"
+//! days = ["weekdays"]
+//! start = "23:00"
+//! end = "07:00"
+//! timeout = 120
+//! fade_brightness = 1
+//!
+//! [[schedules]]
+//! name = "work"
+//! start = "08:00"
+//! end = "17:00"
+//! enabled = false
+//! ```
+
+use std::fmt;
+use std::time::Duration;
+
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::config::{option_secs, RoomConfig};
+
+/// Interval the schedules are checked against the local time
+pub const SCHEDULE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Days of the week `weekdays` stands for in `days`
+const WEEKDAYS: [Weekday; 5] = [
+    Weekday::Mon,
+    Weekday::Tue,
+    Weekday::Wed,
+    Weekday::Thu,
+    Weekday::Fri,
+];
+/// Days of the week `weekends` stands for in `days`
+const WEEKENDS: [Weekday; 2] = [Weekday::Sat, Weekday::Sun];
+
+/// A rule changing the settings of rooms during part of the day
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleConfig {
+    pub name: String,
+    /// Names of the rooms the schedule applies to, all rooms if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rooms: Vec<String>,
+    /// Days the schedule starts on, like `mon`, `sunday`, `weekdays` or `weekends`, every day if empty
+    #[serde(default, with = "days", skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<Weekday>,
+    /// Local time the schedule starts, like `23:00`
+    #[serde(with = "hhmm")]
+    pub start: NaiveTime,
+    /// Local time the schedule ends, the same as `start` for the whole day
+    #[serde(with = "hhmm")]
+    pub end: NaiveTime,
+    /// Schedules with higher priority win over overlapping schedules
+    #[serde(default)]
+    pub priority: i32,
+    /// If motion fades the lights, `false` leaves the lights alone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    /// Time without motion before the lights start fading
+    #[serde(default, with = "option_secs", skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<Duration>,
+    /// Duration of the fade
+    #[serde(default, with = "option_secs", skip_serializing_if = "Option::is_none")]
+    pub fade_duration: Option<Duration>,
+    /// Brightness in percent the lights fade to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fade_brightness: Option<f32>,
+}
+
+impl ScheduleConfig {
+    /// If the schedule applies to the room with name `room`
+    pub fn applies_to(&self, room: &str) -> bool {
+        self.rooms.is_empty() || self.rooms.iter().any(|name| name == room)
+    }
+
+    /// If the schedule is active at local time `at`
+    pub fn is_active(&self, at: NaiveDateTime) -> bool {
+        let time = at.time();
+        let today = at.weekday();
+        let on = |day: Weekday| self.days.is_empty() || self.days.contains(&day);
+        if self.start < self.end {
+            on(today) && self.start <= time && time < self.end
+        } else if self.start > self.end {
+            // runs past midnight, the morning belongs to the day before
+            (on(today) && time >= self.start) || (on(today.pred()) && time < self.end)
+        } else {
+            on(today)
+        }
+    }
+}
+
+/// Settings of a room changed by the schedules active at some time, see [`overrides`]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Overrides {
+    pub enabled: Option<bool>,
+    pub timeout: Option<Duration>,
+    pub fade_duration: Option<Duration>,
+    pub fade_brightness: Option<f32>,
+    /// Names of the active schedules, by increasing precedence
+    pub active: Vec<String>,
+}
+
+impl Overrides {
+    /// If motion control is enabled, the default without schedules
+    pub fn enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+
+    /// Settings of `room` with the overridden settings replaced
+    pub fn apply(&self, room: &RoomConfig) -> RoomConfig {
+        RoomConfig {
+            timeout: self.timeout.unwrap_or(room.timeout),
+            fade_duration: self.fade_duration.unwrap_or(room.fade_duration),
+            fade_brightness: self.fade_brightness.unwrap_or(room.fade_brightness),
+            ..room.clone()
+        }
+    }
+}
+
+impl fmt::Display for Overrides {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.active.is_empty() {
+            return write!(fmt, "no schedule");
+        }
+        write!(fmt, "schedules {:?}", self.active)?;
+        if !self.enabled() {
+            write!(fmt, ", disabled")?;
+        }
+        if let Some(timeout) = self.timeout {
+            write!(fmt, ", timeout {:?}", timeout)?;
+        }
+        if let Some(fade_duration) = self.fade_duration {
+            write!(fmt, ", fade duration {:?}", fade_duration)?;
+        }
+        if let Some(fade_brightness) = self.fade_brightness {
+            write!(fmt, ", fade to {}%", fade_brightness)?;
+        }
+        Ok(())
+    }
+}
+
+/// Merge the `schedules` active for `room` at local time `at`, see the [module documentation](self) for the precedence
+pub fn overrides(schedules: &[ScheduleConfig], room: &str, at: NaiveDateTime) -> Overrides {
+    let mut active: Vec<(usize, &ScheduleConfig)> = schedules
+        .iter()
+        .enumerate()
+        .filter(|(_, schedule)| schedule.applies_to(room) && schedule.is_active(at))
+        .collect();
+    // lowest precedence first, so later schedules overwrite earlier ones
+    active.sort_by_key(|(index, schedule)| (schedule.priority, *index));
+
+    let mut overrides = Overrides::default();
+    for (_, schedule) in active {
+        overrides.enabled = schedule.enabled.or(overrides.enabled);
+        overrides.timeout = schedule.timeout.or(overrides.timeout);
+        overrides.fade_duration = schedule.fade_duration.or(overrides.fade_duration);
+        overrides.fade_brightness = schedule.fade_brightness.or(overrides.fade_brightness);
+        overrides.active.push(schedule.name.clone());
+    }
+    overrides
+}
+
+/// Serialize [`NaiveTime`] as `HH:MM`, seconds are accepted too
+mod hhmm {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(time: &NaiveTime, serializer: S) -> Result<S::Ok, S::Error> {
+        time.format("%H:%M").to_string().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+        let time = String::deserialize(deserializer)?;
+        NaiveTime::parse_from_str(&time, "%H:%M")
+            .or_else(|_| NaiveTime::parse_from_str(&time, "%H:%M:%S"))
+            .map_err(|_| {
+                serde::de::Error::custom(format!("invalid time {:?}, expected HH:MM", time))
+            })
+    }
+}
+
+/// Serialize days of the week as short names, accepting `weekdays` and `weekends`
+mod days {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(days: &[Weekday], serializer: S) -> Result<S::Ok, S::Error> {
+        days.iter()
+            .map(|day| day.to_string().to_lowercase())
+            .collect::<Vec<_>>()
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Weekday>, D::Error> {
+        let mut days = Vec::new();
+        for day in Vec::<String>::deserialize(deserializer)? {
+            match day.to_lowercase().as_str() {
+                "weekdays" => days.extend(WEEKDAYS),
+                "weekends" => days.extend(WEEKENDS),
+                name => days.push(name.parse().map_err(|_| {
+                    serde::de::Error::custom(format!("invalid day of the week {:?}", day))
+                })?),
+            }
+        }
+        Ok(days)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use chrono::NaiveDate;
+
+    const EXAMPLE: &str = include_str!("../motion_sensor_lifx.toml");
+
+    /// Local time on a day in the week of Monday 2024-01-01
+    fn at(day: Weekday, time: &str) -> NaiveDateTime {
+        NaiveDate::from_isoywd_opt(2024, 1, day)
+            .unwrap()
+            .and_time(NaiveTime::parse_from_str(time, "%H:%M").unwrap())
+    }
+
+    fn schedules(toml: &str) -> Vec<ScheduleConfig> {
+        #[derive(Deserialize)]
+        struct Schedules {
+            schedules: Vec<ScheduleConfig>,
+        }
+        toml::from_str::<Schedules>(toml).unwrap().schedules
+    }
+
+    #[test]
+    fn test_is_active() {
+        let [night, work, all_day] = &schedules(
+            r#"
+            [[schedules]]
+            name = "night"
+            days = ["weekdays"]
+            start = "23:00"
+            end = "07:00"
+
+            [[schedules]]
+            name = "work"
+            days = ["mon", "Tuesday"]
+            start = "08:00"
+            end = "17:00"
+
+            [[schedules]]
+            name = "all day"
+            days = ["weekends"]
+            start = "00:00"
+            end = "00:00"
+            "#,
+        )[..] else {
+            panic!("three schedules");
+        };
+        assert!(night.is_active(at(Weekday::Mon, "23:00")));
+        assert!(night.is_active(at(Weekday::Tue, "06:59")));
+        assert!(!night.is_active(at(Weekday::Tue, "07:00")));
+        assert!(!night.is_active(at(Weekday::Mon, "06:00")), "sunday night");
+        assert!(night.is_active(at(Weekday::Sat, "06:00")), "friday night");
+        assert!(!night.is_active(at(Weekday::Sat, "23:30")));
+
+        assert!(work.is_active(at(Weekday::Tue, "08:00")));
+        assert!(!work.is_active(at(Weekday::Tue, "17:00")));
+        assert!(!work.is_active(at(Weekday::Wed, "12:00")));
+
+        assert!(all_day.is_active(at(Weekday::Sun, "12:00")));
+        assert!(!all_day.is_active(at(Weekday::Mon, "12:00")));
+    }
+
+    #[test]
+    fn test_precedence() {
+        let schedules = schedules(
+            r#"
+            [[schedules]]
+            name = "evening"
+            start = "18:00"
+            end = "00:00"
+            timeout = 300
+            fade_brightness = 10
+
+            [[schedules]]
+            name = "late"
+            start = "22:00"
+            end = "00:00"
+            timeout = 120
+
+            [[schedules]]
+            name = "party"
+            rooms = ["kitchen"]
+            priority = 10
+            start = "20:00"
+            end = "02:00"
+            enabled = false
+
+            [[schedules]]
+            name = "bedtime"
+            rooms = ["bedroom"]
+            priority = -1
+            start = "22:00"
+            end = "23:00"
+            timeout = 60
+            "#,
+        );
+        let overrides = overrides(&schedules, "bedroom", at(Weekday::Fri, "22:30"));
+        assert_eq!(overrides.active, ["bedtime", "evening", "late"]);
+        assert_eq!(
+            overrides.timeout,
+            Some(Duration::from_secs(120)),
+            "declared last"
+        );
+        assert_eq!(overrides.fade_brightness, Some(10.0), "merged per setting");
+        assert!(overrides.enabled());
+
+        let overrides = super::overrides(&schedules, "kitchen", at(Weekday::Fri, "22:30"));
+        assert!(!overrides.enabled(), "highest priority");
+        assert_eq!(overrides.timeout, Some(Duration::from_secs(120)));
+
+        let config: Config = EXAMPLE.parse().unwrap();
+        let room = &config.rooms[0];
+        let overrides = super::overrides(&schedules, &room.name, at(Weekday::Fri, "12:00"));
+        assert_eq!(overrides, Overrides::default());
+        assert_eq!(&overrides.apply(room), room);
+    }
+
+    #[test]
+    fn test_apply() {
+        let config: Config = EXAMPLE.parse().unwrap();
+        let overrides = Overrides {
+            timeout: Some(Duration::from_secs(120)),
+            fade_brightness: Some(1.0),
+            active: vec!["night".to_string()],
+            ..Default::default()
+        };
+        let room = overrides.apply(&config.rooms[0]);
+        assert_eq!(room.timeout, Duration::from_secs(120));
+        assert_eq!(room.fade_brightness, 1.0);
+        assert_eq!(room.fade_duration, config.rooms[0].fade_duration);
+        assert_eq!(
+            overrides.to_string(),
+            r#"schedules ["night"], timeout 120s, fade to 1%"#
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |toml: &str| {
+            toml::from_str::<ScheduleConfig>(toml)
+                .unwrap_err()
+                .message()
+                .to_string()
+        };
+        assert_eq!(
+            error("name = \"x\"\nstart = \"25:00\"\nend = \"07:00\""),
+            r#"invalid time "25:00", expected HH:MM"#
+        );
+        assert_eq!(
+            error("name = \"x\"\ndays = [\"someday\"]\nstart = \"23:00\"\nend = \"07:00\""),
+            r#"invalid day of the week "someday""#
+        );
+    }
+}
@@ -0,0 +1,374 @@
+//! Fade state of the rooms kept in a JSON file, so faded lights are still restored after a restart
+//!
+//! The file is written whenever a room changes state or a fade starts or ends, and read once at
+//! startup. Saved fades are thrown away when they can not be trusted anymore:
+//!
+//! - the whole file when it was saved longer than `max_age` ago
+//! - fades of rooms or lights that are no longer in the config file
+//! - fades of lights changed while the daemon was down, found by the next poll or motion as if
+//!   the light was changed during the fade
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use lifx_core::HSBK;
+use serde::{Deserialize, Serialize};
+
+use crate::config::secs;
+use crate::events::{DaemonEvent, Events};
+use crate::light::Pattern;
+use crate::presence::Fade;
+use crate::room::Rooms;
+use crate::Room;
+
+/// Age after which a state file is ignored at startup
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Color of a light as saved
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Color {
+    pub hue: u16,
+    pub saturation: u16,
+    pub brightness: u16,
+    pub kelvin: u16,
+}
+
+impl From<HSBK> for Color {
+    fn from(color: HSBK) -> Self {
+        Self {
+            hue: color.hue,
+            saturation: color.saturation,
+            brightness: color.brightness,
+            kelvin: color.kelvin,
+        }
+    }
+}
+
+impl From<Color> for HSBK {
+    fn from(color: Color) -> Self {
+        HSBK {
+            hue: color.hue,
+            saturation: color.saturation,
+            brightness: color.brightness,
+            kelvin: color.kelvin,
+        }
+    }
+}
+
+/// Fade of one light as saved, with the wall clock time it started
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedFade {
+    pub before: Color,
+    pub target: Color,
+    pub started: DateTime<Utc>,
+    #[serde(with = "secs")]
+    pub duration: Duration,
+    #[serde(default)]
+    pub power_off: bool,
+    #[serde(default)]
+    pub powered_off: bool,
+}
+
+impl SavedFade {
+    /// `fade` with its start in wall clock time, given the instant `now` is wall clock time `wall`
+    pub fn new(fade: &Fade, now: Instant, wall: DateTime<Utc>) -> Self {
+        let ago = now.saturating_duration_since(fade.started);
+        Self {
+            before: fade.before.into(),
+            target: fade.target.into(),
+            started: wall - chrono::Duration::from_std(ago).unwrap_or(chrono::Duration::zero()),
+            duration: fade.duration,
+            power_off: fade.power_off,
+            powered_off: fade.powered_off,
+        }
+    }
+
+    /// The fade with its start as an instant, given the instant `now` is wall clock time `wall`
+    pub fn fade(&self, now: Instant, wall: DateTime<Utc>) -> Fade {
+        let ago = (wall - self.started).to_std().unwrap_or_default();
+        Fade {
+            before: self.before.into(),
+            target: self.target.into(),
+            started: now.checked_sub(ago).unwrap_or(now),
+            duration: self.duration,
+            power_off: self.power_off,
+            powered_off: self.powered_off,
+        }
+    }
+}
+
+/// State of one room as saved
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SavedRoom {
+    /// State when saved, for people reading the file
+    pub state: String,
+    /// Fade per light name
+    #[serde(default)]
+    pub fades: BTreeMap<String, SavedFade>,
+    /// Zone colors from before the fade per light name, of multizone lights
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub zones: BTreeMap<String, Vec<Color>>,
+    /// Pixels of every tile from before the fade per light name, of matrix lights
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tiles: BTreeMap<String, Vec<Vec<Color>>>,
+}
+
+/// Colors as saved
+fn save_colors(colors: &[HSBK]) -> Vec<Color> {
+    colors.iter().copied().map(Color::from).collect()
+}
+
+/// Colors as saved, back as [`HSBK`]
+fn load_colors(colors: &[Color]) -> Vec<HSBK> {
+    colors.iter().copied().map(HSBK::from).collect()
+}
+
+/// Contents of the state file
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedState {
+    pub saved: DateTime<Utc>,
+    /// State per room name
+    pub rooms: BTreeMap<String, SavedRoom>,
+}
+
+impl SavedState {
+    /// State of `rooms`, given the instant `now` is wall clock time `wall`
+    pub fn new(rooms: &[Arc<Room>], now: Instant, wall: DateTime<Utc>) -> Self {
+        let rooms = rooms
+            .iter()
+            .map(|room| {
+                let fades = room
+                    .fades()
+                    .iter()
+                    .map(|(light, fade)| (light.clone(), SavedFade::new(fade, now, wall)))
+                    .collect();
+                let mut saved = SavedRoom {
+                    state: room.state().to_string(),
+                    fades,
+                    ..Default::default()
+                };
+                for (light, pattern) in room.patterns() {
+                    match pattern {
+                        Pattern::Zones(zones) => {
+                            saved.zones.insert(light, save_colors(&zones));
+                        }
+                        Pattern::Tiles(tiles) => {
+                            let tiles = tiles.iter().map(|pixels| save_colors(pixels)).collect();
+                            saved.tiles.insert(light, tiles);
+                        }
+                    }
+                }
+                (room.name().to_string(), saved)
+            })
+            .collect();
+        Self { saved: wall, rooms }
+    }
+
+    /// Read the state file at `path`, `None` if there is none
+    pub fn load(path: &Path) -> io::Result<Option<Self>> {
+        match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map(Some)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Write the state file at `path`, replacing it at once so a crash never leaves half a file
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent)?;
+        }
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let temporary = PathBuf::from(temporary);
+        fs::write(&temporary, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&temporary, path)
+    }
+
+    /// If the state was saved more than `max_age` before wall clock time `wall`
+    pub fn is_stale(&self, wall: DateTime<Utc>, max_age: Duration) -> bool {
+        (wall - self.saved).to_std().unwrap_or_default() > max_age
+    }
+
+    /// Resume the saved fades of `rooms`, given the instant `now` is wall clock time `wall`
+    pub fn resume(&self, rooms: &[Arc<Room>], now: Instant, wall: DateTime<Utc>) {
+        for room in rooms {
+            let Some(saved) = self.rooms.get(room.name()) else {
+                continue;
+            };
+            let fades: HashMap<String, Fade> = saved
+                .fades
+                .iter()
+                .map(|(light, fade)| (light.clone(), fade.fade(now, wall)))
+                .collect();
+            let zones = saved
+                .zones
+                .iter()
+                .map(|(light, zones)| (light.clone(), Pattern::Zones(load_colors(zones))));
+            let tiles = saved.tiles.iter().map(|(light, tiles)| {
+                let tiles = tiles.iter().map(|pixels| load_colors(pixels)).collect();
+                (light.clone(), Pattern::Tiles(tiles))
+            });
+            room.resume_fades(fades, zones.chain(tiles).collect());
+        }
+    }
+}
+
+/// Resume the fades saved at `path` unless older than `max_age`, then keep saving the state of `rooms` on every change
+pub fn spawn(path: PathBuf, max_age: Duration, rooms: Rooms, events: &Events) -> io::Result<()> {
+    {
+        let rooms = rooms.lock().unwrap();
+        let (now, wall) = (Instant::now(), Utc::now());
+        match SavedState::load(&path) {
+            Ok(Some(saved)) if saved.is_stale(wall, max_age) => {
+                tracing::info!(
+                    "ignoring state file {} saved {}",
+                    path.display(),
+                    saved.saved
+                )
+            }
+            Ok(Some(saved)) => saved.resume(&rooms, now, wall),
+            Ok(None) => {}
+            Err(err) => tracing::warn!("could not read state file {}: {}", path.display(), err),
+        }
+        SavedState::new(&rooms, now, wall).save(&path)?;
+    }
+
+    let receiver = events.subscribe();
+    thread::Builder::new()
+        .name("state".to_string())
+        .spawn(move || {
+            while let Ok(event) = receiver.recv() {
+                if !matches!(event, DaemonEvent::Room { .. } | DaemonEvent::Fade { .. }) {
+                    continue;
+                }
+                // a fade publishes several events at once, save after the last one
+                while receiver.try_recv().is_ok() {}
+                let rooms = rooms.lock().unwrap().clone();
+                let state = SavedState::new(&rooms, Instant::now(), Utc::now());
+                if let Err(err) = state.save(&path) {
+                    tracing::warn!("could not save state file {}: {}", path.display(), err);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::config::{RoomConfig, Timings};
+    use crate::fake::FakeBulb;
+    use crate::light;
+    use crate::motion::{Motion, MotionEvent};
+    use crate::presence::State;
+    use crate::{fade_to, Clock};
+    use std::time::SystemTime;
+
+    const WHITE: HSBK = HSBK {
+        hue: 0,
+        saturation: 0,
+        brightness: light::MAX,
+        kelvin: 3500,
+    };
+
+    /// Directory of its own for a test, removed when dropped also if the test fails
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let nanos = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos();
+            let name = format!(
+                "motion_sensor_lifx_{}_{}_{}",
+                name,
+                std::process::id(),
+                nanos
+            );
+            let path = std::env::temp_dir().join(name);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn room(bulb: &FakeBulb, clock: Arc<dyn Clock>) -> Arc<Room> {
+        let config = RoomConfig::test("bedroom", Duration::from_secs(10));
+        let lights = vec![("lamp".to_string(), bulb.light().unwrap())];
+        Arc::new(Room::with_clock(config, Timings::default(), lights, clock))
+    }
+
+    #[test]
+    fn test_saved_fade() {
+        let now = Instant::now();
+        let wall = Utc::now();
+        let fade = Fade {
+            before: WHITE,
+            target: fade_to(WHITE, light::MIN),
+            started: now - Duration::from_secs(5),
+            duration: Duration::from_secs(180),
+            power_off: true,
+            powered_off: true,
+        };
+        let saved = SavedFade::new(&fade, now, wall);
+        assert_eq!(saved.started, wall - chrono::Duration::seconds(5));
+
+        // restarted a minute later
+        let later = now + Duration::from_secs(60);
+        let resumed = saved.fade(later, wall + chrono::Duration::seconds(60));
+        assert_eq!(resumed, fade);
+    }
+
+    #[test]
+    fn test_save_and_resume() {
+        let directory = TempDir::new("state");
+        let path = directory.0.join("state.json");
+        let clock = Arc::new(MockClock::new());
+        let bulb = FakeBulb::with_clock("Taklampa", clock.clone()).unwrap();
+        bulb.set_color(WHITE);
+        let before = vec![room(&bulb, clock.clone())];
+        before[0].force_timeout();
+        assert!(before[0].is_fading());
+        // halfway through the fade
+        clock.advance(Duration::from_secs(1));
+        assert_ne!(bulb.color(), WHITE);
+        let (now, wall) = (clock.now(), Utc::now());
+        SavedState::new(&before, now, wall).save(&path).unwrap();
+        drop(before);
+
+        let saved = SavedState::load(&path).unwrap().unwrap();
+        assert_eq!(saved.rooms["bedroom"].state, "fading");
+        assert_eq!(saved.rooms["bedroom"].fades["lamp"].before, WHITE.into());
+        assert!(!saved.is_stale(wall, DEFAULT_MAX_AGE));
+        assert!(saved.is_stale(wall + chrono::Duration::hours(13), DEFAULT_MAX_AGE));
+
+        let after = vec![room(&bulb, clock.clone())];
+        saved.resume(&after, clock.now(), wall);
+        assert!(matches!(after[0].state(), State::Fading { .. }));
+        after[0].motion(&MotionEvent {
+            motion: Motion::On,
+            timestamp: clock.now(),
+        });
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(bulb.color(), WHITE, "restored after the restart");
+    }
+}
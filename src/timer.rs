@@ -1,37 +1,78 @@
-//! A restartable threaded timer with configurable timeout (kind of like a hardware timer)
+//! A restartable threaded timer with configurable timeout and milestones (kind of like a hardware timer)
 
 use std::sync::mpsc::Sender;
 use std::sync::{mpsc, MutexGuard, PoisonError};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use crate::clock::{self, Clock};
 use crate::{ACTION, SIGNAL};
 
 pub type SignalResult = Result<(), mpsc::SendError<SIGNAL<String>>>;
 
+/// A named point of the countdown of a [`Timer`], like a warning before the timeout
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Milestone {
+    /// Name passed to the callback with [`ACTION::MILESTONE`], like one from the config file
+    pub name: Arc<str>,
+    /// Time since the timer was started
+    pub after: Duration,
+}
+
+/// Countdown of the timer thread, shared to answer [`Timer::remaining`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Countdown {
+    /// Instant the timer was started, moved later when extended or resumed
+    started: Instant,
+    /// Instant the countdown was paused, if it is
+    paused: Option<Instant>,
+}
+
 /// A restartable timer
 #[derive(Debug)]
 pub struct Timer {
     thread: JoinHandle<()>,
     pub sender: Sender<SIGNAL<String>>,
     timeout: Arc<Mutex<Duration>>,
+    milestones: Arc<Mutex<Vec<Milestone>>>,
     running: Arc<Mutex<bool>>,
+    countdown: Arc<Mutex<Countdown>>,
+    clock: Arc<dyn Clock>,
 }
 
 impl Timer {
     /// Create new timer with `timeout` and `callback`
-    pub fn new<F: 'static + FnMut(ACTION) -> () + std::marker::Send>(
+    pub fn new<F: 'static + FnMut(ACTION) + std::marker::Send>(
         timeout: Duration,
+        callback: F,
+    ) -> Self {
+        Self::with_clock(timeout, clock::real(), callback)
+    }
+
+    /// Create new timer with `timeout` measured on `clock`, and `callback`
+    pub fn with_clock<F: 'static + FnMut(ACTION) + std::marker::Send>(
+        timeout: Duration,
+        clock: Arc<dyn Clock>,
         mut callback: F,
     ) -> Self {
         let timeout_mutex = Arc::new(Mutex::new(timeout));
         let timeout_inner = timeout_mutex.clone();
 
+        let milestones_mutex = Arc::new(Mutex::new(Vec::new()));
+        let milestones_inner = milestones_mutex.clone();
+
         let running_mutex = Arc::new(Mutex::new(true));
         let running = running_mutex.clone();
 
+        let countdown_mutex = Arc::new(Mutex::new(Countdown {
+            started: clock.now(),
+            paused: None,
+        }));
+        let countdown = countdown_mutex.clone();
+        let clock_inner = clock.clone();
+
         // Create sender and receiver to communicate with timer thread
         let (sender, receiver) = mpsc::channel();
 
@@ -39,51 +80,97 @@ impl Timer {
         let thread = thread::Builder::new()
             .name("timer".to_string())
             .spawn(move || {
+                let clock = clock_inner;
+                // Milestones called back since the timer was started
+                let mut reached: Vec<Milestone> = Vec::new();
                 // Keep the thread alive, always check for next signal
-                'outer: loop {
-                    // Wait for signal or timeout, whichever comes first
-                    match receiver.recv_timeout(*timeout_inner.lock().unwrap()) {
+                loop {
+                    let timeout = *timeout_inner.lock().unwrap();
+                    let is_running = *running.lock().unwrap();
+                    let Countdown { started, paused } = *countdown.lock().unwrap();
+                    let milestone = milestones_inner
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .find(|milestone| !reached.contains(milestone))
+                        .cloned();
+                    // Wait for signal, milestone or timeout, whichever comes first
+                    let next = match milestone {
+                        _ if paused.is_some() => None,
+                        Some(ref milestone) if !is_running || milestone.after <= timeout => {
+                            Some(milestone.after)
+                        }
+                        _ => is_running.then_some(timeout),
+                    };
+                    let result = match next {
+                        Some(after) => {
+                            let wait = (started + after).saturating_duration_since(clock.now());
+                            clock::recv_timeout(clock.as_ref(), &receiver, wait)
+                        }
+                        // Block until start or resume signal is received
+                        None => clock::recv(clock.as_ref(), &receiver)
+                            .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+                    };
+                    match result {
                         Ok(SIGNAL::START) => {
-                            callback(ACTION::START { restarted: true });
+                            tracing::trace!(restarted = is_running, "timer started");
                             *running.lock().unwrap() = true;
+                            // a paused timer starts over, still paused
+                            let now = clock.now();
+                            *countdown.lock().unwrap() = Countdown {
+                                started: now,
+                                paused: paused.map(|_| now),
+                            };
+                            reached.clear();
+                            // counting down from now already, whatever the callback waits for
+                            callback(ACTION::START {
+                                restarted: is_running,
+                            });
+                        }
+                        Ok(SIGNAL::PAUSE) => {
+                            tracing::trace!("timer paused");
+                            countdown.lock().unwrap().paused.get_or_insert(clock.now());
+                        }
+                        Ok(SIGNAL::RESUME) => {
+                            let mut countdown = countdown.lock().unwrap();
+                            if let Some(paused) = countdown.paused.take() {
+                                let pause = clock.now().saturating_duration_since(paused);
+                                tracing::trace!(?pause, "timer resumed");
+                                countdown.started += pause;
+                            }
+                        }
+                        Ok(SIGNAL::EXTEND(by)) if is_running => {
+                            tracing::trace!(?by, "timer extended");
+                            countdown.lock().unwrap().started += by;
                         }
-                        Ok(SIGNAL::TERMINATE) => break 'outer,
+                        Ok(SIGNAL::EXTEND(by)) => {
+                            tracing::debug!(?by, "not extending timer that ran out")
+                        }
+                        Ok(SIGNAL::TERMINATE) => break,
                         // Arbitrary message received
                         Ok(SIGNAL::OTHER(message)) => {
-                            println!("Signal received on thread with message: {}", message)
+                            tracing::debug!(%message, running = is_running, "timer signal received")
                         }
                         // Signal receiving timed out
                         Err(mpsc::RecvTimeoutError::Timeout) => {
-                            let mut is_running = running.lock().unwrap();
-                            if *is_running {
-                                {
+                            match milestone.filter(|milestone| next == Some(milestone.after)) {
+                                Some(milestone) => {
+                                    tracing::trace!(
+                                        name = %milestone.name,
+                                        "timer reached milestone"
+                                    );
+                                    callback(ACTION::MILESTONE {
+                                        name: milestone.name.clone(),
+                                    });
+                                    reached.push(milestone);
+                                }
+                                None => {
+                                    tracing::trace!(?timeout, "timer ran out");
+                                    // unlocked before the callback, which can take seconds
+                                    *running.lock().unwrap() = false;
                                     callback(ACTION::TIMEOUT);
-                                    *is_running = false;
-
-                                    // release lock before blocking
-                                    drop(is_running);
-
-                                    // Block until start signal is received
-                                    loop {
-                                        match receiver.recv() {
-                                            Ok(SIGNAL::START) => {
-                                                callback(ACTION::START { restarted: false });
-                                                *running.lock().unwrap() = true;
-                                                break;
-                                            }
-                                            Ok(SIGNAL::TERMINATE) => break 'outer,
-                                            Ok(SIGNAL::OTHER(message)) => {
-                                                println!(
-                                                    "Signal sent to thread with message: {}",
-                                                    message
-                                                )
-                                            }
-                                            Err(err) => panic!("Channel has hung up: {}", err),
-                                        }
-                                    }
                                 }
                             }
-                            // Ignore timeout while timer not running
                         }
                         Err(err) => panic!("Channel has hung up: {}", err),
                     }
@@ -96,6 +183,9 @@ impl Timer {
             sender,
             running: running_mutex,
             timeout: timeout_mutex,
+            milestones: milestones_mutex,
+            countdown: countdown_mutex,
+            clock,
         }
     }
 
@@ -103,6 +193,23 @@ impl Timer {
     pub fn start(&self) -> SignalResult {
         self.sender.send(SIGNAL::START)
     }
+    /// Freeze the countdown, also of the milestones, until [`Timer::resume`]
+    ///
+    /// A paused timer started again counts down from the full timeout once resumed.
+    pub fn pause(&self) -> SignalResult {
+        self.sender.send(SIGNAL::PAUSE)
+    }
+
+    /// Continue the countdown paused with [`Timer::pause`] where it was
+    pub fn resume(&self) -> SignalResult {
+        self.sender.send(SIGNAL::RESUME)
+    }
+
+    /// Push the timeout and the milestones not reached yet back `by`, if the timer is running
+    pub fn extend(&self, by: Duration) -> SignalResult {
+        self.sender.send(SIGNAL::EXTEND(by))
+    }
+
     /// Send a custom signal to the timer thread
     pub fn signal(&self, signal: SIGNAL<String>) -> SignalResult {
         self.sender.send(signal)
@@ -113,8 +220,27 @@ impl Timer {
         *self.running.lock().unwrap()
     }
 
+    /// If the countdown is paused with [`Timer::pause`]
+    pub fn is_paused(&self) -> bool {
+        self.countdown.lock().unwrap().paused.is_some()
+    }
+
+    /// Time left until the timeout, frozen while paused, `None` if the timer is not running
+    pub fn remaining(&self) -> Option<Duration> {
+        if !self.is_running() {
+            return None;
+        }
+        let countdown = *self.countdown.lock().unwrap();
+        let now = countdown.paused.unwrap_or_else(|| self.clock.now());
+        let timeout = *self.timeout.lock().unwrap();
+        Some((countdown.started + timeout).saturating_duration_since(now))
+    }
+
     /// Set the timer's timeout duration
-    pub fn set_timeout(&self, timeout: Duration) -> Result<(), PoisonError<MutexGuard<Duration>>> {
+    pub fn set_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<(), PoisonError<MutexGuard<'_, Duration>>> {
         *self.timeout.lock()? = timeout;
         Ok(())
     }
@@ -124,6 +250,28 @@ impl Timer {
         Ok(*self.timeout.lock()?)
     }
 
+    /// Call back with [`ACTION::MILESTONE`] at each of `milestones`, in order of time
+    ///
+    /// Milestones are counted from when the timer was started, also past the timeout, and reached
+    /// again after every restart. A milestone at the timeout is reached before it.
+    pub fn set_milestones(
+        &self,
+        mut milestones: Vec<Milestone>,
+    ) -> Result<(), PoisonError<MutexGuard<'_, Vec<Milestone>>>> {
+        milestones.sort_by_key(|milestone| milestone.after);
+        *self.milestones.lock()? = milestones;
+        // wake up the timer thread so the milestones count for the running countdown
+        let _ = self.signal(SIGNAL::OTHER("milestones changed".to_string()));
+        Ok(())
+    }
+
+    /// Get the milestones in order of time
+    pub fn milestones(
+        &self,
+    ) -> Result<Vec<Milestone>, PoisonError<MutexGuard<'_, Vec<Milestone>>>> {
+        Ok(self.milestones.lock()?.clone())
+    }
+
     pub fn destroy(self) -> thread::Result<()> {
         self.sender.send(SIGNAL::TERMINATE).unwrap();
         self.thread.join()
@@ -133,6 +281,7 @@ impl Timer {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::MockClock;
 
     #[test]
     fn test_creation() {
@@ -169,11 +318,11 @@ mod tests {
     }
     #[test]
     fn test_timeout() {
-        let actions: Arc<Mutex<[Option<ACTION>; 2]>> = Arc::new(Mutex::new([None; 2]));
+        let actions: Arc<Mutex<[Option<ACTION>; 2]>> = Arc::new(Mutex::new([None, None]));
         let actions_outer = actions.clone();
         let timer = Timer::new(Duration::from_millis(100), move |action| {
             let mut actions_inner = actions.lock().unwrap();
-            if matches!(actions_inner[0], None) {
+            if actions_inner[0].is_none() {
                 (*actions_inner)[0] = Some(action);
             } else {
                 (*actions_inner)[1] = Some(action);
@@ -182,7 +331,7 @@ mod tests {
         timer.start().unwrap();
         thread::sleep(Duration::from_millis(200));
         timer.destroy().unwrap();
-        let actions_values = *actions_outer.lock().unwrap();
+        let actions_values = actions_outer.lock().unwrap().clone();
         assert_eq!(
             actions_values,
             [
@@ -191,4 +340,170 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = Arc::new(MockClock::new());
+        let (sender, receiver) = mpsc::channel();
+        let timer = Timer::with_clock(Duration::from_secs(600), clock.clone(), move |action| {
+            sender.send(action).unwrap()
+        });
+        // let the timer thread start waiting before moving the clock
+        let real_timeout = Duration::from_secs(1);
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_secs(540));
+        timer.start().unwrap();
+        assert_eq!(
+            receiver.recv_timeout(real_timeout),
+            Ok(ACTION::START { restarted: true })
+        );
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_secs(540));
+        clock.wait_for_sleepers(1);
+        assert!(receiver.try_recv().is_err(), "restarted 9 minutes ago");
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(receiver.recv_timeout(real_timeout), Ok(ACTION::TIMEOUT));
+        assert!(!timer.is_running());
+        timer.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_pause_and_extend() {
+        let clock = Arc::new(MockClock::new());
+        let (sender, receiver) = mpsc::channel();
+        let timer = Timer::with_clock(Duration::from_secs(600), clock.clone(), move |action| {
+            sender.send(action).unwrap()
+        });
+        let real_timeout = Duration::from_secs(1);
+        let minutes = |minutes: u64| Some(Duration::from_secs(minutes * 60));
+        timer.start().unwrap();
+        assert_eq!(
+            receiver.recv_timeout(real_timeout),
+            Ok(ACTION::START { restarted: true })
+        );
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_secs(240));
+        assert_eq!(timer.remaining(), minutes(6));
+
+        // a movie is on, the countdown freezes
+        timer.pause().unwrap();
+        clock.wait_for_sleepers(1);
+        assert!(timer.is_paused());
+        clock.advance(Duration::from_secs(3600));
+        clock.wait_for_sleepers(1);
+        assert!(receiver.try_recv().is_err(), "paused for an hour");
+        assert_eq!(timer.remaining(), minutes(6));
+        timer.resume().unwrap();
+        clock.wait_for_sleepers(1);
+        assert!(!timer.is_paused());
+        assert_eq!(timer.remaining(), minutes(6));
+
+        timer.extend(Duration::from_secs(120)).unwrap();
+        clock.wait_for_sleepers(1);
+        assert_eq!(timer.remaining(), minutes(8));
+        clock.advance(Duration::from_secs(420));
+        clock.wait_for_sleepers(1);
+        assert!(receiver.try_recv().is_err(), "extended by 2 minutes");
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(receiver.recv_timeout(real_timeout), Ok(ACTION::TIMEOUT));
+        assert_eq!(timer.remaining(), None);
+        timer.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_milestones() {
+        let clock = Arc::new(MockClock::new());
+        let (sender, receiver) = mpsc::channel();
+        let timer = Timer::with_clock(Duration::from_secs(600), clock.clone(), move |action| {
+            sender.send(action).unwrap()
+        });
+        let milestone = |name: &str, minutes: u64| Milestone {
+            name: name.into(),
+            after: Duration::from_secs(minutes * 60),
+        };
+        let reached = |name: &str| ACTION::MILESTONE { name: name.into() };
+        timer
+            .set_milestones(vec![
+                milestone("dim", 10),
+                milestone("off", 20),
+                milestone("warn", 8),
+            ])
+            .unwrap();
+        let milestones = timer.milestones().unwrap();
+        let names: Vec<&str> = milestones.iter().map(|m| &*m.name).collect();
+        assert_eq!(names, ["warn", "dim", "off"]);
+        let real_timeout = Duration::from_secs(1);
+        let next = || receiver.recv_timeout(real_timeout).unwrap();
+        timer.start().unwrap();
+        assert_eq!(next(), ACTION::START { restarted: true });
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_secs(470));
+        clock.wait_for_sleepers(1);
+        assert!(
+            receiver.try_recv().is_err(),
+            "10 seconds to the first milestone"
+        );
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(next(), reached("warn"));
+
+        // restarted between milestones, all are reached again
+        timer.start().unwrap();
+        assert_eq!(next(), ACTION::START { restarted: true });
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_secs(600));
+        assert_eq!(next(), reached("warn"));
+        assert_eq!(next(), reached("dim"));
+        assert_eq!(next(), ACTION::TIMEOUT);
+        assert!(!timer.is_running());
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_secs(600));
+        assert_eq!(next(), reached("off"), "past the timeout");
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_secs(600));
+        clock.wait_for_sleepers(1);
+        assert!(receiver.try_recv().is_err());
+
+        timer.start().unwrap();
+        assert_eq!(next(), ACTION::START { restarted: false });
+        timer.destroy().unwrap();
+    }
+
+    /// The timer answers while the timeout is handled, like a light retried for seconds
+    #[test]
+    fn test_remaining_during_timeout() {
+        let clock = Arc::new(MockClock::new());
+        let (sender, receiver) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let timer = Timer::with_clock(Duration::from_secs(600), clock.clone(), move |action| {
+            let timeout = action == ACTION::TIMEOUT;
+            sender.send(action).unwrap();
+            if timeout {
+                released.recv().unwrap();
+            }
+        });
+        let real_timeout = Duration::from_secs(1);
+        timer.start().unwrap();
+        assert_eq!(
+            receiver.recv_timeout(real_timeout),
+            Ok(ACTION::START { restarted: true })
+        );
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_secs(600));
+        assert_eq!(receiver.recv_timeout(real_timeout), Ok(ACTION::TIMEOUT));
+
+        // asked from another thread, so a blocked timer fails the test instead of hanging it
+        let (answer, answered) = mpsc::channel();
+        let answer = thread::scope(|scope| {
+            scope.spawn(|| {
+                answer
+                    .send((timer.remaining(), timer.is_running()))
+                    .unwrap()
+            });
+            let answered = answered.recv_timeout(real_timeout);
+            release.send(()).unwrap();
+            answered
+        });
+        assert_eq!(answer, Ok((None, false)));
+        timer.destroy().unwrap();
+    }
 }